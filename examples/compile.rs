@@ -1,13 +1,13 @@
+use animation_lang::assembler::FromAssembly;
+use animation_lang::budget::Bound;
 use animation_lang::compiler::FromSource;
+use animation_lang::container::Metadata;
 use animation_lang::program::Program;
 use anyhow::{bail, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64_ENGINE, Engine};
 use clap::Parser;
 use reqwest::blocking::Client;
 use std::path::PathBuf;
-use base64::{
-    engine::general_purpose::STANDARD as BASE64_ENGINE,
-    Engine
-};
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -19,27 +19,124 @@ struct Args {
 
     #[arg(long, short, help = "address to send base64 encoded program")]
     send_addr: Option<String>,
+
+    #[arg(
+        long,
+        short,
+        help = "treat input as assembly listing instead of source code"
+    )]
+    asm: bool,
+
+    #[arg(
+        long,
+        short,
+        help = "save output as program container instead of bare bytecode"
+    )]
+    container: bool,
+
+    #[arg(long, help = "program author stored in container metadata")]
+    author: Option<String>,
+
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "minimal led strip length stored in container metadata"
+    )]
+    min_length: u32,
+
+    #[arg(long, help = "leave source map out of the container")]
+    strip_debug: bool,
+
+    #[arg(
+        long,
+        help = "reject the program unless every frame provably fits this frame rate"
+    )]
+    fps: Option<u64>,
+
+    #[arg(
+        long,
+        default_value_t = 1_000_000,
+        help = "instructions the device executes per second, used by --fps"
+    )]
+    instructions_per_second: u64,
+
+    #[arg(
+        long,
+        help = "led strip length assumed by --fps, defaults to --min-length"
+    )]
+    assume_length: Option<usize>,
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
 
-    let source_code = std::fs::read_to_string(args.in_file)?;
-    let p = Program::from_source(&source_code)?;
+    let source_code = std::fs::read_to_string(&args.in_file)?;
+    let mut p = if args.asm {
+        Program::from_assembly(&source_code)?
+    } else {
+        Program::from_source(&source_code)?
+    };
 
     println!("assembly:");
     println!("{:?}", p);
 
+    if let Some(fps) = args.fps {
+        let length = match (args.assume_length, args.min_length) {
+            (Some(length), _) => length,
+            (None, 0) => bail!("--fps needs --assume-length or --min-length"),
+            (None, length) => length as usize,
+        };
+        let budget = p.frame_budget(length)?;
+        let limit = args.instructions_per_second / fps.max(1);
+        println!(
+            "worst case instructions per frame: {} (limit {} for {} fps)",
+            budget.per_frame, limit, fps
+        );
+        if budget.per_event != Bound::Finite(0) {
+            println!(
+                "worst case instructions added per event: {}",
+                budget.per_event
+            );
+        }
+        for reason in budget.reasons.iter() {
+            println!("  {}", reason);
+        }
+        if !budget.fits(limit) {
+            bail!("program can not be shown to reach {} fps", fps);
+        }
+    }
+
+    if args.strip_debug {
+        p.set_source_map(None);
+    }
+
+    let metadata = Metadata {
+        name: args
+            .in_file
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        author: args.author.unwrap_or_default(),
+        min_strip_length: args.min_length,
+        required_features: p.required_features(),
+        ..Default::default()
+    };
+    let container = p.to_container(&metadata)?;
+
     if let Some(path) = args.out_file {
         println!("Saving program into {:?}", path);
-        std::fs::write(path, p.code())?;
+        if args.container {
+            std::fs::write(path, &container)?;
+        } else {
+            std::fs::write(path, p.code())?;
+        }
     }
 
     if let Some(addr) = args.send_addr {
         println!("Sending program to {}", addr);
         let resp = Client::new()
             .post(addr)
-            .body(BASE64_ENGINE.encode(p.code()))
+            .body(BASE64_ENGINE.encode(&container))
             .send()?;
 
         if resp.status() != 200 {
@@ -0,0 +1,167 @@
+use animation_lang::compiler::FromSource;
+use animation_lang::instructions::Instruction;
+use animation_lang::program::Program;
+use animation_lang::vm::debug::{Breakpoint, Debugger, Event};
+use animation_lang::vm::{VMConfig, VMStateConfig, VM};
+use anyhow::Result;
+use clap::Parser;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+struct Args {
+    #[arg(long, short)]
+    in_file: PathBuf,
+
+    #[arg(long, short, default_value_t = 10, help = "led strip length")]
+    length: usize,
+}
+
+const HELP: &str = "commands:
+  s                step one instruction
+  c                continue until breakpoint, watched pixel write or frame
+  f                run until next frame, ignoring breakpoints
+  b <pc>           break at pc
+  l <line>         break at source line
+  d <pc>|l <line>  delete breakpoint, e.g. `d 12` or `d l 3`
+  w <index>        watch writes to pixel
+  u <index>        stop watching pixel
+  stack            print stack
+  vars             print variables in scope
+  q                quit";
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let source_code = std::fs::read_to_string(args.in_file)?;
+    let program = Program::from_source(&source_code)?;
+    let vm = VM::new(args.length, VMConfig::default());
+    let mut debugger = Debugger::new(vm.start(program, VMStateConfig::default()));
+
+    println!("{}", HELP);
+    print_position(&debugger);
+
+    let stdin = std::io::stdin();
+    loop {
+        print!("> ");
+        std::io::stdout().flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            break;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        let event = match words.as_slice() {
+            ["s"] => debugger.step(),
+            ["c"] => debugger.resume(),
+            ["f"] => debugger.run_until_blit(),
+            ["b", pc] => {
+                if let Some(pc) = argument(pc) {
+                    report(debugger.add_breakpoint(Breakpoint::Pc(pc)));
+                }
+                continue;
+            }
+            ["l", line] => {
+                if let Some(line) = argument(line) {
+                    report(debugger.add_breakpoint(Breakpoint::Line(line)));
+                }
+                continue;
+            }
+            ["d", "l", line] => {
+                if let Some(line) = argument(line) {
+                    report(debugger.remove_breakpoint(Breakpoint::Line(line)));
+                }
+                continue;
+            }
+            ["d", pc] => {
+                if let Some(pc) = argument(pc) {
+                    report(debugger.remove_breakpoint(Breakpoint::Pc(pc)));
+                }
+                continue;
+            }
+            ["w", index] => {
+                if let Some(index) = argument(index) {
+                    debugger.watch_pixel(index);
+                }
+                continue;
+            }
+            ["u", index] => {
+                if let Some(index) = argument(index) {
+                    debugger.unwatch_pixel(index);
+                }
+                continue;
+            }
+            ["stack"] => {
+                println!("{:?}", debugger.stack());
+                continue;
+            }
+            ["vars"] => {
+                for (name, value) in debugger.variables() {
+                    println!("{} = {}", name, value);
+                }
+                continue;
+            }
+            ["q"] => break,
+            _ => {
+                println!("{}", HELP);
+                continue;
+            }
+        };
+
+        match event {
+            Event::Stepped => {}
+            Event::Breakpoint(pc) => println!("breakpoint at {}", pc),
+            Event::PixelWrite { pc, index, color } => println!(
+                "pixel {} set to ({}, {}, {}, {}) at {}",
+                index, color.r, color.g, color.b, color.a.0, pc
+            ),
+            Event::Blit(frame) => {
+                let frame: Vec<_> = frame.map(|p| (p.r, p.g, p.b, p.a.0)).collect();
+                println!("frame: {:?}", frame);
+            }
+            Event::Ended => {
+                println!("program ended");
+                break;
+            }
+            Event::Error(e) => {
+                println!("error: {}", e);
+                break;
+            }
+        }
+        print_position(&debugger);
+    }
+
+    Ok(())
+}
+
+fn report<T, E: std::fmt::Display>(result: Result<T, E>) {
+    match result {
+        Ok(_) => {}
+        Err(e) => println!("{}", e),
+    }
+}
+
+/// Parses a command argument, printing why it is invalid
+fn argument<T: std::str::FromStr>(word: &str) -> Option<T>
+where
+    T::Err: std::fmt::Display,
+{
+    word.parse()
+        .map_err(|e| println!("invalid argument {}: {}", word, e))
+        .ok()
+}
+
+fn print_position(debugger: &Debugger) {
+    let state = debugger.state();
+    let program = state.program();
+    let instruction = Instruction::decode(program.code(), state.pc())
+        .map(|(ins, _)| ins.to_string())
+        .unwrap_or_else(|e| e.to_string());
+    let line = program
+        .source_map()
+        .and_then(|m| m.lookup(state.pc()))
+        .map(|span| format!(" ({})", span))
+        .unwrap_or_default();
+    println!("{:04}.\t{}{}", state.pc(), instruction, line);
+}
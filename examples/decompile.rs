@@ -0,0 +1,30 @@
+use animation_lang::decompiler::ToSource;
+use animation_lang::program::Program;
+use anyhow::Result;
+use clap::Parser;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+struct Args {
+    #[arg(long, short)]
+    in_file: PathBuf,
+
+    #[arg(long, short)]
+    out_file: Option<PathBuf>,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let p = Program::from_file(args.in_file.to_str().unwrap())?;
+    let source_code = p.to_source()?;
+
+    if let Some(path) = args.out_file {
+        println!("Saving source into {:?}", path);
+        std::fs::write(path, source_code)?;
+    } else {
+        print!("{}", source_code);
+    }
+
+    Ok(())
+}
@@ -28,7 +28,7 @@ const WIDTH: usize =
     VLED_QUANTITY * (VLED_WIDTH + VLED_H_SPACING) - VLED_H_SPACING + 2 * WINDOW_PADDING;
 const HEIGHT: usize = VLED_HEIGHT + 2 * WINDOW_PADDING;
 const FB_SIZE: usize = WIDTH * HEIGHT;
-//                                                                   / <- FPS here
+// Used unless the program sets its frame rate       / <- FPS here
 const FRAME_TIME: Duration = Duration::from_micros(((1_f32 / 32_f32) * 1_000_000_f32) as u64);
 const MAIN_LOOP_TIME: Duration = Duration::from_millis(1);
 const DEFAULT_PROG: &str = include_str!("../example_progs/blink.txt");
@@ -70,24 +70,28 @@ fn main() {
     );
     let mut vm_running = true;
 
+    let mut frame_time = FRAME_TIME;
     let mut last_update = Instant::now() - 2 * FRAME_TIME;
     while window.is_open() && !window.is_key_down(Key::Escape) {
         if let Some(new_prog) = try_receive_new_prob(&mut server) {
             vm_state = {
                 let (vm, state_config, _) = vm_state.stop();
-                vm.start(Program::from_binary(new_prog), state_config)
+                vm.start(new_prog, state_config)
             };
             vm_running = true;
         }
 
         let now = Instant::now();
-        if vm_running && now > last_update + FRAME_TIME {
+        if vm_running && now > last_update + frame_time {
             match vm_state.next() {
                 Some(r) => match r {
-                    Ok(frame) => led_strip.write(frame).unwrap(),
+                    Ok(frame) => {
+                        frame_time = frame.delay.unwrap_or(FRAME_TIME);
+                        led_strip.write(frame).unwrap()
+                    }
                     Err(e) => {
                         println!("halting vm until new program received");
-                        eprintln!("{:?}", e);
+                        eprintln!("{}", e);
                         vm_running = false;
                     }
                 },
@@ -129,7 +133,7 @@ impl Restart for VMState {
     }
 }
 
-fn try_receive_new_prob(server: &mut Server) -> Option<Vec<u8>> {
+fn try_receive_new_prob(server: &mut Server) -> Option<Program> {
     match server.try_recv().unwrap() {
         Some(mut req) => match req.url() {
             "/send_prog_base64" => match req.method() {
@@ -137,18 +141,17 @@ fn try_receive_new_prob(server: &mut Server) -> Option<Vec<u8>> {
                     let mut body = Vec::new();
                     req.as_reader().read_to_end(&mut body).unwrap();
 
-                    match BASE64_ENGINE.decode(&body) {
+                    match load_program(&body) {
                         Ok(prog) => {
                             req.respond(Response::empty(200)).unwrap();
                             Some(prog)
                         }
-                        Err(e) => {
-                            let message = format!("bad base64 payload: {}", e);
+                        Err(message) => {
                             req.respond(Response::new(
                                 StatusCode(400),
                                 vec![],
                                 message.as_bytes(),
-                                Some(message.as_bytes().len()),
+                                Some(message.len()),
                                 None,
                             ))
                             .unwrap();
@@ -170,6 +173,25 @@ fn try_receive_new_prob(server: &mut Server) -> Option<Vec<u8>> {
     }
 }
 
+fn load_program(body: &[u8]) -> Result<Program, String> {
+    let data = BASE64_ENGINE
+        .decode(body)
+        .map_err(|e| format!("bad base64 payload: {}", e))?;
+    let (prog, metadata) = Program::from_container(&data).map_err(|e| e.to_string())?;
+    metadata
+        .check_strip_length(VLED_QUANTITY as u32)
+        .map_err(|e| e.to_string())?;
+    prog.verify().map_err(|errors| {
+        errors
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    })?;
+    println!("received program {:?} by {:?}", metadata.name, metadata.author);
+    Ok(prog)
+}
+
 trait ExportFB {
     fn export_fb(&self, frame_buffer: &mut [u32]);
 }
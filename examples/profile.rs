@@ -0,0 +1,61 @@
+use animation_lang::compiler::FromSource;
+use animation_lang::program::Program;
+use animation_lang::vm::{Outcome, VMConfig, VMStateConfig, VM};
+use anyhow::Result;
+use clap::Parser;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+struct Args {
+    #[arg(long, short)]
+    in_file: PathBuf,
+
+    #[arg(long, short, default_value_t = 10, help = "led strip length")]
+    length: usize,
+
+    #[arg(long, short, default_value_t = 100, help = "number of frames to run")]
+    frames: usize,
+
+    #[arg(long, default_value_t = 20, help = "number of hotspots to list")]
+    top: usize,
+
+    #[arg(long, help = "write folded stacks for flamegraph tools to this file")]
+    folded: Option<PathBuf>,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let source_code = std::fs::read_to_string(args.in_file)?;
+    let program = Program::from_source(&source_code)?;
+    let vm = VM::new(
+        args.length,
+        VMConfig {
+            deterministic: true,
+            ..Default::default()
+        },
+    );
+    let config = VMStateConfig {
+        profile: true,
+        ..Default::default()
+    };
+    let mut state = vm.start(program.clone(), config);
+
+    for _ in 0..args.frames {
+        match state.run() {
+            Outcome::BLIT(_) => {}
+            Outcome::Ended => break,
+            Outcome::Error(e) => {
+                println!("error: {}", e);
+                break;
+            }
+        }
+    }
+
+    let profile = state.profile().expect("profiling is enabled");
+    print!("{}", profile.report(&program, args.top));
+    if let Some(path) = args.folded {
+        std::fs::write(path, profile.folded_stacks(&program))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use animation_lang::budget::Bound;
+use animation_lang::compiler::FromSource;
+use animation_lang::cost::{target, TARGETS};
+use animation_lang::program::Program;
+use animation_lang::vm::{VMConfig, VMStateConfig, VM};
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+struct Args {
+    #[arg(long, short)]
+    in_file: PathBuf,
+
+    #[arg(long, short, default_value_t = 10, help = "led strip length")]
+    length: usize,
+
+    #[arg(long, short, default_value_t = 100, help = "number of frames to run")]
+    frames: usize,
+
+    #[arg(
+        long,
+        short,
+        default_value = "cortex-m0",
+        help = "cost model: uniform, cortex-m0 or riscv32"
+    )]
+    target: String,
+
+    #[arg(long, short, default_value_t = 48_000_000, help = "cpu clock in Hz")]
+    clock: u64,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let source_code = std::fs::read_to_string(args.in_file)?;
+    let program = Program::from_source(&source_code)?;
+    let model = || {
+        target(&args.target).ok_or_else(|| {
+            anyhow!(
+                "unknown target {}, expected one of {:?}",
+                args.target,
+                TARGETS
+            )
+        })
+    };
+
+    let budget = program.frame_cycles(args.length, model()?.as_ref())?;
+    match budget.per_frame {
+        Bound::Finite(cycles) if cycles > 0 => println!(
+            "static worst case cycles per frame: {} ({:.1} fps)",
+            cycles,
+            args.clock as f64 / cycles as f64
+        ),
+        bound => println!("static worst case cycles per frame: {}", bound),
+    }
+    for reason in budget.reasons.iter() {
+        println!("  {}", reason);
+    }
+
+    let vm = VM::new(
+        args.length,
+        VMConfig {
+            deterministic: true,
+            ..Default::default()
+        },
+    );
+    let config = VMStateConfig {
+        cost_model: Some(model()?),
+        ..Default::default()
+    };
+    let simulation = vm
+        .start(program, config)
+        .simulate(args.frames, args.clock)?;
+    println!("simulated frames: {}", simulation.frame_cycles.len());
+    if let (Some(average), Some(worst)) = (simulation.average_fps(), simulation.worst_fps()) {
+        println!("predicted fps: {:.1} average, {:.1} worst", average, worst);
+    }
+    Ok(())
+}
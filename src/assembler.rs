@@ -0,0 +1,479 @@
+use std::collections::HashMap;
+
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_while, take_while1, take_while_m_n},
+    character::complete::{space0, space1},
+    combinator::{all_consuming, map, map_res, opt, recognize},
+    multi::separated_list1,
+    sequence::{delimited, pair, preceded, terminated, tuple},
+    Finish, IResult,
+};
+use thiserror::Error;
+
+use crate::compiler::{dec_number, hex_literal};
+use crate::instructions::{
+    Binary, Coordinate, EventKind, Instruction, Prefix, Special, Unary, UserCommand,
+};
+use crate::program::{Program, POSTFIX_MAX};
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum AssemblyError {
+    #[error("line {0}: could not parse: {1}")]
+    ParseError(usize, String),
+
+    #[error("line {0}: unknown mnemonic {1}")]
+    UnknownMnemonic(usize, String),
+
+    #[error("line {0}: invalid operand for {1}: {2}")]
+    InvalidOperand(usize, String, String),
+
+    #[error("line {0}: label already defined: {1}")]
+    RedefinedLabel(usize, String),
+
+    #[error("line {0}: label was not defined: {1}")]
+    UndefinedLabel(usize, String),
+
+    #[error("line {0}: no instruction listed at address {1}")]
+    UndefinedAddress(usize, usize),
+
+    #[error("line {0}: jump target {1} does not fit into 16 bits")]
+    TargetOutOfRange(usize, usize),
+}
+
+pub trait FromAssembly {
+    fn from_assembly(source: &str) -> Result<Program, AssemblyError>;
+}
+
+#[derive(Debug)]
+enum Target {
+    Address(usize),
+    Label(String),
+}
+
+#[derive(Debug)]
+enum Item {
+    Bytes(Vec<u8>),
+    Jump(fn(u16) -> Instruction<'static>, Target),
+    /// Event handler, the target is its end
+    On(EventKind, Vec<u8>, Target),
+}
+
+impl Item {
+    fn len(&self) -> usize {
+        match self {
+            Item::Bytes(b) => b.len(),
+            Item::Jump(..) => 3,
+            Item::On(_, key, _) => 5 + key.len(),
+        }
+    }
+}
+
+/// A single source line: `[0042.] [2f] [label:] [MNEMONIC [operand]] [; comment]`
+struct Line<'a> {
+    address: Option<u32>,
+    label: Option<&'a str>,
+    instruction: Option<(&'a str, &'a str)>,
+}
+
+/// Jump target, `to` is optional
+fn target(input: &str) -> IResult<&str, Target> {
+    preceded(
+        opt(pair(tag("to"), space1)),
+        alt((
+            map(number, |v| Target::Address(v as usize)),
+            map(identifier, |l| Target::Label(l.to_string())),
+        )),
+    )(input)
+}
+
+fn identifier(input: &str) -> IResult<&str, &str> {
+    recognize(pair(
+        take_while1(|c: char| c.is_ascii_alphabetic() || c == '_'),
+        take_while(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+    ))(input)
+}
+
+fn number(input: &str) -> IResult<&str, u32> {
+    alt((hex_literal, dec_number))(input)
+}
+
+fn hex_byte(input: &str) -> IResult<&str, u8> {
+    map_res(
+        take_while_m_n(2, 2, |c: char| c.is_ascii_hexdigit()),
+        |h: &str| u8::from_str_radix(h, 16),
+    )(input)
+}
+
+/// `{:02x?}` formatted byte slice, as printed by the disassembler
+fn byte_list(input: &str) -> IResult<&str, Vec<u8>> {
+    delimited(
+        terminated(tag("["), space0),
+        separated_list1(tuple((space0, tag(","), space0)), hex_byte),
+        preceded(space0, tag("]")),
+    )(input)
+}
+
+fn line(input: &str) -> IResult<&str, Line<'_>> {
+    let (input, _) = space0(input)?;
+    let (input, address) = opt(terminated(dec_number, pair(tag("."), space0)))(input)?;
+    let (input, label) = opt(terminated(identifier, pair(tag(":"), space0)))(input)?;
+    // Raw instruction byte of the listing, the mnemonic is authoritative
+    let (input, _) = opt(terminated(hex_byte, space1))(input)?;
+    let (input, instruction) = opt(pair(
+        take_while1(|c: char| c.is_ascii_uppercase()),
+        preceded(space0, take_while(|c: char| c != ';')),
+    ))(input)?;
+    let (input, _) = opt(preceded(tag(";"), take_while(|_| true)))(input)?;
+    Ok((
+        input,
+        Line {
+            address,
+            label,
+            instruction: instruction.map(|(m, o)| (m, o.trim_end())),
+        },
+    ))
+}
+
+fn invalid(n: usize, mnemonic: &str, input: &str) -> AssemblyError {
+    AssemblyError::InvalidOperand(n, mnemonic.to_string(), input.to_string())
+}
+
+fn operand<'a, O, F>(
+    n: usize,
+    mnemonic: &str,
+    input: &'a str,
+    parser: F,
+) -> Result<O, AssemblyError>
+where
+    F: FnMut(&'a str) -> IResult<&'a str, O>,
+{
+    all_consuming(parser)(input)
+        .finish()
+        .map(|(_, o)| o)
+        .map_err(|_| invalid(n, mnemonic, input))
+}
+
+/// Looks up an opcode variant by the name its `Display` implementation prints
+fn by_name<T: ToString>(name: &str, from: impl Fn(u8) -> Option<T>) -> Option<u8> {
+    (0..=POSTFIX_MAX).find(|code| from(*code).map(|v| v.to_string()).as_deref() == Some(name))
+}
+
+fn postfix(n: usize, mnemonic: &str, input: &str) -> Result<u8, AssemblyError> {
+    let value = operand(n, mnemonic, input, number)?;
+    if value > u32::from(POSTFIX_MAX) {
+        return Err(invalid(n, mnemonic, input));
+    }
+    Ok(value as u8)
+}
+
+fn named_postfix<T: ToString>(
+    n: usize,
+    mnemonic: &str,
+    input: &str,
+    from: impl Fn(u8) -> Option<T>,
+) -> Result<u8, AssemblyError> {
+    if let Some(code) = by_name(input, from) {
+        return Ok(code);
+    }
+    // Variants the disassembler could not name are printed as `unknown N`
+    let raw = operand(
+        n,
+        mnemonic,
+        input,
+        preceded(pair(tag("unknown"), space1), number),
+    )?;
+    if raw > u32::from(POSTFIX_MAX) {
+        return Err(invalid(n, mnemonic, input));
+    }
+    Ok(raw as u8)
+}
+
+fn encoded(ins: Instruction) -> Item {
+    let mut code = vec![];
+    ins.encode(&mut code);
+    Item::Bytes(code)
+}
+
+/// Named variants are written as raw bytes, so postfixes the disassembler printed as `unknown N` survive
+fn instruction(n: usize, mnemonic: &str, input: &str) -> Result<Item, AssemblyError> {
+    let prefix = match mnemonic {
+        "PEEK" => Prefix::PEEK,
+        _ => (0..=POSTFIX_MAX)
+            .filter_map(|p| Prefix::from(p << 4))
+            .find(|p| p.to_string() == mnemonic)
+            .ok_or_else(|| AssemblyError::UnknownMnemonic(n, mnemonic.to_string()))?,
+    };
+
+    let item = match prefix {
+        Prefix::POP => encoded(Instruction::Pop(postfix(n, mnemonic, input)?)),
+        Prefix::PEEK => encoded(Instruction::Peek(postfix(n, mnemonic, input)?)),
+        Prefix::SWAP => encoded(Instruction::Swap(postfix(n, mnemonic, input)?)),
+        Prefix::PUSHB => {
+            let bytes = operand(
+                n,
+                mnemonic,
+                input,
+                alt((
+                    byte_list,
+                    map_res(number, |v| match v {
+                        0 => Ok(vec![]),
+                        _ => u8::try_from(v).map(|b| vec![b]),
+                    }),
+                )),
+            )?;
+            if bytes.len() > POSTFIX_MAX as usize {
+                return Err(invalid(n, mnemonic, input));
+            }
+            encoded(Instruction::PushB(&bytes))
+        }
+        Prefix::PUSHI => {
+            let bytes = operand(
+                n,
+                mnemonic,
+                input,
+                alt((byte_list, map(number, |v| v.to_le_bytes().to_vec()))),
+            )?;
+            if bytes.len() % 4 != 0 || bytes.len() / 4 > POSTFIX_MAX as usize {
+                return Err(invalid(n, mnemonic, input));
+            }
+            encoded(Instruction::PushI(&bytes))
+        }
+        Prefix::JMP | Prefix::JZ | Prefix::JNZ => {
+            let jump = match prefix {
+                Prefix::JMP => Instruction::Jmp,
+                Prefix::JZ => Instruction::Jz,
+                _ => Instruction::Jnz,
+            };
+            Item::Jump(jump, operand(n, mnemonic, input, target)?)
+        }
+        Prefix::UNARY => Item::Bytes(vec![
+            prefix as u8 | named_postfix(n, mnemonic, input, Unary::from)?,
+        ]),
+        Prefix::BINARY => Item::Bytes(vec![
+            prefix as u8 | named_postfix(n, mnemonic, input, Binary::from)?,
+        ]),
+        Prefix::USER if input.starts_with("host") => {
+            // host <id> in <inputs> out <outputs>
+            let (id, inputs, outputs) = operand(
+                n,
+                mnemonic,
+                input,
+                tuple((
+                    preceded(pair(tag("host"), space1), number),
+                    preceded(tuple((space1, tag("in"), space1)), number),
+                    preceded(tuple((space1, tag("out"), space1)), number),
+                )),
+            )?;
+            let (Ok(id), true, true) = (
+                u8::try_from(id),
+                inputs <= u32::from(POSTFIX_MAX),
+                outputs <= u32::from(POSTFIX_MAX),
+            ) else {
+                return Err(invalid(n, mnemonic, input));
+            };
+            encoded(Instruction::Host {
+                id,
+                inputs: inputs as u8,
+                outputs: outputs as u8,
+            })
+        }
+        Prefix::USER => Item::Bytes(vec![
+            prefix as u8 | named_postfix(n, mnemonic, input, UserCommand::from)?,
+        ]),
+        Prefix::SPECIAL if input.starts_with("spawn") => Item::Jump(
+            Instruction::Spawn,
+            operand(
+                n,
+                mnemonic,
+                input,
+                preceded(pair(tag("spawn"), space1), target),
+            )?,
+        ),
+        Prefix::SPECIAL if input.starts_with("position") => {
+            let name = operand(
+                n,
+                mnemonic,
+                input,
+                preceded(pair(tag("position"), space1), identifier),
+            )?;
+            let coordinate = by_name(name, Coordinate::from)
+                .and_then(Coordinate::from)
+                .ok_or_else(|| invalid(n, mnemonic, input))?;
+            encoded(Instruction::Position(coordinate))
+        }
+        Prefix::SPECIAL if input.starts_with("on") => {
+            // on button <n> to <end> | on param_changed <name> to <end>
+            let (kind, key, end) = operand(
+                n,
+                mnemonic,
+                input,
+                preceded(
+                    pair(tag("on"), space1),
+                    alt((
+                        tuple((
+                            map(tag("button"), |_| EventKind::BUTTON),
+                            preceded(
+                                space1,
+                                map_res(number, |v| u8::try_from(v).map(|b| vec![b])),
+                            ),
+                            preceded(space1, target),
+                        )),
+                        tuple((
+                            map(tag("param_changed"), |_| EventKind::PARAM_CHANGED),
+                            preceded(space1, map(identifier, |name| name.as_bytes().to_vec())),
+                            preceded(space1, target),
+                        )),
+                    )),
+                ),
+            )?;
+            if key.len() > u8::MAX as usize {
+                return Err(invalid(n, mnemonic, input));
+            }
+            Item::On(kind, key, end)
+        }
+        Prefix::SPECIAL => Item::Bytes(vec![
+            prefix as u8 | named_postfix(n, mnemonic, input, Special::from)?,
+        ]),
+    };
+    Ok(item)
+}
+
+impl FromAssembly for Program {
+    /// Assembles mnemonic source, accepting the listing printed by `Program`'s `Debug` implementation.
+    ///
+    /// Jump targets are either labels or numbers. When lines carry listing addresses (`0042.`),
+    /// numeric targets refer to those addresses, so instructions can be inserted or removed
+    /// without renumbering. Otherwise numeric targets are absolute.
+    fn from_assembly(source: &str) -> Result<Program, AssemblyError> {
+        let mut items = vec![];
+        let mut labels = HashMap::<String, usize>::new();
+        let mut addresses = HashMap::<usize, usize>::new();
+        let mut listing_end = None;
+        let mut pc = 0;
+
+        for (i, text) in source.lines().enumerate() {
+            let n = i + 1;
+            let l = match all_consuming(line)(text).finish() {
+                Ok((_, l)) => l,
+                Err(e) => return Err(AssemblyError::ParseError(n, e.input.to_string())),
+            };
+
+            if let Some(label) = l.label {
+                if labels.insert(label.to_string(), pc).is_some() {
+                    return Err(AssemblyError::RedefinedLabel(n, label.to_string()));
+                }
+            }
+
+            let item = match l.instruction {
+                Some((mnemonic, input)) => instruction(n, mnemonic, input)?,
+                None => continue,
+            };
+
+            if let Some(address) = l.address {
+                addresses.insert(address as usize, pc);
+                listing_end = Some((address as usize + item.len(), pc + item.len()));
+            }
+            pc += item.len();
+            items.push((n, item));
+        }
+
+        // A jump may target the address right after the last listed instruction
+        if let Some((address, pc)) = listing_end {
+            addresses.entry(address).or_insert(pc);
+        }
+
+        let resolve = |n: usize, target: Target| {
+            let target = match target {
+                Target::Label(label) => *labels
+                    .get(&label)
+                    .ok_or(AssemblyError::UndefinedLabel(n, label))?,
+                Target::Address(address) if !addresses.is_empty() => *addresses
+                    .get(&address)
+                    .ok_or(AssemblyError::UndefinedAddress(n, address))?,
+                Target::Address(address) => address,
+            };
+            u16::try_from(target).map_err(|_| AssemblyError::TargetOutOfRange(n, target))
+        };
+
+        let mut code = Vec::with_capacity(pc);
+        for (n, item) in items {
+            match item {
+                Item::Bytes(b) => code.extend(b),
+                Item::Jump(jump, target) => jump(resolve(n, target)?).encode(&mut code),
+                Item::On(kind, key, end) => Instruction::On {
+                    kind,
+                    key: &key,
+                    end: resolve(n, end)?,
+                }
+                .encode(&mut code),
+            }
+        }
+
+        Ok(Program::from_binary(code))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::FromSource;
+
+    #[test]
+    fn check_listing_round_trip() {
+        for source in [
+            include_str!("../example_progs/clamp.txt"),
+            include_str!("../example_progs/comment.txt"),
+            include_str!("../example_progs/fade_life.txt"),
+            include_str!("../example_progs/hue_rainbow.txt"),
+        ] {
+            let p = Program::from_source(source).unwrap();
+            let listing = format!("{:?}", p);
+            assert_eq!(Program::from_assembly(&listing).unwrap().code(), p.code());
+        }
+    }
+
+    #[test]
+    fn check_labels() {
+        let p = Program::from_assembly(
+            "
+            PUSHB [05]
+        top:
+            JZ to end       ; forward reference
+            USER blit
+            UNARY DEC
+            JMP top
+        end: POP 1
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            p.code(),
+            &vec![0x11, 0x05, 0x50, 0x0A, 0x00, 0xE4, 0x71, 0x40, 0x02, 0x00, 0x01]
+        );
+    }
+
+    #[test]
+    fn check_listing_addresses_follow_patch() {
+        // Inserting an instruction moves the jump target along with the listed address
+        let p = Program::from_assembly("0000.\t40\tJMP\tto 3\n\tUSER\tblit\n0003.\t01\tPOP\t1\n")
+            .unwrap();
+        assert_eq!(p.code(), &vec![0x40, 0x04, 0x00, 0xE4, 0x01]);
+    }
+
+    #[test]
+    fn check_errors() {
+        assert_eq!(
+            Program::from_assembly("JMP nowhere").unwrap_err(),
+            AssemblyError::UndefinedLabel(1, "nowhere".to_string())
+        );
+        assert_eq!(
+            Program::from_assembly("POP 1\nFOO 2").unwrap_err(),
+            AssemblyError::UnknownMnemonic(2, "FOO".to_string())
+        );
+        assert_eq!(
+            Program::from_assembly("POP 16").unwrap_err(),
+            AssemblyError::InvalidOperand(1, "POP".to_string(), "16".to_string())
+        );
+    }
+}
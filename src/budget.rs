@@ -0,0 +1,598 @@
+use crate::cost::CostModel;
+use crate::instructions::{DecodeError, Instruction, Special, Unary, UserCommand};
+use crate::program::Program;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::ops::{Add, Mul};
+
+/// Upper bound on a number of instructions or cycles
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Bound {
+    Finite(u64),
+    Unbounded,
+}
+
+impl Add for Bound {
+    type Output = Bound;
+
+    fn add(self, rhs: Bound) -> Bound {
+        match (self, rhs) {
+            (Bound::Finite(a), Bound::Finite(b)) => {
+                a.checked_add(b).map_or(Bound::Unbounded, Bound::Finite)
+            }
+            _ => Bound::Unbounded,
+        }
+    }
+}
+
+impl Mul for Bound {
+    type Output = Bound;
+
+    fn mul(self, rhs: Bound) -> Bound {
+        match (self, rhs) {
+            (Bound::Finite(0), _) | (_, Bound::Finite(0)) => Bound::Finite(0),
+            (Bound::Finite(a), Bound::Finite(b)) => {
+                a.checked_mul(b).map_or(Bound::Unbounded, Bound::Finite)
+            }
+            _ => Bound::Unbounded,
+        }
+    }
+}
+
+impl Bound {
+    fn saturating_sub(self, n: u64) -> Bound {
+        match self {
+            Bound::Finite(a) => Bound::Finite(a.saturating_sub(n)),
+            Bound::Unbounded => Bound::Unbounded,
+        }
+    }
+}
+
+impl fmt::Display for Bound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bound::Finite(n) => write!(f, "{}", n),
+            Bound::Unbounded => write!(f, "unbounded"),
+        }
+    }
+}
+
+/// Why no finite bound could be proven
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Unbounded {
+    /// `for` loop at pc whose trip count is not known statically
+    TripCount(usize),
+    /// `loop` at pc has a path through its body without `blit`
+    NoBlit(usize),
+    /// `spawn` at pc inside a loop or handler may start any number of tasks
+    RepeatedSpawn(usize),
+    /// Jump at pc does not match the shape of compiled statements
+    Unstructured(usize),
+}
+
+impl fmt::Display for Unbounded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unbounded::TripCount(pc) => write!(f, "trip count of for loop at {} is not known", pc),
+            Unbounded::NoBlit(pc) => {
+                write!(f, "loop at {} can repeat without blit or yield", pc)
+            }
+            Unbounded::RepeatedSpawn(pc) => write!(f, "spawn at {} may run more than once", pc),
+            Unbounded::Unstructured(pc) => {
+                write!(f, "jump at {} is not a compiled loop or branch", pc)
+            }
+        }
+    }
+}
+
+/// Result of `Program::frame_budget` and `Program::frame_cycles`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FrameBudget {
+    /// Most instructions or cycles spent between two blits, or between start or end and a blit,
+    /// summed over the main program and every task
+    pub per_frame: Bound,
+    /// Most instructions or cycles an event handler adds to the frame it runs in
+    pub per_event: Bound,
+    /// Constructs that made a bound unprovable, empty when both bounds are finite
+    pub reasons: Vec<Unbounded>,
+}
+
+impl FrameBudget {
+    /// True when every frame provably takes at most `limit` instructions or cycles, not counting
+    /// event handlers
+    pub fn fits(&self, limit: u64) -> bool {
+        self.per_frame <= Bound::Finite(limit)
+    }
+}
+
+impl Program {
+    /// Bounds the instructions executed per frame, that is per `VMState::run`, assuming a strip of
+    /// `strip_length` leds. `for` trip counts are evaluated from constants and `get_length`.
+    pub fn frame_budget(&self, strip_length: usize) -> Result<FrameBudget, DecodeError> {
+        self.bound_per_frame(strip_length, |_| 1)
+    }
+
+    /// Like `frame_budget` but bounds the cycles `model` charges per frame
+    pub fn frame_cycles(
+        &self,
+        strip_length: usize,
+        model: &dyn CostModel,
+    ) -> Result<FrameBudget, DecodeError> {
+        self.bound_per_frame(strip_length, |ins| model.cycles(ins, strip_length))
+    }
+
+    fn bound_per_frame(
+        &self,
+        strip_length: usize,
+        cost: impl Fn(&Instruction) -> u64,
+    ) -> Result<FrameBudget, DecodeError> {
+        let code = self.code();
+        let mut decoded: Vec<Option<(Instruction, usize)>> = vec![None; code.len()];
+        let mut costs = vec![0; code.len()];
+        let mut pc = 0;
+        while pc < code.len() {
+            let (ins, len) = Instruction::decode(code, pc)?;
+            decoded[pc] = Some((ins, len));
+            costs[pc] = cost(&ins);
+            pc += len;
+        }
+
+        let mut analysis = Analysis {
+            loops: BTreeMap::new(),
+            strip_length: strip_length as u32,
+            reasons: BTreeSet::new(),
+            per_event: Bound::Finite(0),
+            tasks: BTreeMap::new(),
+            loop_depth: 0,
+            counters: vec![],
+            decoded,
+            costs,
+        };
+        for (pc, ins) in analysis.decoded.iter().enumerate() {
+            if let Some((Instruction::Jmp(target), _)) = ins {
+                let target = *target as usize;
+                if target <= pc && !analysis.is_for(target, pc + 3) {
+                    analysis.loops.entry(target).or_default().push(pc);
+                }
+            }
+        }
+
+        let summary = analysis.region(0, code.len(), &mut vec![]);
+        let per_frame = analysis
+            .tasks
+            .values()
+            .fold(summary.longest(), |total, task| total + *task);
+        let per_event = analysis.per_event;
+        let reasons = match per_frame.max(per_event) {
+            Bound::Unbounded => analysis.reasons.into_iter().collect(),
+            Bound::Finite(_) => vec![],
+        };
+        Ok(FrameBudget {
+            per_frame,
+            per_event,
+            reasons,
+        })
+    }
+}
+
+/// Longest paths through a piece of code, `None` where no such path exists
+#[derive(Clone, Copy, Debug)]
+struct Summary {
+    /// Entry to exit without blit
+    through: Option<Bound>,
+    /// Entry to the first blit, including it
+    to_blit: Option<Bound>,
+    /// After the last blit to exit
+    from_blit: Option<Bound>,
+    /// Between two blits inside
+    inner: Option<Bound>,
+}
+
+const EMPTY: Summary = Summary {
+    through: Some(Bound::Finite(0)),
+    to_blit: None,
+    from_blit: None,
+    inner: None,
+};
+
+const UNBOUNDED: Summary = Summary {
+    through: Some(Bound::Unbounded),
+    to_blit: Some(Bound::Unbounded),
+    from_blit: Some(Bound::Unbounded),
+    inner: Some(Bound::Unbounded),
+};
+
+fn plus(a: Option<Bound>, b: Option<Bound>) -> Option<Bound> {
+    Some(a? + b?)
+}
+
+fn times(a: Option<Bound>, n: Bound) -> Option<Bound> {
+    a.map(|a| a * n)
+}
+
+impl Summary {
+    /// Longest of the paths, the most a frame or turn can take
+    fn longest(&self) -> Bound {
+        [self.through, self.to_blit, self.from_blit, self.inner]
+            .into_iter()
+            .max()
+            .flatten()
+            .unwrap_or(Bound::Finite(0))
+    }
+
+    fn straight(n: u64) -> Summary {
+        Summary {
+            through: Some(Bound::Finite(n)),
+            ..EMPTY
+        }
+    }
+
+    fn blit(n: u64) -> Summary {
+        Summary {
+            through: None,
+            to_blit: Some(Bound::Finite(n)),
+            from_blit: Some(Bound::Finite(0)),
+            inner: None,
+        }
+    }
+
+    fn then(self, next: Summary) -> Summary {
+        Summary {
+            through: plus(self.through, next.through),
+            to_blit: self.to_blit.max(plus(self.through, next.to_blit)),
+            from_blit: plus(self.from_blit, next.through).max(next.from_blit),
+            inner: self
+                .inner
+                .max(next.inner)
+                .max(plus(self.from_blit, next.to_blit)),
+        }
+    }
+
+    fn or(self, other: Summary) -> Summary {
+        Summary {
+            through: self.through.max(other.through),
+            to_blit: self.to_blit.max(other.to_blit),
+            from_blit: self.from_blit.max(other.from_blit),
+            inner: self.inner.max(other.inner),
+        }
+    }
+
+    /// `n` iterations of `self` back to back
+    fn repeat(self, n: Bound) -> Summary {
+        if n == Bound::Finite(0) {
+            return EMPTY;
+        }
+        // Paths may start or end in any iteration, the longest ones use the first and last
+        let before_last = times(self.through, n.saturating_sub(1));
+        let inner = if n > Bound::Finite(1) {
+            let between = times(self.through, n.saturating_sub(2));
+            plus(self.from_blit, self.to_blit)
+                .max(plus(plus(self.from_blit, between), self.to_blit))
+        } else {
+            None
+        };
+        Summary {
+            through: times(self.through, n),
+            to_blit: self.to_blit.max(plus(before_last, self.to_blit)),
+            from_blit: self.from_blit.max(plus(self.from_blit, before_last)),
+            inner: self.inner.max(inner),
+        }
+    }
+
+    /// `self` repeated without end, only left through blits
+    fn forever(self) -> Summary {
+        if self.through.is_some() {
+            return Summary {
+                through: None,
+                to_blit: Some(Bound::Unbounded),
+                from_blit: None,
+                inner: Some(Bound::Unbounded),
+            };
+        }
+        Summary {
+            through: None,
+            to_blit: self.to_blit,
+            from_blit: None,
+            inner: self.inner.max(plus(self.from_blit, self.to_blit)),
+        }
+    }
+}
+
+struct Analysis<'a> {
+    decoded: Vec<Option<(Instruction<'a>, usize)>>,
+    /// Start of every `loop` mapped to the pcs of the jumps closing it
+    loops: BTreeMap<usize, Vec<usize>>,
+    /// Cost of the instruction starting at each pc
+    costs: Vec<u64>,
+    strip_length: u32,
+    reasons: BTreeSet<Unbounded>,
+    /// Longest event handler seen so far
+    per_event: Bound,
+    /// Longest turn of the task started by the `spawn` at each pc
+    tasks: BTreeMap<usize, Bound>,
+    /// Number of loop bodies and handlers around the code being summarized
+    loop_depth: usize,
+    /// Stack slots of the `for` counters around the code being summarized, and whether the
+    /// loop body writes them
+    counters: Vec<(usize, bool)>,
+}
+
+impl<'a> Analysis<'a> {
+    fn ins(&self, pc: usize) -> Option<Instruction<'a>> {
+        self.decoded.get(pc).copied().flatten().map(|(ins, _)| ins)
+    }
+
+    /// Straight line summary of the instruction at `pc`
+    fn cost(&self, pc: usize) -> Summary {
+        Summary::straight(self.costs[pc])
+    }
+
+    /// True for the `[JZ end][body][DEC][JMP start]` shape `Program::repeat` emits
+    fn is_for(&self, start: usize, end: usize) -> bool {
+        end >= start + 7
+            && matches!(self.ins(start), Some(Instruction::Jz(t)) if t as usize == end)
+            && matches!(self.ins(end - 3), Some(Instruction::Jmp(t)) if t as usize == start)
+            && matches!(self.ins(end - 4), Some(Instruction::Unary(Unary::DEC)))
+    }
+
+    /// Summarizes code from `start` to `end`, tracking statically known stack values in `stack`
+    fn region(&mut self, start: usize, end: usize, stack: &mut Vec<Option<u32>>) -> Summary {
+        let mut summary = EMPTY;
+        let mut pc = start;
+        while pc < end {
+            let closing = self
+                .loops
+                .get(&pc)
+                .and_then(|ends| ends.iter().copied().filter(|e| *e < end).max());
+            if let Some(jmp) = closing {
+                let body = self.body(pc, jmp, stack);
+                if body.through.is_some() {
+                    self.reasons.insert(Unbounded::NoBlit(pc));
+                }
+                summary = summary.then(body.then(self.cost(jmp)).forever());
+                pc = jmp + 3;
+                continue;
+            }
+
+            let Some((ins, len)) = self.decoded.get(pc).copied().flatten() else {
+                self.reasons.insert(Unbounded::Unstructured(pc));
+                return summary.then(UNBOUNDED);
+            };
+            match ins {
+                Instruction::Jz(t) if self.is_for(pc, t as usize) && (t as usize) <= end => {
+                    let t = t as usize;
+                    let mut count = stack.last().copied().flatten();
+                    if let Some(slot) = stack.len().checked_sub(1) {
+                        self.written(slot..slot + 1);
+                        stack[slot] = None;
+                        self.counters.push((slot, false));
+                    }
+                    let body = self.body(pc + len, t - 4, stack);
+                    // A body assigning the counter runs any number of iterations
+                    if stack.last().is_some() && self.counters.pop().is_some_and(|(_, w)| w) {
+                        count = None;
+                    }
+                    let iteration = self
+                        .cost(pc)
+                        .then(body)
+                        .then(self.cost(t - 4))
+                        .then(self.cost(t - 3));
+                    let iterations = match count {
+                        Some(n) => iteration.repeat(Bound::Finite(n as u64)),
+                        None => {
+                            if iteration.through.is_some() {
+                                self.reasons.insert(Unbounded::TripCount(pc));
+                            }
+                            iteration.repeat(Bound::Unbounded).or(EMPTY)
+                        }
+                    };
+                    summary = summary.then(iterations).then(self.cost(pc));
+                    if let Some(top) = stack.last_mut() {
+                        *top = Some(0);
+                    }
+                    pc = t;
+                }
+                Instruction::Jz(t) | Instruction::Jnz(t)
+                    if (t as usize) > pc && (t as usize) <= end =>
+                {
+                    let t = t as usize;
+                    let mut branch = stack.clone();
+                    let body = self.region(pc + len, t, &mut branch);
+                    merge(stack, &branch);
+                    summary = summary.then(self.cost(pc)).then(body.or(EMPTY));
+                    pc = t;
+                }
+                // Handlers run on their own stack whenever an event is raised
+                Instruction::On { end: t, .. } if (t as usize) > pc && (t as usize) <= end => {
+                    let t = t as usize;
+                    self.loop_depth += 1;
+                    let handler = self.detached(pc + len, t);
+                    self.loop_depth -= 1;
+                    self.per_event = self.per_event.max(handler.longest());
+                    summary = summary.then(self.cost(pc));
+                    pc = t;
+                }
+                // Tasks take a turn in every frame
+                Instruction::Spawn(t) if (t as usize) > pc && (t as usize) <= end => {
+                    let t = t as usize;
+                    let turn = if self.loop_depth > 0 {
+                        self.reasons.insert(Unbounded::RepeatedSpawn(pc));
+                        Bound::Unbounded
+                    } else {
+                        self.detached(pc + len, t).longest()
+                    };
+                    self.tasks.insert(pc, turn);
+                    summary = summary.then(self.cost(pc));
+                    pc = t;
+                }
+                Instruction::Jmp(_)
+                | Instruction::Jz(_)
+                | Instruction::Jnz(_)
+                | Instruction::On { .. }
+                | Instruction::Spawn(_) => {
+                    self.reasons.insert(Unbounded::Unstructured(pc));
+                    return summary.then(UNBOUNDED);
+                }
+                Instruction::User(UserCommand::BLIT | UserCommand::WAIT)
+                | Instruction::Special(Special::YIELD) => {
+                    summary = summary.then(Summary::blit(self.costs[pc]));
+                    pc += len;
+                }
+                _ => {
+                    self.step(ins, stack);
+                    summary = summary.then(self.cost(pc));
+                    pc += len;
+                }
+            }
+        }
+        summary
+    }
+
+    /// Summarizes a loop body, forgetting stack values it changes until they are stable
+    fn body(&mut self, start: usize, end: usize, stack: &mut [Option<u32>]) -> Summary {
+        self.loop_depth += 1;
+        let summary = loop {
+            let mut after = stack.to_vec();
+            let summary = self.region(start, end, &mut after);
+            if !merge(stack, &after) {
+                break summary;
+            }
+        };
+        self.loop_depth -= 1;
+        summary
+    }
+
+    /// Summarizes code running on a stack of its own, like handlers and tasks
+    fn detached(&mut self, start: usize, end: usize) -> Summary {
+        let counters = std::mem::take(&mut self.counters);
+        let summary = self.region(start, end, &mut vec![]);
+        self.counters = counters;
+        summary
+    }
+
+    /// Marks the `for` counters among `slots` as written
+    fn written(&mut self, slots: std::ops::Range<usize>) {
+        for (slot, written) in &mut self.counters {
+            *written |= slots.contains(slot);
+        }
+    }
+
+    fn step(&mut self, ins: Instruction, stack: &mut Vec<Option<u32>>) {
+        let len = stack.len();
+        match ins {
+            Instruction::PushB(_) | Instruction::PushI(_) | Instruction::Peek(_) => {}
+            Instruction::Swap(n) => {
+                if let Some(i) = len.checked_sub(n as usize + 1) {
+                    self.written(i..i + 1);
+                    self.written(len - 1..len);
+                }
+            }
+            Instruction::User(UserCommand::GET_LENGTH) => {}
+            _ => {
+                let (inputs, _) = ins.stack_effect();
+                self.written(len.saturating_sub(inputs)..len);
+            }
+        }
+        match ins {
+            Instruction::PushB(_) | Instruction::PushI(_) => {
+                stack.extend(ins.immediates().map(Some))
+            }
+            Instruction::Peek(n) => {
+                let value = stack
+                    .len()
+                    .checked_sub(n as usize + 1)
+                    .and_then(|i| stack[i]);
+                stack.push(value);
+            }
+            Instruction::Swap(n) => {
+                if let Some(i) = stack.len().checked_sub(n as usize + 1) {
+                    let last = stack.len() - 1;
+                    stack.swap(i, last);
+                }
+            }
+            Instruction::Unary(op) => {
+                if let Some(top) = stack.last_mut() {
+                    *top = top.and_then(|v| op.apply(v).ok());
+                }
+            }
+            Instruction::Binary(op) => {
+                let (rhs, lhs) = (stack.pop().flatten(), stack.pop().flatten());
+                stack.push(lhs.zip(rhs).and_then(|(l, r)| op.apply(l, r).ok()));
+            }
+            Instruction::User(UserCommand::GET_LENGTH) => stack.push(Some(self.strip_length)),
+            _ => {
+                let (inputs, outputs) = ins.stack_effect();
+                stack.truncate(stack.len().saturating_sub(inputs));
+                stack.extend(std::iter::repeat_n(None, outputs));
+            }
+        }
+    }
+}
+
+/// Forgets values of `stack` which differ in `other`, returning whether any did
+fn merge(stack: &mut [Option<u32>], other: &[Option<u32>]) -> bool {
+    let mut changed = false;
+    for (i, value) in stack.iter_mut().enumerate() {
+        if value.is_some() && *value != other.get(i).copied().flatten() {
+            *value = None;
+            changed = true;
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::FromSource;
+    use crate::vm::{VMStateConfig, VM};
+
+    #[test]
+    fn check_frame_budget() {
+        let source = "let a = 0;\nloop {\n    for(i=get_length) {\n        if(i > a) {\n            set_pixel(i - 1, 1, 2, 3, 4);\n        };\n    };\n    blit;\n}";
+        let p = Program::from_source(source).unwrap();
+        let config = VMStateConfig {
+            profile: true,
+            ..Default::default()
+        };
+        let mut state = VM::new(5, Default::default()).start(p.clone(), config);
+        for _ in 0..3 {
+            state.run();
+        }
+        // Every branch is taken, which is the worst case the analysis assumes
+        let longest = state.profile().unwrap().frames().iter().max().copied();
+        let budget = p.frame_budget(5).unwrap();
+        assert_eq!(
+            Some(budget.per_frame),
+            longest.map(|n| Bound::Finite(n as u64))
+        );
+        assert!(budget.fits(1000) && budget.reasons.is_empty());
+        assert!(p.frame_budget(50).unwrap().per_frame > budget.per_frame);
+
+        let p =
+            Program::from_source("for(i=random(5)) { set_pixel(0, 1, 2, 3, 4); }; blit;").unwrap();
+        let budget = p.frame_budget(5).unwrap();
+        assert_eq!(budget.per_frame, Bound::Unbounded);
+        assert!(matches!(budget.reasons[..], [Unbounded::TripCount(_)]));
+
+        // Loops whose body always blits are fine whatever their trip count
+        let p = Program::from_source("for(i=random(5)) { blit; }; loop { blit; }").unwrap();
+        assert!(p.frame_budget(5).unwrap().fits(20));
+
+        let p = Program::from_source("loop { if(random(2)) { blit; }; }").unwrap();
+        let budget = p.frame_budget(5).unwrap();
+        assert!(!budget.fits(u64::MAX));
+        assert!(matches!(budget.reasons[..], [Unbounded::NoBlit(0)]));
+
+        // Assigning the counter makes the trip count unknown
+        let source = "for(i = 2) { i = 200000; set_pixel(0, 1, 0, 0, 0); }; blit;";
+        let budget = Program::from_source(source)
+            .unwrap()
+            .frame_budget(10)
+            .unwrap();
+        assert_eq!(budget.per_frame, Bound::Unbounded);
+        assert!(matches!(budget.reasons[..], [Unbounded::TripCount(_)]));
+        let p = Program::from_source("for(i = 2) { let j = i; j = 5; }; blit;").unwrap();
+        assert_eq!(p.frame_budget(10).unwrap().reasons, vec![]);
+    }
+}
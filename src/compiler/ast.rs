@@ -1,5 +1,8 @@
 use crate::instructions;
 use crate::program::{Program, SyntaxError};
+use crate::source_map::Span;
+use crate::vm::event::Event;
+use crate::vm::host::HostCommands;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Node {
@@ -14,6 +17,13 @@ pub enum Node {
     NewVarAssignment(String, Expression),
     VarAssignment(String, Expression),
     For(String, Expression, Vec<Node>),
+    /// Handler run when the host raises the event
+    On(Event, Vec<Node>),
+    /// Task running alongside the rest of the program
+    Spawn(Vec<Node>),
+    /// Statement starting the given number of bytes before the end of the source,
+    /// parsers only see the remaining input so the position is resolved through `Scope`
+    Located(usize, Box<Node>),
 }
 
 #[derive(Debug, Default)]
@@ -21,6 +31,8 @@ pub struct Scope<'a> {
     variables: Vec<String>,
     level: u32,
     parent: Option<&'a Scope<'a>>,
+    source: Option<&'a str>,
+    commands: Option<&'a HostCommands>,
 }
 
 impl<'a> Scope<'a> {
@@ -28,11 +40,38 @@ impl<'a> Scope<'a> {
         Self::default()
     }
 
+    /// Top level scope recording the source position of every statement in the program
+    pub fn with_source(source: &'a str) -> Self {
+        Scope {
+            source: Some(source),
+            ..Self::default()
+        }
+    }
+
+    /// Resolves calls to unknown functions through `commands`
+    pub fn with_commands(self, commands: &'a HostCommands) -> Self {
+        Scope {
+            commands: Some(commands),
+            ..self
+        }
+    }
+
     pub fn nest(&'a self) -> Scope<'a> {
         Scope {
             parent: Some(self),
             level: 0,
             variables: vec![],
+            source: self.source,
+            commands: self.commands,
+        }
+    }
+
+    /// Scope for code running on its own stack, like event handlers and tasks, sharing only source and commands
+    pub fn isolated(&self) -> Scope<'a> {
+        Scope {
+            source: self.source,
+            commands: self.commands,
+            ..Self::default()
         }
     }
 
@@ -77,7 +116,22 @@ impl<'a> Scope<'a> {
         }
     }
 
+    /// Stack slot, counted from the bottom, of the first value in this scope
+    fn base(&self) -> usize {
+        self.parent.map_or(0, |p| p.base() + p.level as usize)
+    }
+
+    /// Records the most recently defined variable in the program's debug info
+    fn record_variable(&self, program: &mut Program) {
+        if let Some(name) = self.variables.last() {
+            program.open_variable(name, self.base() + self.variables.len() - 1);
+        }
+    }
+
     pub(crate) fn assemble_teardown(&self, program: &mut Program) -> Result<(), SyntaxError> {
+        for name in self.variables.iter() {
+            program.close_variable(name);
+        }
         if !self.variables.is_empty() {
             program.pop(self.variables.len() as u8)?;
         }
@@ -89,6 +143,12 @@ impl<'a> Scope<'a> {
 impl Node {
     pub fn assemble(&self, program: &mut Program, scope: &mut Scope) -> Result<(), SyntaxError> {
         match self {
+            Node::Expression(Expression::Call(name, args)) => {
+                let outputs = Expression::assemble_call(name, args, program, scope)?;
+                if outputs > 0 {
+                    program.pop(outputs)?;
+                }
+            }
             Node::Expression(e) => {
                 e.assemble(program, scope)?;
                 program.pop(1)?;
@@ -143,9 +203,11 @@ impl Node {
                         scope.level = pre_level;
                     }
                     _ => {
+                        let pre_level = scope.level;
                         for param in e.iter() {
                             param.assemble(program, scope)?;
                         }
+                        scope.level = pre_level;
                     }
                 }
                 program.user(*s);
@@ -156,6 +218,14 @@ impl Node {
                     i.assemble(program, scope)?;
                 }
             }
+            Node::Located(rest, node) => {
+                if let Some(source) = scope.source {
+                    if let Some(offset) = source.len().checked_sub(*rest) {
+                        program.mark(Span::at(source, offset));
+                    }
+                }
+                node.assemble(program, scope)?;
+            }
             Node::Loop(stmts) => {
                 program.repeat_forever(|q| {
                     let mut child_scope = scope.nest();
@@ -169,6 +239,7 @@ impl Node {
             Node::For(variable_name, expression, stmts) => {
                 expression.assemble(program, scope)?;
                 scope.define_variable(variable_name)?;
+                scope.record_variable(program);
                 program.repeat(|q| {
                     let mut child_scope = scope.nest();
                     for i in stmts.iter() {
@@ -181,9 +252,28 @@ impl Node {
 
                 // Undefine variable
                 scope.undefine_variable(variable_name)?;
+                program.close_variable(variable_name);
                 scope.level -= 1;
                 program.pop(1)?;
             }
+            Node::On(event, stmts) => {
+                program.on(event, |q| {
+                    let mut handler_scope = scope.isolated();
+                    for i in stmts.iter() {
+                        i.assemble(q, &mut handler_scope)?;
+                    }
+                    handler_scope.assemble_teardown(q)
+                })?;
+            }
+            Node::Spawn(stmts) => {
+                program.spawn(|q| {
+                    let mut task_scope = scope.isolated();
+                    for i in stmts.iter() {
+                        i.assemble(q, &mut task_scope)?;
+                    }
+                    task_scope.assemble_teardown(q)
+                })?;
+            }
             Node::If(e, ss) => {
                 let old_level = scope.level;
                 e.assemble(program, scope)?;
@@ -223,6 +313,7 @@ impl Node {
             Node::NewVarAssignment(variable_name, expression) => {
                 expression.assemble(program, scope)?;
                 scope.define_variable(variable_name)?; // Value left on the stack but cleaned up later by Scope::assemble_teardown
+                scope.record_variable(program);
             }
             Node::VarAssignment(variable_name, expression) => {
                 let old_level = scope.level;
@@ -252,8 +343,12 @@ pub enum Expression {
     Binary(Box<Expression>, instructions::Binary, Box<Expression>),
     User(instructions::UserCommand),
     UserCall(instructions::UserCommand, Vec<Expression>),
+    /// Host command, see `vm::host`
+    Call(String, Vec<Expression>),
     Load(String),
     Intrinsic(Intrinsic),
+    /// Layout coordinate of the led at the index, see `vm::layout`
+    Position(instructions::Coordinate, Box<Expression>),
 }
 
 impl Expression {
@@ -282,10 +377,20 @@ impl Expression {
                 program.user(*s);
                 scope.level = old_level + 1;
             }
+            Expression::Call(name, args) => {
+                if Self::assemble_call(name, args, program, scope)? != 1 {
+                    return Err(SyntaxError::NoValue(name.clone()));
+                }
+                scope.level += 1;
+            }
             Expression::Unary(op, rhs) => {
                 rhs.assemble(program, scope)?;
                 program.unary(*op);
             }
+            Expression::Position(coordinate, index) => {
+                index.assemble(program, scope)?;
+                program.position(*coordinate);
+            }
             Expression::Binary(lhs, op, rhs) => {
                 lhs.assemble(program, scope)?;
                 rhs.assemble(program, scope)?;
@@ -311,46 +416,29 @@ impl Expression {
                         program.peek(1)?; // [min, value, min, value]
                         program.binary(instructions::Binary::LT); // [value < min, min, value]
 
-                        // value < min
+                        // value < min, overwrite value with min
                         program.if_not_zero(|b| {
-                            b.pop(1)?; // [min, value]
-                            b.swap(1)?; // [value, min]
-                            b.pop(1)?; // [min]
-                            b.leave_on_stack(-2);
+                            b.peek(1)?; // [min, value < min, min, value]
+                            b.swap(3)?; // [value, value < min, min, min]
+                            b.pop(1)?; // [value < min, min, min]
                             Ok(())
                         })?;
-
-                        // value >= min
-                        program.if_zero(|b| {
-                            b.pop(2)?; // [value]
-                            b.leave_on_stack(-2);
-                            Ok(())
-                        })?;
-
-                        program.leave_on_stack(2);
+                        program.pop(2)?; // [previous_result]
 
                         max.assemble(program, scope)?; // [max, previous_result]
                         program.peek(1)?; // [previous_result, max, previous_result]
                         program.peek(1)?; // [max, previous_result, max, previous_result]
                         program.binary(instructions::Binary::GT); // [previous_result > max, max, previous_result]
 
-                        // previous_result > max
+                        // previous_result > max, overwrite previous_result with max
                         program.if_not_zero(|b| {
-                            b.pop(1)?; // [max, previous_result]
-                            b.swap(1)?; // [previous_result, max]
-                            b.pop(1)?; // [max]
-                            b.leave_on_stack(-2);
-                            Ok(())
-                        })?;
-
-                        // previous_result <= max
-                        program.if_zero(|b| {
-                            b.pop(2)?; // [previous_result]
-                            b.leave_on_stack(-2);
+                            b.peek(1)?; // [max, previous_result > max, max, previous_result]
+                            b.swap(3)?; // [previous_result, previous_result > max, max, max]
+                            b.pop(1)?; // [previous_result > max, max, max]
                             Ok(())
                         })?;
+                        program.pop(2)?; // [result]
 
-                        program.leave_on_stack(2);
                         scope.level = old_level + 1;
                     }
                 }
@@ -360,10 +448,41 @@ impl Expression {
         Ok(())
     }
 
+    /// Evaluates `args` and calls host command `name`, returning how many values it pushed
+    fn assemble_call(
+        name: &str,
+        args: &[Expression],
+        program: &mut Program,
+        scope: &mut Scope,
+    ) -> Result<u8, SyntaxError> {
+        let (id, signature) = scope
+            .commands
+            .and_then(|c| c.lookup(name))
+            .ok_or_else(|| SyntaxError::UnknownFunction(name.to_string()))?;
+        if args.len() != signature.inputs as usize {
+            return Err(SyntaxError::ArgumentCount(
+                name.to_string(),
+                signature.inputs as usize,
+                args.len(),
+            ));
+        }
+
+        let old_level = scope.level;
+        for arg in args {
+            arg.assemble(program, scope)?;
+        }
+        program.host(id, signature.inputs, signature.outputs);
+        scope.level = old_level;
+        Ok(signature.outputs)
+    }
+
     fn const_value(&self) -> Option<u32> {
         match &self {
             Expression::Literal(u) => Some(*u),
-            Expression::UserCall(_, _) | Expression::User(_) => None,
+            Expression::UserCall(_, _)
+            | Expression::User(_)
+            | Expression::Call(_, _)
+            | Expression::Position(_, _) => None,
             Expression::Load(_var_name) => None,
             Expression::Binary(lhs, op, rhs) => {
                 if let (Some(lhc), Some(rhc)) = (lhs.const_value(), rhs.const_value()) {
@@ -10,6 +10,9 @@ use nom::{
 };
 
 use crate::program::Program;
+use crate::source_map::SourceMap;
+use crate::vm::event::Event;
+use crate::vm::host::HostCommands;
 use crate::{instructions, program::SyntaxError};
 use ast::{Expression, Intrinsic, Node, Scope};
 
@@ -48,11 +51,11 @@ fn sp(input: &str) -> IResult<&str, ()> {
     Ok((input, ()))
 }
 
-fn hex_number(input: &str) -> IResult<&str, u32> {
+pub(crate) fn hex_number(input: &str) -> IResult<&str, u32> {
     map_res(take_while1(is_hex_digit), from_hex)(input)
 }
 
-fn dec_number(input: &str) -> IResult<&str, u32> {
+pub(crate) fn dec_number(input: &str) -> IResult<&str, u32> {
     map_res(take_while1(is_dec_digit), from_dec)(input)
 }
 
@@ -60,7 +63,7 @@ fn variable_name(input: &str) -> IResult<&str, &str> {
     take_while1(|c: char| c.is_alphabetic())(input)
 }
 
-fn hex_literal(input: &str) -> IResult<&str, u32> {
+pub(crate) fn hex_literal(input: &str) -> IResult<&str, u32> {
     let (input, _) = tag("0x")(input)?;
     let (input, num) = hex_number(input)?;
     Ok((input, num))
@@ -75,6 +78,24 @@ fn load_expression(input: &str) -> IResult<&str, Expression> {
     map(variable_name, |v| Expression::Load(v.to_string()))(input)
 }
 
+fn function_name(input: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')(input)
+}
+
+// name(a, b, ...), resolved against the host commands when assembling
+fn call_expression(input: &str) -> IResult<&str, Expression> {
+    map(
+        tuple((
+            function_name,
+            tag("("),
+            sp,
+            separated_list0(tag(","), preceded(sp, terminated(expression, sp))),
+            tag(")"),
+        )),
+        |t| Expression::Call(t.0.to_string(), t.3),
+    )(input)
+}
+
 fn bracketed_expression(input: &str) -> IResult<&str, Expression> {
     preceded(tag("("), terminated(expression, tag(")")))(input)
 }
@@ -83,6 +104,7 @@ fn term(input: &str) -> IResult<&str, Expression> {
     alt((
         literal,
         user_expression,
+        call_expression,
         load_expression,
         bracketed_expression,
     ))(input)
@@ -243,7 +265,12 @@ fn expression_statement(input: &str) -> IResult<&str, Node> {
 }
 
 fn special_statement(input: &str) -> IResult<&str, Node> {
-    map(tag("dump"), |_| Node::Special(instructions::Special::DUMP))(input)
+    alt((
+        map(tag("dump"), |_| Node::Special(instructions::Special::DUMP)),
+        map(tag("yield"), |_| {
+            Node::Special(instructions::Special::YIELD)
+        }),
+    ))(input)
 }
 
 fn user_statement(input: &str) -> IResult<&str, Node> {
@@ -271,20 +298,106 @@ fn user_statement(input: &str) -> IResult<&str, Node> {
                 )
             },
         ),
+        // set_xy(x, y, r, g, b, w)
+        map(
+            tuple((
+                tag("set_xy("),
+                preceded(sp, terminated(expression, sp)),
+                tag(","),
+                preceded(sp, terminated(expression, sp)),
+                tag(","),
+                preceded(sp, terminated(expression, sp)),
+                tag(","),
+                preceded(sp, terminated(expression, sp)),
+                tag(","),
+                preceded(sp, terminated(expression, sp)),
+                tag(","),
+                preceded(sp, terminated(expression, sp)),
+                tag(")"),
+            )),
+            |t| {
+                let index = Expression::UserCall(instructions::UserCommand::XY, vec![t.1, t.3]);
+                Node::UserCall(
+                    instructions::UserCommand::SET_PIXEL,
+                    vec![index, t.5, t.7, t.9, t.11],
+                )
+            },
+        ),
+        map(tuple((tag("wait("), expression, tag(")"))), |t| {
+            Node::UserCall(instructions::UserCommand::WAIT, vec![t.1])
+        }),
+        map(tuple((tag("frame_rate("), expression, tag(")"))), |t| {
+            Node::UserCall(instructions::UserCommand::FRAME_RATE, vec![t.1])
+        }),
     ))(input)
 }
 
+/// `(x, y)` of `xy` and `get_xy`, the index of the led showing `x`, `y`
+fn xy(input: &str) -> IResult<&str, Expression> {
+    map(
+        tuple((
+            tag("("),
+            preceded(sp, terminated(expression, sp)),
+            tag(","),
+            preceded(sp, terminated(expression, sp)),
+            tag(")"),
+        )),
+        |t| Expression::UserCall(instructions::UserCommand::XY, vec![t.1, t.3]),
+    )(input)
+}
+
+/// `pos_x(i)`, `pos_y(i)`, `pos_z(i)`, `angle(i)` and `radius(i)`
+fn position(input: &str) -> IResult<&str, Expression> {
+    map(
+        tuple((
+            alt((
+                map(tag("pos_x("), |_| instructions::Coordinate::X),
+                map(tag("pos_y("), |_| instructions::Coordinate::Y),
+                map(tag("pos_z("), |_| instructions::Coordinate::Z),
+                map(tag("angle("), |_| instructions::Coordinate::ANGLE),
+                map(tag("radius("), |_| instructions::Coordinate::RADIUS),
+            )),
+            expression,
+            tag(")"),
+        )),
+        |t| Expression::Position(t.0, Box::new(t.1)),
+    )(input)
+}
+
 fn user_expression(input: &str) -> IResult<&str, Expression> {
     alt((
+        position,
         map(tuple((tag("random("), expression, tag(")"))), |t| {
             Expression::UserCall(instructions::UserCommand::RANDOM_INT, vec![t.1])
         }),
         map(tuple((tag("get_pixel("), expression, tag(")"))), |t| {
             Expression::UserCall(instructions::UserCommand::GET_PIXEL, vec![t.1])
         }),
+        map(preceded(tag("get_xy"), xy), |index| {
+            Expression::UserCall(instructions::UserCommand::GET_PIXEL, vec![index])
+        }),
+        preceded(tag("xy"), xy),
+        map(tuple((tag("input_changed("), expression, tag(")"))), |t| {
+            Expression::UserCall(instructions::UserCommand::INPUT_CHANGED, vec![t.1])
+        }),
+        map(tuple((tag("input("), expression, tag(")"))), |t| {
+            Expression::UserCall(instructions::UserCommand::GET_INPUT, vec![t.1])
+        }),
         map(tag("get_length"), |_| {
             Expression::User(instructions::UserCommand::GET_LENGTH)
         }),
+        map(tag("get_width"), |_| {
+            Expression::UserCall(
+                instructions::UserCommand::SIZE,
+                vec![Expression::Literal(0)],
+            )
+        }),
+        map(tag("get_height"), |_| {
+            Expression::UserCall(
+                instructions::UserCommand::SIZE,
+                vec![Expression::Literal(1)],
+            )
+        }),
         map(tag("get_wall_time"), |_| {
             Expression::User(instructions::UserCommand::GET_WALL_TIME)
         }),
@@ -428,6 +541,94 @@ fn loop_statement(input: &str) -> IResult<&str, Node> {
     )(input)
 }
 
+fn event(input: &str) -> IResult<&str, Event> {
+    alt((
+        map(
+            delimited(
+                tag("button("),
+                preceded(
+                    sp,
+                    terminated(map_res(alt((hex_literal, dec_number)), u8::try_from), sp),
+                ),
+                tag(")"),
+            ),
+            Event::Button,
+        ),
+        map(
+            delimited(
+                tag("param_changed("),
+                preceded(sp, terminated(variable_name, sp)),
+                tag(")"),
+            ),
+            |name| Event::ParamChanged(name.to_string()),
+        ),
+    ))(input)
+}
+
+fn on_statement(input: &str) -> IResult<&str, Node> {
+    map(
+        tuple((tag("on"), sp, event, sp, tag("{"), sp, program, tag("}"))),
+        |t| {
+            if let Node::Statements(ss) = t.6 {
+                Node::On(t.2, ss)
+            } else {
+                unreachable!()
+            }
+        },
+    )(input)
+}
+
+/// Duration in milliseconds, `500ms` or `2s`
+fn duration(input: &str) -> IResult<&str, u32> {
+    alt((
+        terminated(dec_number, tag("ms")),
+        map_res(terminated(dec_number, tag("s")), |s| {
+            s.checked_mul(1000).ok_or("duration out of range")
+        }),
+    ))(input)
+}
+
+fn timer_statement(input: &str) -> IResult<&str, Node> {
+    map(
+        tuple((
+            alt((
+                map(tag("every"), |_| instructions::UserCommand::EVERY),
+                map(tag("after"), |_| instructions::UserCommand::AFTER),
+            )),
+            sp,
+            duration,
+            sp,
+            tag("{"),
+            sp,
+            program,
+            tag("}"),
+        )),
+        |t| {
+            if let Node::Statements(ss) = t.6 {
+                Node::If(
+                    Expression::UserCall(t.0, vec![Expression::Literal(t.2)]),
+                    ss,
+                )
+            } else {
+                unreachable!()
+            }
+        },
+    )(input)
+}
+
+fn spawn_statement(input: &str) -> IResult<&str, Node> {
+    map(
+        tuple((tag("spawn"), sp, tag("{"), sp, program, tag("}"))),
+        |t| {
+            if let Node::Statements(ss) = t.4 {
+                Node::Spawn(ss)
+            } else {
+                unreachable!()
+            }
+        },
+    )(input)
+}
+
 fn comment(input: &str) -> IResult<&str, &str> {
     alt((multi_line_comment, single_line_comment))(input)
 }
@@ -488,11 +689,21 @@ fn var_assigment_statement(input: &str) -> IResult<&str, Node> {
     )(input)
 }
 
+fn located<'a, F>(mut parser: F) -> impl FnMut(&'a str) -> IResult<&'a str, Node>
+where
+    F: FnMut(&'a str) -> IResult<&'a str, Node>,
+{
+    move |input: &'a str| {
+        let (remainder, node) = parser(input)?;
+        Ok((remainder, Node::Located(input.len(), Box::new(node))))
+    }
+}
+
 fn statement(input: &str) -> IResult<&str, Node> {
     terminated(
         preceded(
             sp,
-            alt((
+            located(alt((
                 user_statement,
                 special_statement,
                 new_var_assigment_statement,
@@ -500,8 +711,11 @@ fn statement(input: &str) -> IResult<&str, Node> {
                 if_statement,
                 for_statement,
                 loop_statement,
+                on_statement,
+                spawn_statement,
+                timer_statement,
                 expression_statement,
-            )),
+            ))),
         ),
         sp,
     )(input)
@@ -523,21 +737,34 @@ fn program(input: &str) -> IResult<&str, Node> {
     )(input)
 }
 
-pub trait FromSource {
-    fn from_source(source: &str) -> Result<Program, SyntaxError>;
+pub trait FromSource: Sized {
+    fn from_source(source: &str) -> Result<Self, SyntaxError> {
+        Self::from_source_with_commands(source, &HostCommands::new())
+    }
+
+    /// Compiles `source` which may call the host commands in `commands`, usually `VM::commands`
+    fn from_source_with_commands(
+        source: &str,
+        commands: &HostCommands,
+    ) -> Result<Self, SyntaxError>;
 }
 
 impl FromSource for Program {
-    fn from_source(source: &str) -> Result<Program, SyntaxError> {
+    fn from_source_with_commands(
+        source: &str,
+        commands: &HostCommands,
+    ) -> Result<Program, SyntaxError> {
         match program(source).finish() {
             Ok((remainder, n)) => {
                 if !remainder.is_empty() {
                     Err(SyntaxError::CouldNotParseRamainder(remainder.to_string()))
                 } else {
                     let mut p = Program::new();
-                    let mut scope = Scope::new();
+                    p.set_source_map(Some(SourceMap::default()));
+                    let mut scope = Scope::with_source(source).with_commands(commands);
                     n.assemble(&mut p, &mut scope)?;
                     scope.assemble_teardown(&mut p)?;
+                    p.record_host_commands(commands);
                     Ok(p)
                 }
             }
@@ -0,0 +1,541 @@
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::instructions::{Instruction, Special, UserCommand};
+use crate::program::Program;
+use crate::source_map::SourceMap;
+use crate::vm::host::Signature;
+
+/// Leading bytes of every container
+pub const MAGIC: [u8; 4] = *b"ALNG";
+
+/// Layout version of the container itself, bumped on incompatible header or section changes
+pub const FORMAT_VERSION: u8 = 1;
+
+/// Instruction set implemented by this VM. Programs built for the same major and
+/// an equal or older minor version run unchanged, minor bumps only add instructions.
+/// 1.1 added host commands, 1.2 input registers, 1.3 event handlers, 1.4 tasks,
+/// 1.5 timers, 1.6 frame pacing, 1.7 matrices, 1.8 layouts.
+pub const ISA_VERSION: IsaVersion = IsaVersion { major: 1, minor: 8 };
+
+/// Features this VM build provides, see `Metadata::required_features`, each one came with the
+/// instruction set minor version of the same position plus one
+pub const SUPPORTED_FEATURES: &[&str] = &[
+    "host_commands",
+    "inputs",
+    "events",
+    "tasks",
+    "timers",
+    "frame_pacing",
+    "matrix",
+    "layout",
+];
+
+const HEADER_SIZE: usize = 12;
+const CRC_OFFSET: usize = 8;
+
+/// Sections with the high bit set may be skipped by readers which don't know them
+const OPTIONAL_SECTION: u8 = 0x80;
+
+const SECTION_METADATA: u8 = 0x01;
+const SECTION_CODE: u8 = 0x02;
+const SECTION_HOST_COMMANDS: u8 = 0x03;
+const SECTION_PARAMETERS: u8 = OPTIONAL_SECTION | 0x01;
+const SECTION_DEBUG_INFO: u8 = OPTIONAL_SECTION | 0x02;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IsaVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl IsaVersion {
+    /// Whether a program built for `self` runs on a VM implementing `vm`:
+    /// - the major versions are equal, a major bump changes the meaning of existing instructions;
+    /// - the program's minor version is at most the VM's, minor bumps only add instructions,
+    ///   so older programs keep running on newer VMs but not the other way round;
+    /// - the format version of the container is checked separately and has to match exactly.
+    ///
+    /// A program loaded from a container also needs every feature in `Metadata::required_features`
+    /// to be listed in `SUPPORTED_FEATURES`, so builds leaving out e.g. tasks reject programs
+    /// spawning them before they run.
+    pub fn runs_on(&self, vm: IsaVersion) -> bool {
+        self.major == vm.major && self.minor <= vm.minor
+    }
+}
+
+impl fmt::Display for IsaVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub name: String,
+    pub author: String,
+    /// Smallest strip the program draws correctly on, 0 when any length works
+    pub min_strip_length: u32,
+    /// Features the VM has to provide, checked against `SUPPORTED_FEATURES`
+    pub required_features: Vec<String>,
+    pub parameters: Vec<Parameter>,
+}
+
+/// Value the host may tune without recompiling the program
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Parameter {
+    pub name: String,
+    pub default: u32,
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ContainerError {
+    #[error("not a program container, bad magic bytes")]
+    BadMagic,
+
+    #[error("unsupported container format version {0}, expected {FORMAT_VERSION}")]
+    UnsupportedFormat(u8),
+
+    #[error("program requires instruction set {0}, vm implements {ISA_VERSION}")]
+    IncompatibleIsa(IsaVersion),
+
+    #[error("checksum mismatch: stored {0:#010x}, computed {1:#010x}")]
+    ChecksumMismatch(u32, u32),
+
+    #[error("container truncated at byte {0}")]
+    Truncated(usize),
+
+    #[error("unknown required section {0:#04x}")]
+    UnknownSection(u8),
+
+    #[error("duplicate section {0:#04x}")]
+    DuplicateSection(u8),
+
+    #[error("missing required section {0:#04x}")]
+    MissingSection(u8),
+
+    #[error("malformed section at byte {0}")]
+    InvalidSection(usize),
+
+    #[error("string at byte {0} is not valid utf-8")]
+    InvalidString(usize),
+
+    #[error("program requires unsupported feature: {0}")]
+    UnsupportedFeature(String),
+
+    #[error("program needs at least {0} leds, strip has {1}")]
+    StripTooShort(u32, u32),
+
+    #[error("{0} is too long for the container")]
+    TooLong(String),
+}
+
+impl Metadata {
+    /// Checks the program can run on a strip of `strip_length` leds
+    pub fn check_strip_length(&self, strip_length: u32) -> Result<(), ContainerError> {
+        if strip_length < self.min_strip_length {
+            return Err(ContainerError::StripTooShort(
+                self.min_strip_length,
+                strip_length,
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Program {
+    /// Oldest instruction set the code runs on, so programs not using newer instructions
+    /// still load on older VMs
+    pub fn required_isa(&self) -> IsaVersion {
+        let minor = self.features().max().unwrap_or(0);
+        IsaVersion { major: 1, minor }
+    }
+
+    /// Names of the features the code uses, in `SUPPORTED_FEATURES` order, to put in
+    /// `Metadata::required_features`
+    pub fn required_features(&self) -> Vec<String> {
+        let used: std::collections::BTreeSet<u8> = self.features().collect();
+        used.into_iter()
+            .map(|minor| SUPPORTED_FEATURES[minor as usize - 1].to_string())
+            .collect()
+    }
+
+    /// Instruction set minor version introducing each instruction the code uses beyond 1.0
+    fn features(&self) -> impl Iterator<Item = u8> + '_ {
+        self.instructions().filter_map(|i| match i {
+            Ok((_, Instruction::Host { .. })) => Some(1),
+            Ok((_, Instruction::User(UserCommand::GET_INPUT | UserCommand::INPUT_CHANGED))) => {
+                Some(2)
+            }
+            // `RETURN` ends handlers and tasks, so it comes with `ON` or `SPAWN`
+            Ok((_, Instruction::On { .. })) => Some(3),
+            Ok((_, Instruction::Spawn(_) | Instruction::Special(Special::YIELD))) => Some(4),
+            Ok((_, Instruction::User(UserCommand::EVERY | UserCommand::AFTER))) => Some(5),
+            Ok((_, Instruction::User(UserCommand::WAIT | UserCommand::FRAME_RATE))) => Some(6),
+            Ok((_, Instruction::User(UserCommand::XY | UserCommand::SIZE))) => Some(7),
+            Ok((_, Instruction::Position(_))) => Some(8),
+            _ => None,
+        })
+    }
+
+    /// Serializes the program together with `metadata`, strings and lists are limited to 65535
+    /// entries and sections to 4 GiB
+    pub fn to_container(&self, metadata: &Metadata) -> Result<Vec<u8>, ContainerError> {
+        let isa = self.required_isa();
+        let mut data = Vec::from(MAGIC);
+        data.extend([FORMAT_VERSION, isa.major, isa.minor, 0]);
+        data.extend([0; 4]); // CRC, filled in below
+
+        let mut section = vec![];
+        put_string(&mut section, &metadata.name, "name")?;
+        put_string(&mut section, &metadata.author, "author")?;
+        section.extend(metadata.min_strip_length.to_le_bytes());
+        put_count(
+            &mut section,
+            metadata.required_features.len(),
+            "feature list",
+        )?;
+        for feature in metadata.required_features.iter() {
+            put_string(&mut section, feature, "feature")?;
+        }
+        put_section(&mut data, SECTION_METADATA, &section)?;
+
+        put_section(&mut data, SECTION_CODE, &self.code)?;
+
+        if !self.host_commands.is_empty() {
+            let mut section = vec![];
+            put_count(&mut section, self.host_commands.len(), "host command list")?;
+            for (id, signature) in self.host_commands.iter() {
+                section.push(*id);
+                put_string(&mut section, &signature.name, "host command name")?;
+                section.push(signature.inputs << 4 | signature.outputs);
+            }
+            put_section(&mut data, SECTION_HOST_COMMANDS, &section)?;
+        }
+
+        if !metadata.parameters.is_empty() {
+            let mut section = vec![];
+            put_count(&mut section, metadata.parameters.len(), "parameter list")?;
+            for parameter in metadata.parameters.iter() {
+                put_string(&mut section, &parameter.name, "parameter name")?;
+                section.extend(parameter.default.to_le_bytes());
+            }
+            put_section(&mut data, SECTION_PARAMETERS, &section)?;
+        }
+
+        if let Some(source_map) = self.source_map() {
+            put_section(&mut data, SECTION_DEBUG_INFO, &source_map.to_bytes())?;
+        }
+
+        let crc = checksum(&data);
+        data[CRC_OFFSET..HEADER_SIZE].copy_from_slice(&crc.to_le_bytes());
+        Ok(data)
+    }
+
+    /// Parses a container, rejecting it when this VM cannot run the program
+    pub fn from_container(data: &[u8]) -> Result<(Program, Metadata), ContainerError> {
+        if data.len() < MAGIC.len() || data[..MAGIC.len()] != MAGIC {
+            return Err(ContainerError::BadMagic);
+        }
+        if data.len() < HEADER_SIZE {
+            return Err(ContainerError::Truncated(data.len()));
+        }
+        // The header versions are checked only once the checksum vouches for them
+        let stored = u32::from_le_bytes(data[CRC_OFFSET..HEADER_SIZE].try_into().unwrap());
+        let computed = checksum(data);
+        if stored != computed {
+            return Err(ContainerError::ChecksumMismatch(stored, computed));
+        }
+        if data[4] != FORMAT_VERSION {
+            return Err(ContainerError::UnsupportedFormat(data[4]));
+        }
+        let isa = IsaVersion {
+            major: data[5],
+            minor: data[6],
+        };
+        if !isa.runs_on(ISA_VERSION) {
+            return Err(ContainerError::IncompatibleIsa(isa));
+        }
+
+        let mut reader = Reader {
+            data,
+            pos: HEADER_SIZE,
+        };
+        let mut metadata = None;
+        let mut code = None;
+        let mut host_commands = None;
+        let mut parameters = None;
+        let mut debug_info = None;
+        while reader.pos < data.len() {
+            let tag = reader.u8()?;
+            let length = reader.u32()? as usize;
+            let start = reader.pos;
+            let payload = reader.bytes(length)?;
+            let slot = match tag {
+                SECTION_METADATA => &mut metadata,
+                SECTION_CODE => &mut code,
+                SECTION_HOST_COMMANDS => &mut host_commands,
+                SECTION_PARAMETERS => &mut parameters,
+                SECTION_DEBUG_INFO => &mut debug_info,
+                t if t & OPTIONAL_SECTION != 0 => continue,
+                t => return Err(ContainerError::UnknownSection(t)),
+            };
+            if slot.replace((start, payload)).is_some() {
+                return Err(ContainerError::DuplicateSection(tag));
+            }
+        }
+
+        let (start, payload) = metadata.ok_or(ContainerError::MissingSection(SECTION_METADATA))?;
+        let mut reader = Reader::section(data, start, payload);
+        let mut metadata = Metadata {
+            name: reader.string()?,
+            author: reader.string()?,
+            min_strip_length: reader.u32()?,
+            ..Default::default()
+        };
+        for _ in 0..reader.u16()? {
+            metadata.required_features.push(reader.string()?);
+        }
+
+        if let Some((start, payload)) = parameters {
+            let mut reader = Reader::section(data, start, payload);
+            for _ in 0..reader.u16()? {
+                metadata.parameters.push(Parameter {
+                    name: reader.string()?,
+                    default: reader.u32()?,
+                });
+            }
+        }
+
+        if let Some(feature) = metadata
+            .required_features
+            .iter()
+            .find(|f| !SUPPORTED_FEATURES.contains(&f.as_str()))
+        {
+            return Err(ContainerError::UnsupportedFeature(feature.clone()));
+        }
+
+        let (_, code) = code.ok_or(ContainerError::MissingSection(SECTION_CODE))?;
+        let mut program = Program::from_binary(code.to_vec());
+        if let Some((start, payload)) = host_commands {
+            let mut reader = Reader::section(data, start, payload);
+            for _ in 0..reader.u16()? {
+                let id = reader.u8()?;
+                let name = reader.string()?;
+                let effect = reader.u8()?;
+                program.host_commands.push((
+                    id,
+                    Signature {
+                        name,
+                        inputs: effect >> 4,
+                        outputs: effect & 0x0F,
+                    },
+                ));
+            }
+        }
+        if let Some((start, payload)) = debug_info {
+            program.set_source_map(Some(
+                SourceMap::from_bytes(payload).ok_or(ContainerError::InvalidSection(start))?,
+            ));
+        }
+        Ok((program, metadata))
+    }
+}
+
+/// Writes a 2 byte count, `what` names the field in the error
+fn put_count(data: &mut Vec<u8>, count: usize, what: &str) -> Result<(), ContainerError> {
+    let count = u16::try_from(count).map_err(|_| ContainerError::TooLong(what.to_string()))?;
+    data.extend(count.to_le_bytes());
+    Ok(())
+}
+
+fn put_string(data: &mut Vec<u8>, s: &str, what: &str) -> Result<(), ContainerError> {
+    put_count(data, s.len(), what)?;
+    data.extend(s.as_bytes());
+    Ok(())
+}
+
+fn put_section(data: &mut Vec<u8>, tag: u8, payload: &[u8]) -> Result<(), ContainerError> {
+    let length = u32::try_from(payload.len())
+        .map_err(|_| ContainerError::TooLong(format!("section {:#04x}", tag)))?;
+    data.push(tag);
+    data.extend(length.to_le_bytes());
+    data.extend(payload);
+    Ok(())
+}
+
+/// CRC-32 of the whole container but the CRC field itself
+fn checksum(data: &[u8]) -> u32 {
+    crc32(data[..CRC_OFFSET].iter().chain(&data[HEADER_SIZE..]))
+}
+
+/// CRC-32 (IEEE 802.3), as used by zip and png
+fn crc32<'a>(data: impl IntoIterator<Item = &'a u8>) -> u32 {
+    let mut crc = u32::MAX;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Cursor over container bytes, positions in errors are relative to the whole container
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn section(data: &'a [u8], start: usize, payload: &[u8]) -> Self {
+        Reader {
+            data: &data[..start + payload.len()],
+            pos: start,
+        }
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], ContainerError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + n)
+            .ok_or(ContainerError::Truncated(self.pos))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ContainerError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ContainerError> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, ContainerError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, ContainerError> {
+        let length = self.u16()? as usize;
+        let start = self.pos;
+        String::from_utf8(self.bytes(length)?.to_vec())
+            .map_err(|_| ContainerError::InvalidString(start))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::FromSource;
+
+    fn metadata() -> Metadata {
+        Metadata {
+            name: "blink".to_string(),
+            author: "someone".to_string(),
+            min_strip_length: 4,
+            required_features: vec![],
+            parameters: vec![Parameter {
+                name: "speed".to_string(),
+                default: 10,
+            }],
+        }
+    }
+
+    /// Recomputes the checksum after the test tampered with the container
+    fn reseal(data: &mut [u8]) {
+        let crc = checksum(data);
+        data[CRC_OFFSET..HEADER_SIZE].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    #[test]
+    fn check_container_round_trip() {
+        let p = Program::from_source(include_str!("../example_progs/blink.txt")).unwrap();
+        let data = p.to_container(&metadata()).unwrap();
+        let (q, m) = Program::from_container(&data).unwrap();
+        assert_eq!(p.code(), q.code());
+        assert_eq!(p.source_map(), q.source_map());
+        assert_eq!(m, metadata());
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn check_container_errors() {
+        let p = Program::from_source("blit;").unwrap();
+        let data = p.to_container(&metadata()).unwrap();
+
+        assert_eq!(
+            Program::from_container(p.code()).unwrap_err(),
+            ContainerError::BadMagic
+        );
+
+        let mut corrupted = data.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            Program::from_container(&corrupted),
+            Err(ContainerError::ChecksumMismatch(_, _))
+        ));
+
+        // The checksum covers the header, a flipped version bit is caught
+        let mut flipped = data.clone();
+        flipped[6] ^= 0x10;
+        assert!(matches!(
+            Program::from_container(&flipped),
+            Err(ContainerError::ChecksumMismatch(_, _))
+        ));
+
+        let mut newer = data.clone();
+        newer[6] = ISA_VERSION.minor + 1;
+        reseal(&mut newer);
+        assert!(matches!(
+            Program::from_container(&newer),
+            Err(ContainerError::IncompatibleIsa(_))
+        ));
+
+        let mut unknown = data.clone();
+        put_section(&mut unknown, OPTIONAL_SECTION | 0x7F, &[1, 2, 3]).unwrap();
+        reseal(&mut unknown);
+        assert!(Program::from_container(&unknown).is_ok());
+        put_section(&mut unknown, 0x7F, &[]).unwrap();
+        reseal(&mut unknown);
+        assert_eq!(
+            Program::from_container(&unknown).unwrap_err(),
+            ContainerError::UnknownSection(0x7F)
+        );
+
+        let spawning = Program::from_source("spawn { yield; }; every 1s { blit; };").unwrap();
+        assert_eq!(spawning.required_features(), vec!["tasks", "timers"]);
+        let m = Metadata {
+            required_features: spawning.required_features(),
+            ..metadata()
+        };
+        let (_, loaded) = Program::from_container(&spawning.to_container(&m).unwrap()).unwrap();
+        assert_eq!(loaded.required_features, vec!["tasks", "timers"]);
+
+        let mut m = metadata();
+        m.required_features.push("teleport".to_string());
+        assert_eq!(
+            Program::from_container(&p.to_container(&m).unwrap()).unwrap_err(),
+            ContainerError::UnsupportedFeature("teleport".to_string())
+        );
+
+        let mut m = metadata();
+        m.author = "x".repeat(70_000);
+        assert_eq!(
+            p.to_container(&m),
+            Err(ContainerError::TooLong("author".to_string()))
+        );
+        let mut m = metadata();
+        m.parameters = vec![Parameter::default(); 65_536];
+        assert_eq!(
+            p.to_container(&m),
+            Err(ContainerError::TooLong("parameter list".to_string()))
+        );
+
+        assert_eq!(
+            metadata().check_strip_length(3),
+            Err(ContainerError::StripTooShort(4, 3))
+        );
+    }
+}
@@ -0,0 +1,129 @@
+use crate::instructions::{Binary, Instruction, Prefix, UserCommand};
+
+/// Cycles the interpreter spends per instruction on some target, implement it to add a target profile
+pub trait CostModel {
+    /// Cost of decoding and dispatching every instruction with this prefix
+    fn prefix(&self, prefix: Prefix) -> u64;
+
+    /// Added to `prefix` for binary operations
+    fn binary(&self, _op: Binary) -> u64 {
+        0
+    }
+
+    /// Added to `prefix` for user commands, `blit` usually scales with the strip length
+    fn user(&self, _command: UserCommand, _strip_length: usize) -> u64 {
+        0
+    }
+
+    /// Added to `prefix` for host commands, whatever the host function itself costs
+    fn host(&self, _id: u8) -> u64 {
+        0
+    }
+
+    fn cycles(&self, ins: &Instruction, strip_length: usize) -> u64 {
+        let extra = match *ins {
+            Instruction::Binary(op) => self.binary(op),
+            Instruction::User(command) => self.user(command, strip_length),
+            Instruction::Host { id, .. } => self.host(id),
+            _ => 0,
+        };
+        self.prefix(ins.prefix()) + extra
+    }
+}
+
+/// Every instruction costs one cycle, so cycles equal instruction counts
+pub struct Uniform;
+
+impl CostModel for Uniform {
+    fn prefix(&self, _prefix: Prefix) -> u64 {
+        1
+    }
+}
+
+/// Rough costs on a Cortex-M0+ without hardware divider, e.g. SAMD21
+pub struct CortexM0;
+
+impl CostModel for CortexM0 {
+    fn prefix(&self, prefix: Prefix) -> u64 {
+        match prefix {
+            Prefix::PUSHI => 18,
+            Prefix::JMP | Prefix::JZ | Prefix::JNZ => 16,
+            Prefix::USER | Prefix::SPECIAL => 20,
+            _ => 14,
+        }
+    }
+
+    fn binary(&self, op: Binary) -> u64 {
+        match op {
+            Binary::DIV | Binary::MOD => 90,
+            _ => 2,
+        }
+    }
+
+    fn user(&self, command: UserCommand, strip_length: usize) -> u64 {
+        match command {
+            UserCommand::GET_LENGTH => 2,
+            UserCommand::GET_WALL_TIME | UserCommand::GET_PRECISE_TIME => 80,
+            UserCommand::SET_PIXEL => 30,
+            UserCommand::GET_PIXEL => 24,
+            UserCommand::GET_INPUT | UserCommand::INPUT_CHANGED => 12,
+            UserCommand::EVERY | UserCommand::AFTER => 150,
+            UserCommand::FRAME_RATE => 90,
+            UserCommand::XY => 20,
+            UserCommand::SIZE => 4,
+            UserCommand::RANDOM_INT => 140,
+            UserCommand::BLIT | UserCommand::WAIT => 200 + 40 * strip_length as u64,
+        }
+    }
+}
+
+/// Rough costs on a single core RV32IMC with hardware divider, e.g. ESP32-C3
+pub struct RiscV32;
+
+impl CostModel for RiscV32 {
+    fn prefix(&self, prefix: Prefix) -> u64 {
+        match prefix {
+            Prefix::PUSHI => 12,
+            Prefix::JMP | Prefix::JZ | Prefix::JNZ => 11,
+            Prefix::USER | Prefix::SPECIAL => 14,
+            _ => 9,
+        }
+    }
+
+    fn binary(&self, op: Binary) -> u64 {
+        match op {
+            Binary::DIV | Binary::MOD => 34,
+            Binary::MUL => 4,
+            _ => 1,
+        }
+    }
+
+    fn user(&self, command: UserCommand, strip_length: usize) -> u64 {
+        match command {
+            UserCommand::GET_LENGTH => 2,
+            UserCommand::GET_WALL_TIME | UserCommand::GET_PRECISE_TIME => 50,
+            UserCommand::SET_PIXEL => 20,
+            UserCommand::GET_PIXEL => 16,
+            UserCommand::GET_INPUT | UserCommand::INPUT_CHANGED => 8,
+            UserCommand::EVERY | UserCommand::AFTER => 90,
+            UserCommand::FRAME_RATE => 40,
+            UserCommand::XY => 12,
+            UserCommand::SIZE => 3,
+            UserCommand::RANDOM_INT => 60,
+            UserCommand::BLIT | UserCommand::WAIT => 150 + 24 * strip_length as u64,
+        }
+    }
+}
+
+/// Names accepted by `target`
+pub const TARGETS: &[&str] = &["uniform", "cortex-m0", "riscv32"];
+
+/// Built-in cost model by name
+pub fn target(name: &str) -> Option<Box<dyn CostModel>> {
+    match name {
+        "uniform" => Some(Box::new(Uniform)),
+        "cortex-m0" => Some(Box::new(CortexM0)),
+        "riscv32" => Some(Box::new(RiscV32)),
+        _ => None,
+    }
+}
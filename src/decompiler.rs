@@ -0,0 +1,808 @@
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::compiler::ast::{Expression, Intrinsic, Node};
+use crate::instructions::{
+    Binary, DecodeError, Instruction, Instructions, Special, Unary, UserCommand,
+};
+use crate::program::Program;
+use crate::vm::event::Event;
+use crate::vm::host::HostCommands;
+
+const INDENT: &str = "    ";
+const CLAMP_HALF_LEN: usize = 10;
+
+/* Statements that start with these words are parsed as keywords, so generated names must avoid them */
+const RESERVED_PREFIXES: [&str; 4] = ["let", "blit", "dump", "yield"];
+const RESERVED_NAMES: [&str; 4] = ["if", "for", "loop", "else"];
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum DecompileError {
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+
+    #[error("stack under flow at {0}")]
+    StackUnderflow(usize),
+
+    #[error("unrecognized instruction sequence at {0}")]
+    UnrecognizedPattern(usize),
+
+    #[error("block ending at {0} is not stack neutral")]
+    UnbalancedStack(usize),
+}
+
+pub trait ToSource {
+    fn to_source(&self) -> Result<String, DecompileError> {
+        self.to_source_with_commands(&HostCommands::new())
+    }
+
+    /// Decompiles calls to the host commands in `commands` by name, usually `VM::commands`,
+    /// commands missing from it are written as `host_{id}`
+    fn to_source_with_commands(&self, commands: &HostCommands) -> Result<String, DecompileError>;
+}
+
+impl ToSource for Program {
+    fn to_source_with_commands(&self, commands: &HostCommands) -> Result<String, DecompileError> {
+        let nodes = Decompiler::new(&self.code, commands)?.decompile()?;
+        let mut out = String::new();
+        write_block(&mut out, &nodes, 0);
+        Ok(out)
+    }
+}
+
+/// Symbolic view of a single stack slot while walking the bytecode
+#[derive(Clone, Debug)]
+enum Slot {
+    /// Named variable, introduced by `let` or `for`
+    Var(String),
+    /// Value that is not yet consumed, becomes `let` if it is referenced later
+    Temp(Expression),
+    /// `if` condition kept on the stack while branch bodies run
+    Condition,
+    /// `value` and `min` of a `clamp` whose `max` is still being evaluated
+    ClampLow(Box<Expression>, Box<Expression>),
+}
+
+struct Decompiler<'a> {
+    code: &'a [u8],
+    /// `loop` start pc -> pcs of closing `JMP`s, outermost last
+    loops: HashMap<usize, Vec<usize>>,
+    stack: Vec<Slot>,
+    next_name: usize,
+    commands: &'a HostCommands,
+}
+
+impl<'a> Decompiler<'a> {
+    fn new(code: &'a [u8], commands: &'a HostCommands) -> Result<Self, DecompileError> {
+        let mut d = Decompiler {
+            code,
+            loops: HashMap::new(),
+            stack: vec![],
+            next_name: 0,
+            commands,
+        };
+
+        // Backward jumps which are not closing a `for` are forever loops
+        for decoded in Instructions::new(code) {
+            if let (pc, Instruction::Jmp(target)) = decoded? {
+                let target = target as usize;
+                if target <= pc && !d.is_for_loop(target, pc + 3) {
+                    d.loops.entry(target).or_default().push(pc);
+                }
+            }
+        }
+
+        Ok(d)
+    }
+
+    fn decompile(mut self) -> Result<Vec<Node>, DecompileError> {
+        let nodes = self.block(0, self.code.len())?;
+        if !self.stack.is_empty() {
+            return Err(DecompileError::UnbalancedStack(self.code.len()));
+        }
+        Ok(nodes)
+    }
+
+    fn at(&self, pc: usize) -> Option<Instruction<'a>> {
+        Instruction::decode(self.code, pc).ok().map(|(ins, _)| ins)
+    }
+
+    fn is_jump(&self, pc: usize, jump: fn(u16) -> Instruction<'static>, target: usize) -> bool {
+        u16::try_from(target).is_ok_and(|t| self.at(pc) == Some(jump(t)))
+    }
+
+    /// `Program::repeat` layout: [JZ end][...body...][DEC][JMP start], end:
+    fn is_for_loop(&self, start: usize, end: usize) -> bool {
+        end >= start + 7
+            && self.is_jump(start, Instruction::Jz, end)
+            && self.at(end - 4) == Some(Instruction::Unary(Unary::DEC))
+            && self.is_jump(end - 3, Instruction::Jmp, start)
+    }
+
+    /// One half of the `clamp` intrinsic, see `Intrinsic::Clamp` in `Expression::assemble`
+    fn is_clamp_half(&self, pc: usize, op: Binary) -> bool {
+        self.at(pc) == Some(Instruction::Peek(1))
+            && self.at(pc + 1) == Some(Instruction::Peek(1))
+            && self.at(pc + 2) == Some(Instruction::Binary(op))
+            && self.is_jump(pc + 3, Instruction::Jz, pc + 9)
+            && self.at(pc + 6) == Some(Instruction::Peek(1))
+            && self.at(pc + 7) == Some(Instruction::Swap(3))
+            && self.at(pc + 8) == Some(Instruction::Pop(1))
+            && self.at(pc + 9) == Some(Instruction::Pop(2))
+    }
+
+    fn new_name(&mut self) -> String {
+        loop {
+            let mut n = self.next_name;
+            self.next_name += 1;
+
+            let mut name = String::new();
+            loop {
+                name.insert(0, (b'a' + (n % 26) as u8) as char);
+                if n < 26 {
+                    break;
+                }
+                n = n / 26 - 1;
+            }
+
+            if !RESERVED_NAMES.contains(&name.as_str())
+                && !RESERVED_PREFIXES.iter().any(|p| name.starts_with(p))
+                && self.commands.lookup(&name).is_none()
+            {
+                return name;
+            }
+        }
+    }
+
+    /// Turns every pending value at or below `depth` into a `let` statement
+    fn promote(&mut self, depth: usize, out: &mut Vec<Node>) {
+        for i in 0..depth.min(self.stack.len()) {
+            if let Slot::Temp(e) = &self.stack[i] {
+                let e = e.clone();
+                let name = self.new_name();
+                out.push(Node::NewVarAssignment(name.clone(), e));
+                self.stack[i] = Slot::Var(name);
+            }
+        }
+    }
+
+    fn pop_temp(&mut self, pc: usize) -> Result<Expression, DecompileError> {
+        match self.stack.pop() {
+            Some(Slot::Temp(e)) => Ok(e),
+            Some(_) => Err(DecompileError::UnrecognizedPattern(pc)),
+            None => Err(DecompileError::StackUnderflow(pc)),
+        }
+    }
+
+    fn expect_pop(&self, pc: usize, n: u8) -> Result<(), DecompileError> {
+        if self.at(pc) == Some(Instruction::Pop(n)) {
+            Ok(())
+        } else {
+            Err(DecompileError::UnrecognizedPattern(pc))
+        }
+    }
+
+    /// Decompiles the handler or task started at `pc`, which runs on its own stack from `start`
+    /// up to the `RETURN` before `end`
+    fn isolated(
+        &mut self,
+        pc: usize,
+        start: usize,
+        end: usize,
+        limit: usize,
+        out: &mut Vec<Node>,
+    ) -> Result<Vec<Node>, DecompileError> {
+        if end <= start
+            || end > limit
+            || self.at(end - 1) != Some(Instruction::Special(Special::RETURN))
+        {
+            return Err(DecompileError::UnrecognizedPattern(pc));
+        }
+
+        self.promote(self.stack.len(), out);
+        let stack = std::mem::take(&mut self.stack);
+        let body = self.block(start, end - 1);
+        self.stack = stack;
+        body
+    }
+
+    /// Decompiles the stack neutral region [start, end)
+    fn block(&mut self, start: usize, end: usize) -> Result<Vec<Node>, DecompileError> {
+        let mut out = vec![];
+        let base = self.stack.len();
+        let mut pc = start;
+
+        while pc < end {
+            if let Some(jmp) = self
+                .loops
+                .get(&pc)
+                .and_then(|ends| ends.iter().filter(|j| **j + 3 <= end).max().copied())
+            {
+                self.promote(self.stack.len(), &mut out);
+                let body = self.block(pc, jmp)?;
+                out.push(Node::Loop(body));
+                pc = jmp + 3;
+                continue;
+            }
+
+            let (ins, len) = Instruction::decode(self.code, pc)?;
+
+            match ins {
+                Instruction::PushB(_) | Instruction::PushI(_) => {
+                    for value in ins.immediates() {
+                        self.stack.push(Slot::Temp(Expression::Literal(value)));
+                    }
+                }
+                Instruction::Peek(n) => {
+                    if self.is_clamp_half(pc, Binary::LT) {
+                        let min = self.pop_temp(pc)?;
+                        let value = self.pop_temp(pc)?;
+                        self.stack
+                            .push(Slot::ClampLow(Box::new(value), Box::new(min)));
+                        pc += CLAMP_HALF_LEN;
+                        continue;
+                    }
+
+                    if self.is_clamp_half(pc, Binary::GT) {
+                        let max = self.pop_temp(pc)?;
+                        match self.stack.pop() {
+                            Some(Slot::ClampLow(value, min)) => {
+                                self.stack.push(Slot::Temp(Expression::Intrinsic(
+                                    Intrinsic::Clamp(value, min, Box::new(max)),
+                                )));
+                            }
+                            _ => return Err(DecompileError::UnrecognizedPattern(pc)),
+                        }
+                        pc += CLAMP_HALF_LEN;
+                        continue;
+                    }
+
+                    let index = self
+                        .stack
+                        .len()
+                        .checked_sub(usize::from(n) + 1)
+                        .ok_or(DecompileError::StackUnderflow(pc))?;
+                    self.promote(index + 1, &mut out);
+                    match &self.stack[index] {
+                        Slot::Var(name) => {
+                            let load = Expression::Load(name.clone());
+                            self.stack.push(Slot::Temp(load));
+                        }
+                        _ => return Err(DecompileError::UnrecognizedPattern(pc)),
+                    }
+                }
+                Instruction::Swap(n) => {
+                    // Assignment: [value][SWAP n][POP 1]
+                    self.expect_pop(pc + 1, 1)?;
+                    let value = self.pop_temp(pc)?;
+                    let index = self
+                        .stack
+                        .len()
+                        .checked_sub(usize::from(n))
+                        .filter(|_| n > 0)
+                        .ok_or(DecompileError::StackUnderflow(pc))?;
+                    self.promote(self.stack.len(), &mut out);
+                    match &self.stack[index] {
+                        Slot::Var(name) => out.push(Node::VarAssignment(name.clone(), value)),
+                        _ => return Err(DecompileError::UnrecognizedPattern(pc)),
+                    }
+                    pc += 2;
+                    continue;
+                }
+                Instruction::Pop(n) => {
+                    let n = usize::from(n);
+                    if self.stack.len() < base + n {
+                        return Err(DecompileError::StackUnderflow(pc));
+                    }
+
+                    if n == 1 && matches!(self.stack.last(), Some(Slot::Temp(_))) {
+                        let e = self.pop_temp(pc)?;
+                        self.promote(self.stack.len(), &mut out);
+                        out.push(Node::Expression(e));
+                    } else {
+                        // Scope teardown
+                        self.promote(self.stack.len(), &mut out);
+                        let rest = self.stack.len() - n;
+                        if !self.stack[rest..].iter().all(|s| matches!(s, Slot::Var(_))) {
+                            return Err(DecompileError::UnrecognizedPattern(pc));
+                        }
+                        self.stack.truncate(rest);
+                    }
+                }
+                Instruction::Unary(op) => {
+                    let e = self.pop_temp(pc)?;
+                    self.stack
+                        .push(Slot::Temp(Expression::Unary(op, Box::new(e))));
+                }
+                Instruction::Binary(op) => {
+                    let rhs = self.pop_temp(pc)?;
+                    let lhs = self.pop_temp(pc)?;
+                    self.stack.push(Slot::Temp(Expression::Binary(
+                        Box::new(lhs),
+                        op,
+                        Box::new(rhs),
+                    )));
+                }
+                Instruction::User(user) => {
+                    match user {
+                        UserCommand::GET_LENGTH
+                        | UserCommand::GET_WALL_TIME
+                        | UserCommand::GET_PRECISE_TIME => {
+                            self.stack.push(Slot::Temp(Expression::User(user)));
+                        }
+                        UserCommand::EVERY | UserCommand::AFTER => {
+                            // Only written as `every`/`after` statements: [duration][USER][JZ end]
+                            let duration = self.pop_temp(pc)?;
+                            if !matches!(duration, Expression::Literal(_))
+                                || !matches!(self.at(pc + len), Some(Instruction::Jz(_)))
+                            {
+                                return Err(DecompileError::UnrecognizedPattern(pc));
+                            }
+                            self.stack
+                                .push(Slot::Temp(Expression::UserCall(user, vec![duration])));
+                        }
+                        UserCommand::XY => {
+                            let y = self.pop_temp(pc)?;
+                            let x = self.pop_temp(pc)?;
+                            self.stack
+                                .push(Slot::Temp(Expression::UserCall(user, vec![x, y])));
+                        }
+                        UserCommand::SIZE => {
+                            // Only written as get_width or get_height
+                            let n = self.pop_temp(pc)?;
+                            if !matches!(n, Expression::Literal(0 | 1)) {
+                                return Err(DecompileError::UnrecognizedPattern(pc));
+                            }
+                            self.stack
+                                .push(Slot::Temp(Expression::UserCall(user, vec![n])));
+                        }
+                        UserCommand::RANDOM_INT
+                        | UserCommand::GET_PIXEL
+                        | UserCommand::GET_INPUT
+                        | UserCommand::INPUT_CHANGED => {
+                            let e = self.pop_temp(pc)?;
+                            self.stack
+                                .push(Slot::Temp(Expression::UserCall(user, vec![e])));
+                        }
+                        UserCommand::SET_PIXEL => {
+                            // [index][color][SET_PIXEL][POP 1]
+                            self.expect_pop(pc + 1, 1)?;
+                            let color = self.pop_temp(pc)?;
+                            let index = self.pop_temp(pc)?;
+                            self.promote(self.stack.len(), &mut out);
+                            let mut args = vec![index];
+                            args.extend(
+                                split_color(color, 4)
+                                    .ok_or(DecompileError::UnrecognizedPattern(pc))?,
+                            );
+                            out.push(Node::UserCall(user, args));
+                            pc += 2;
+                            continue;
+                        }
+                        UserCommand::WAIT | UserCommand::FRAME_RATE => {
+                            // [argument][USER][POP 1]
+                            self.expect_pop(pc + 1, 1)?;
+                            let argument = self.pop_temp(pc)?;
+                            self.promote(self.stack.len(), &mut out);
+                            out.push(Node::UserCall(user, vec![argument]));
+                            pc += 2;
+                            continue;
+                        }
+                        UserCommand::BLIT => {
+                            self.promote(self.stack.len(), &mut out);
+                            out.push(Node::User(user));
+                        }
+                    }
+                }
+                Instruction::Host {
+                    id,
+                    inputs,
+                    outputs,
+                } => {
+                    let mut args = vec![];
+                    for _ in 0..inputs {
+                        args.push(self.pop_temp(pc)?);
+                    }
+                    args.reverse();
+                    let name = match self.commands.signature(id) {
+                        Some(signature) => signature.name.clone(),
+                        None => format!("host_{}", id),
+                    };
+                    let call = Expression::Call(name, args);
+                    if outputs == 1 {
+                        self.stack.push(Slot::Temp(call));
+                    } else {
+                        // [args][HOST] or [args][HOST][POP outputs]
+                        self.promote(self.stack.len(), &mut out);
+                        out.push(Node::Expression(call));
+                        if outputs > 0 {
+                            self.expect_pop(pc + len, outputs)?;
+                            pc += 1;
+                        }
+                    }
+                }
+                Instruction::Special(special @ (Special::DUMP | Special::YIELD)) => {
+                    self.promote(self.stack.len(), &mut out);
+                    out.push(Node::Special(special));
+                }
+                Instruction::Jz(target) => {
+                    let target = target as usize;
+                    if target < pc + 3 || target > end {
+                        return Err(DecompileError::UnrecognizedPattern(pc));
+                    }
+
+                    let head = self.pop_temp(pc)?;
+                    self.promote(self.stack.len(), &mut out);
+
+                    let timer = timer(&head).is_some();
+                    if timer
+                        && (self.is_for_loop(pc, target)
+                            || matches!(self.at(target), Some(Instruction::Jnz(_))))
+                    {
+                        return Err(DecompileError::UnrecognizedPattern(pc));
+                    }
+
+                    if self.is_for_loop(pc, target) {
+                        let name = self.new_name();
+                        self.stack.push(Slot::Var(name.clone()));
+                        let body = self.block(pc + 3, target - 4)?;
+                        self.expect_pop(target, 1)?;
+                        self.stack.pop();
+                        out.push(Node::For(name, head, body));
+                        pc = target + 1;
+                        continue;
+                    }
+
+                    self.stack.push(Slot::Condition);
+                    let if_body = self.block(pc + 3, target)?;
+
+                    if let Some(Instruction::Jnz(else_end)) = self.at(target) {
+                        let else_end = else_end as usize;
+                        if else_end < target + 3 || else_end > end {
+                            return Err(DecompileError::UnrecognizedPattern(target));
+                        }
+                        let else_body = self.block(target + 3, else_end)?;
+                        self.expect_pop(else_end, 1)?;
+                        self.stack.pop();
+                        out.push(Node::IfElse(head, if_body, else_body));
+                        pc = else_end + 1;
+                    } else {
+                        self.expect_pop(target, 1)?;
+                        self.stack.pop();
+                        out.push(Node::If(head, if_body));
+                        pc = target + 1;
+                    }
+                    continue;
+                }
+                Instruction::On {
+                    kind,
+                    key,
+                    end: handler_end,
+                } => {
+                    let event = Event::from_key(kind, key)
+                        .ok_or(DecompileError::UnrecognizedPattern(pc))?;
+                    let body = self.isolated(pc, pc + len, handler_end as usize, end, &mut out)?;
+                    out.push(Node::On(event, body));
+                    pc = handler_end as usize;
+                    continue;
+                }
+                Instruction::Position(coordinate) => {
+                    let index = self.pop_temp(pc)?;
+                    self.stack.push(Slot::Temp(Expression::Position(
+                        coordinate,
+                        Box::new(index),
+                    )));
+                }
+                Instruction::Spawn(task_end) => {
+                    let body = self.isolated(pc, pc + len, task_end as usize, end, &mut out)?;
+                    out.push(Node::Spawn(body));
+                    pc = task_end as usize;
+                    continue;
+                }
+                Instruction::Jmp(_) | Instruction::Jnz(_) | Instruction::Special(_) => {
+                    return Err(DecompileError::UnrecognizedPattern(pc));
+                }
+            }
+
+            pc += len;
+        }
+
+        if self.stack.len() != base {
+            return Err(DecompileError::UnbalancedStack(end));
+        }
+
+        Ok(out)
+    }
+}
+
+/// Splits the packed color built by `Node::UserCall(SET_PIXEL)` back into `channels` arguments
+fn split_color(color: Expression, channels: usize) -> Option<Vec<Expression>> {
+    if let Expression::Literal(v) = color {
+        return Some(
+            (0..channels)
+                .map(|n| Expression::Literal((v >> (n * 8)) & 0xFF))
+                .collect(),
+        );
+    }
+
+    if channels == 1 {
+        return channel(color, 0).map(|c| vec![c]);
+    }
+
+    match color {
+        Expression::Binary(lhs, Binary::OR, rhs) => {
+            let mut args = split_color(*lhs, channels - 1)?;
+            args.push(channel(*rhs, channels - 1)?);
+            Some(args)
+        }
+        _ => None,
+    }
+}
+
+/// Reverses `(param & 0xFF) << (n * 8)`
+fn channel(e: Expression, n: usize) -> Option<Expression> {
+    if let Expression::Literal(v) = e {
+        return Some(Expression::Literal((v >> (n * 8)) & 0xFF));
+    }
+
+    let mut e = e;
+    for _ in 0..n {
+        e = match e {
+            Expression::Unary(Unary::SHL8, inner) => *inner,
+            _ => return None,
+        };
+    }
+
+    match e {
+        Expression::Binary(param, Binary::AND, mask) if *mask == Expression::Literal(0xFF) => {
+            Some(*param)
+        }
+        _ => None,
+    }
+}
+
+fn write_block(out: &mut String, nodes: &[Node], level: usize) {
+    for node in nodes {
+        out.push_str(&INDENT.repeat(level));
+        write_node(out, node, level);
+        out.push_str(";\n");
+    }
+}
+
+fn write_braced(out: &mut String, nodes: &[Node], level: usize) {
+    out.push_str("{\n");
+    write_block(out, nodes, level + 1);
+    out.push_str(&INDENT.repeat(level));
+    out.push('}');
+}
+
+fn write_node(out: &mut String, node: &Node, level: usize) {
+    match node {
+        Node::Expression(e) => out.push_str(&expression(e)),
+        Node::Special(Special::DUMP) => out.push_str("dump"),
+        Node::Special(Special::YIELD) => out.push_str("yield"),
+        Node::Special(Special::TWOBYTE | Special::RETURN) => unreachable!(),
+        Node::User(_) => out.push_str("blit"),
+        Node::UserCall(UserCommand::SET_PIXEL, args) => match &args[0] {
+            Expression::UserCall(UserCommand::XY, xy) => {
+                let args: Vec<String> = xy.iter().chain(&args[1..]).map(expression).collect();
+                out.push_str(&format!("set_xy({})", args.join(", ")));
+            }
+            _ => {
+                let args: Vec<String> = args.iter().map(expression).collect();
+                out.push_str(&format!("set_pixel({})", args.join(", ")));
+            }
+        },
+        Node::UserCall(user, args) => {
+            let args: Vec<String> = args.iter().map(expression).collect();
+            out.push_str(&format!("{}({})", user, args.join(", ")));
+        }
+        Node::Statements(nodes) => write_block(out, nodes, level),
+        Node::Located(_, node) => write_node(out, node, level),
+        Node::Loop(body) => {
+            out.push_str("loop ");
+            write_braced(out, body, level);
+        }
+        Node::On(event, body) => {
+            match event {
+                Event::Button(n) => out.push_str(&format!("on button({}) ", n)),
+                Event::ParamChanged(name) => out.push_str(&format!("on param_changed({}) ", name)),
+            }
+            write_braced(out, body, level);
+        }
+        Node::Spawn(body) => {
+            out.push_str("spawn ");
+            write_braced(out, body, level);
+        }
+        Node::If(e, body) => {
+            match timer(e) {
+                Some((keyword, ms)) if ms % 1000 == 0 => {
+                    out.push_str(&format!("{} {}s ", keyword, ms / 1000))
+                }
+                Some((keyword, ms)) => out.push_str(&format!("{} {}ms ", keyword, ms)),
+                None => out.push_str(&format!("if({}) ", expression(e))),
+            }
+            write_braced(out, body, level);
+        }
+        Node::IfElse(e, if_body, else_body) => {
+            out.push_str(&format!("if({}) ", expression(e)));
+            write_braced(out, if_body, level);
+            out.push_str(" else ");
+            write_braced(out, else_body, level);
+        }
+        Node::NewVarAssignment(name, e) => {
+            out.push_str(&format!("let {} = {}", name, expression(e)))
+        }
+        Node::VarAssignment(name, e) => out.push_str(&format!("{} = {}", name, expression(e))),
+        Node::For(name, e, body) => {
+            out.push_str(&format!("for({} = {}) ", name, expression(e)));
+            write_braced(out, body, level);
+        }
+    }
+}
+
+/// Keyword and duration of the condition `every` and `after` statements compile to
+fn timer(e: &Expression) -> Option<(&'static str, u32)> {
+    match e {
+        Expression::UserCall(UserCommand::EVERY, args) => match args[..] {
+            [Expression::Literal(ms)] => Some(("every", ms)),
+            _ => None,
+        },
+        Expression::UserCall(UserCommand::AFTER, args) => match args[..] {
+            [Expression::Literal(ms)] => Some(("after", ms)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn operand(e: &Expression) -> String {
+    match e {
+        Expression::Binary(..) | Expression::Unary(..) => format!("({})", expression(e)),
+        _ => expression(e),
+    }
+}
+
+fn expression(e: &Expression) -> String {
+    match e {
+        Expression::Literal(v) if *v > 0xFF => format!("{:#X}", v),
+        Expression::Literal(v) => v.to_string(),
+        Expression::Load(name) => name.clone(),
+        Expression::User(u) => match u {
+            UserCommand::GET_LENGTH => "get_length",
+            UserCommand::GET_WALL_TIME => "get_wall_time",
+            UserCommand::GET_PRECISE_TIME => "get_precise_time",
+            _ => unreachable!(),
+        }
+        .to_string(),
+        Expression::UserCall(UserCommand::SIZE, args) => match args[..] {
+            [Expression::Literal(0)] => "get_width".to_string(),
+            _ => "get_height".to_string(),
+        },
+        Expression::UserCall(UserCommand::GET_PIXEL, args)
+            if matches!(args[0], Expression::UserCall(UserCommand::XY, _)) =>
+        {
+            format!("get_{}", expression(&args[0]))
+        }
+        Expression::UserCall(UserCommand::XY, args) => {
+            format!("xy({}, {})", expression(&args[0]), expression(&args[1]))
+        }
+        Expression::UserCall(u, args) => {
+            let name = match u {
+                UserCommand::RANDOM_INT => "random",
+                UserCommand::GET_PIXEL => "get_pixel",
+                UserCommand::GET_INPUT => "input",
+                UserCommand::INPUT_CHANGED => "input_changed",
+                _ => unreachable!(),
+            };
+            format!("{}({})", name, expression(&args[0]))
+        }
+        Expression::Position(coordinate, index) => {
+            format!("{}({})", coordinate, expression(index))
+        }
+        Expression::Call(name, args) => {
+            let args: Vec<String> = args.iter().map(expression).collect();
+            format!("{}({})", name, args.join(", "))
+        }
+        Expression::Intrinsic(Intrinsic::Clamp(value, min, max)) => format!(
+            "clamp({}, {}, {})",
+            expression(value),
+            expression(min),
+            expression(max)
+        ),
+        Expression::Unary(op @ (Unary::SHL8 | Unary::SHR8), _) => {
+            // Consecutive byte shifts are written as a single shift by a multiple of 8
+            let mut bits = 0;
+            let mut inner = e;
+            while let Expression::Unary(o, rhs) = inner {
+                if o != op {
+                    break;
+                }
+                bits += 8;
+                inner = rhs;
+            }
+            let sign = if *op == Unary::SHL8 { "<<" } else { ">>" };
+            format!("{} {} {}", operand(inner), sign, bits)
+        }
+        Expression::Unary(op, rhs) => {
+            let sign = match op {
+                Unary::NOT => "~",
+                Unary::NEG => "-",
+                // No source syntax, but equivalent binary expressions compile to the same thing
+                Unary::INC => return format!("{} + 1", operand(rhs)),
+                Unary::DEC => return format!("{} - 1", operand(rhs)),
+                Unary::SHL8 | Unary::SHR8 => unreachable!(),
+            };
+            format!("{}{}", sign, operand(rhs))
+        }
+        Expression::Binary(lhs, op, rhs) => {
+            let sign = match op {
+                Binary::ADD => "+",
+                Binary::SUB => "-",
+                Binary::DIV => "/",
+                Binary::MUL => "*",
+                Binary::MOD => "%",
+                Binary::AND => "&",
+                Binary::OR => "|",
+                Binary::XOR => "^",
+                Binary::GT => ">",
+                Binary::GTE => ">=",
+                Binary::LT => "<",
+                Binary::LTE => "<=",
+                Binary::EQ => "==",
+                Binary::NEQ => "!=",
+                Binary::SHL => "<<",
+                Binary::SHR => ">>",
+            };
+            format!("{} {} {}", operand(lhs), sign, operand(rhs))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::FromSource;
+
+    const EXAMPLES: [&str; 10] = [
+        include_str!("../example_progs/blink.txt"),
+        include_str!("../example_progs/clamp.txt"),
+        include_str!("../example_progs/clock.txt"),
+        include_str!("../example_progs/fade_life.txt"),
+        include_str!("../example_progs/gradient_hue_rainbow.txt"),
+        include_str!("../example_progs/hue_rainbow.txt"),
+        include_str!("../example_progs/intrinsics.txt"),
+        include_str!("../example_progs/modify_variable.txt"),
+        include_str!("../example_progs/random_inc.txt"),
+        include_str!("../example_progs/rainbow.txt"),
+    ];
+
+    #[test]
+    fn check_structured_output() {
+        let p = Program::from_source(
+            "let a = 3; loop { for(i=get_length) { if(i == a) { blit; } else { dump; }; }; }",
+        )
+        .unwrap();
+        assert_eq!(
+            p.to_source().unwrap(),
+            "let a = 3;\nloop {\n    for(b = get_length) {\n        if(b == a) {\n            blit;\n        } else {\n            dump;\n        };\n    };\n};\n"
+        );
+    }
+
+    #[test]
+    fn check_example_round_trip() {
+        for source in EXAMPLES {
+            let original = Program::from_source(source).unwrap();
+            let decompiled = original.to_source().unwrap();
+            let recompiled = Program::from_source(&decompiled).unwrap();
+            assert_eq!(original.code(), recompiled.code(), "{}", decompiled);
+        }
+    }
+
+    #[test]
+    fn check_unstructured_jump() {
+        let p = Program::from_binary(vec![0x11, 0x01, 0x60, 0x00, 0x00]);
+        assert_eq!(p.to_source(), Err(DecompileError::UnrecognizedPattern(2)));
+    }
+}
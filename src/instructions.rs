@@ -1,9 +1,10 @@
 use crate::vm::errors::VMError;
 use std::fmt;
+use thiserror::Error;
 
 #[allow(dead_code)]
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Prefix {
     POP = 0x0,
     PUSHB = 0x10,
@@ -68,6 +69,8 @@ impl fmt::Display for Prefix {
 pub enum Special {
     DUMP = 1,
     TWOBYTE = 2,
+    RETURN = 4,
+    YIELD = 6,
 }
 
 impl Special {
@@ -75,11 +78,109 @@ impl Special {
         match code {
             1 => Some(Special::DUMP),
             2 => Some(Special::TWOBYTE),
+            4 => Some(Special::RETURN),
+            6 => Some(Special::YIELD),
             _ => None,
         }
     }
 }
 
+impl fmt::Display for Special {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Special::DUMP => "dump",
+                Special::TWOBYTE => "two-byte instruction",
+                Special::RETURN => "return",
+                Special::YIELD => "yield",
+            }
+        )
+    }
+}
+
+/// `SPECIAL` postfix of event handlers, followed by the event kind, the end of the handler and the event key
+pub const SPECIAL_ON: u8 = 0x03;
+
+/// `SPECIAL` postfix of `spawn`, followed by the end of the task's code
+pub const SPECIAL_SPAWN: u8 = 0x05;
+
+/// `SPECIAL` postfix of the layout intrinsics, followed by the coordinate
+pub const SPECIAL_POSITION: u8 = 0x07;
+
+/// Coordinate of a led in the layout, read by `pos_x(i)` and friends
+#[allow(dead_code, non_camel_case_types)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Coordinate {
+    X = 0,
+    Y = 1,
+    Z = 2,
+    ANGLE = 3,
+    RADIUS = 4,
+}
+
+impl Coordinate {
+    pub fn from(code: u8) -> Option<Coordinate> {
+        match code {
+            0 => Some(Coordinate::X),
+            1 => Some(Coordinate::Y),
+            2 => Some(Coordinate::Z),
+            3 => Some(Coordinate::ANGLE),
+            4 => Some(Coordinate::RADIUS),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Coordinate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Coordinate::X => "pos_x",
+                Coordinate::Y => "pos_y",
+                Coordinate::Z => "pos_z",
+                Coordinate::ANGLE => "angle",
+                Coordinate::RADIUS => "radius",
+            }
+        )
+    }
+}
+
+#[allow(dead_code, non_camel_case_types)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EventKind {
+    BUTTON = 0,
+    PARAM_CHANGED = 1,
+}
+
+impl EventKind {
+    pub fn from(code: u8) -> Option<EventKind> {
+        match code {
+            0 => Some(EventKind::BUTTON),
+            1 => Some(EventKind::PARAM_CHANGED),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                EventKind::BUTTON => "button",
+                EventKind::PARAM_CHANGED => "param_changed",
+            }
+        )
+    }
+}
+
 #[allow(dead_code)]
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -105,15 +206,20 @@ impl Unary {
         }
     }
 
-    pub fn apply(self, lhs: u32) -> u32 {
+    pub fn apply(self, lhs: u32) -> Result<u32, VMError> {
         match self {
-            Unary::DEC => lhs - 1,
-            Unary::INC => lhs + 1,
-            Unary::NEG => unimplemented!(),
-            Unary::NOT => !lhs,
-            Unary::SHL8 => lhs << 8,
-            Unary::SHR8 => lhs >> 8,
+            Unary::DEC => lhs.checked_sub(1),
+            Unary::INC => lhs.checked_add(1),
+            Unary::NEG => {
+                return Err(VMError::UnimplementedInstruction(
+                    Prefix::UNARY as u8 | self as u8,
+                ))
+            }
+            Unary::NOT => Some(!lhs),
+            Unary::SHL8 => Some(lhs << 8),
+            Unary::SHR8 => Some(lhs >> 8),
         }
+        .ok_or(VMError::UnaryArithmeticError(self, lhs))
     }
 }
 
@@ -181,26 +287,24 @@ impl Binary {
 
     pub fn apply(self, lhs: u32, rhs: u32) -> Result<u32, VMError> {
         match self {
-            Binary::ADD => Ok(lhs + rhs),
-            Binary::SUB => Ok(lhs - rhs),
-            Binary::MUL => Ok(lhs * rhs),
-            Binary::DIV => lhs.checked_div(rhs).ok_or(VMError::RuntimeError(format!(
-                "Error during division, lhs: {}, rhs: {}, overflow/underflow/division by zero",
-                lhs, rhs
-            ))),
-            Binary::MOD => Ok(lhs % rhs),
-            Binary::AND => Ok(lhs & rhs),
-            Binary::OR => Ok(lhs | rhs),
-            Binary::SHL => Ok(lhs << rhs),
-            Binary::SHR => Ok(lhs >> rhs),
-            Binary::XOR => Ok(lhs ^ rhs),
-            Binary::EQ => Ok(u32::from(lhs == rhs)),
-            Binary::NEQ => Ok(u32::from(lhs != rhs)),
-            Binary::GT => Ok(u32::from(lhs > rhs)),
-            Binary::GTE => Ok(u32::from(lhs >= rhs)),
-            Binary::LT => Ok(u32::from(lhs < rhs)),
-            Binary::LTE => Ok(u32::from(lhs <= rhs)),
+            Binary::ADD => lhs.checked_add(rhs),
+            Binary::SUB => lhs.checked_sub(rhs),
+            Binary::MUL => lhs.checked_mul(rhs),
+            Binary::DIV => lhs.checked_div(rhs),
+            Binary::MOD => lhs.checked_rem(rhs),
+            Binary::AND => Some(lhs & rhs),
+            Binary::OR => Some(lhs | rhs),
+            Binary::SHL => lhs.checked_shl(rhs),
+            Binary::SHR => lhs.checked_shr(rhs),
+            Binary::XOR => Some(lhs ^ rhs),
+            Binary::EQ => Some(u32::from(lhs == rhs)),
+            Binary::NEQ => Some(u32::from(lhs != rhs)),
+            Binary::GT => Some(u32::from(lhs > rhs)),
+            Binary::GTE => Some(u32::from(lhs >= rhs)),
+            Binary::LT => Some(u32::from(lhs < rhs)),
+            Binary::LTE => Some(u32::from(lhs <= rhs)),
         }
+        .ok_or(VMError::ArithmeticError(self, lhs, rhs))
     }
 }
 
@@ -231,6 +335,9 @@ impl fmt::Display for Binary {
     }
 }
 
+/// `USER` postfix of host commands, followed by the command id and its stack effect
+pub const USER_EXTENDED: u8 = 0x0F;
+
 #[allow(dead_code, non_camel_case_types)]
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -242,6 +349,14 @@ pub enum UserCommand {
     BLIT = 4,
     RANDOM_INT = 5,
     GET_PIXEL = 6,
+    GET_INPUT = 7,
+    INPUT_CHANGED = 8,
+    EVERY = 9,
+    AFTER = 10,
+    WAIT = 11,
+    FRAME_RATE = 12,
+    XY = 13,
+    SIZE = 14,
 }
 
 impl UserCommand {
@@ -254,7 +369,384 @@ impl UserCommand {
             4 => Some(UserCommand::BLIT),
             5 => Some(UserCommand::RANDOM_INT),
             6 => Some(UserCommand::GET_PIXEL),
+            7 => Some(UserCommand::GET_INPUT),
+            8 => Some(UserCommand::INPUT_CHANGED),
+            9 => Some(UserCommand::EVERY),
+            10 => Some(UserCommand::AFTER),
+            11 => Some(UserCommand::WAIT),
+            12 => Some(UserCommand::FRAME_RATE),
+            13 => Some(UserCommand::XY),
+            14 => Some(UserCommand::SIZE),
             _ => None,
         }
     }
+
+    /// Number of stack values the command consumes and produces
+    pub fn stack_effect(self) -> (usize, usize) {
+        match self {
+            UserCommand::GET_LENGTH => (0, 1),
+            UserCommand::GET_WALL_TIME => (0, 1),
+            UserCommand::GET_PRECISE_TIME => (0, 1),
+            UserCommand::SET_PIXEL => (2, 1), // index stays on the stack
+            UserCommand::BLIT => (0, 0),
+            UserCommand::RANDOM_INT => (1, 1),
+            UserCommand::GET_PIXEL => (1, 1),
+            UserCommand::GET_INPUT => (1, 1),
+            UserCommand::INPUT_CHANGED => (1, 1),
+            UserCommand::EVERY => (1, 1),
+            UserCommand::AFTER => (1, 1),
+            UserCommand::WAIT => (1, 1), // milliseconds stay on the stack
+            UserCommand::FRAME_RATE => (1, 1),
+            UserCommand::XY => (2, 1),
+            UserCommand::SIZE => (1, 1),
+        }
+    }
+}
+
+impl fmt::Display for UserCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                UserCommand::GET_LENGTH => "get_length",
+                UserCommand::GET_WALL_TIME => "get_wall_time",
+                UserCommand::GET_PRECISE_TIME => "get_precise_time",
+                UserCommand::SET_PIXEL => "set_pixel",
+                UserCommand::BLIT => "blit",
+                UserCommand::RANDOM_INT => "random_int",
+                UserCommand::GET_PIXEL => "get_pixel",
+                UserCommand::GET_INPUT => "get_input",
+                UserCommand::INPUT_CHANGED => "input_changed",
+                UserCommand::EVERY => "every",
+                UserCommand::AFTER => "after",
+                UserCommand::WAIT => "wait",
+                UserCommand::FRAME_RATE => "frame_rate",
+                UserCommand::XY => "xy",
+                UserCommand::SIZE => "size",
+            }
+        )
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    #[error("unknown instruction {1:#04x} at {0}")]
+    UnknownInstruction(usize, u8),
+
+    #[error("instruction at {0} overruns code")]
+    Truncated(usize),
+}
+
+/// Single decoded instruction, operands borrow from the program code
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Instruction<'a> {
+    Pop(u8),
+    /// Bytes pushed one by one, no bytes pushes a single zero
+    PushB(&'a [u8]),
+    Peek(u8),
+    /// Little endian u32 values packed one after another
+    PushI(&'a [u8]),
+    Jmp(u16),
+    Jz(u16),
+    Jnz(u16),
+    Unary(Unary),
+    Binary(Binary),
+    Swap(u8),
+    User(UserCommand),
+    /// Command registered by the host, encoded as `[USER | 0x0F][id][inputs << 4 | outputs]`
+    Host {
+        id: u8,
+        inputs: u8,
+        outputs: u8,
+    },
+    Special(Special),
+    /// Registers the handler following it for an event and jumps to `end`, encoded as
+    /// `[SPECIAL | 0x03][kind][end, 2 bytes][key length][key]`
+    On {
+        kind: EventKind,
+        key: &'a [u8],
+        end: u16,
+    },
+    /// Starts a task running the code following it and jumps to the given end,
+    /// encoded as `[SPECIAL | 0x05][end, 2 bytes]`
+    Spawn(u16),
+    /// Replaces the led index on top of the stack with its coordinate in the layout,
+    /// encoded as `[SPECIAL | 0x07][coordinate]`
+    Position(Coordinate),
+}
+
+impl<'a> Instruction<'a> {
+    /// Decodes instruction at `pc`, returning it together with its length in bytes
+    pub fn decode(code: &'a [u8], pc: usize) -> Result<(Instruction<'a>, usize), DecodeError> {
+        let opcode = *code.get(pc).ok_or(DecodeError::Truncated(pc))?;
+        let prefix = Prefix::from(opcode).ok_or(DecodeError::UnknownInstruction(pc, opcode))?;
+        let postfix = opcode & 0x0F;
+        let unknown = DecodeError::UnknownInstruction(pc, opcode);
+
+        let operands = |len: usize| {
+            code.get(pc + 1..pc + 1 + len)
+                .ok_or(DecodeError::Truncated(pc))
+        };
+        let target = || operands(2).map(|t| u16::from_le_bytes([t[0], t[1]]));
+
+        let ins = match prefix {
+            Prefix::POP => Instruction::Pop(postfix),
+            Prefix::PUSHB => Instruction::PushB(operands(postfix as usize)?),
+            Prefix::PEEK => Instruction::Peek(postfix),
+            Prefix::PUSHI => Instruction::PushI(operands(postfix as usize * 4)?),
+            Prefix::JMP => Instruction::Jmp(target()?),
+            Prefix::JZ => Instruction::Jz(target()?),
+            Prefix::JNZ => Instruction::Jnz(target()?),
+            Prefix::UNARY => Instruction::Unary(Unary::from(postfix).ok_or(unknown)?),
+            Prefix::BINARY => Instruction::Binary(Binary::from(postfix).ok_or(unknown)?),
+            Prefix::SWAP => Instruction::Swap(postfix),
+            Prefix::USER if postfix == USER_EXTENDED => {
+                let operands = operands(2)?;
+                Instruction::Host {
+                    id: operands[0],
+                    inputs: operands[1] >> 4,
+                    outputs: operands[1] & 0x0F,
+                }
+            }
+            Prefix::USER => Instruction::User(UserCommand::from(postfix).ok_or(unknown)?),
+            Prefix::SPECIAL if postfix == SPECIAL_ON => {
+                let header = operands(4)?;
+                Instruction::On {
+                    kind: EventKind::from(header[0]).ok_or(unknown)?,
+                    end: u16::from_le_bytes([header[1], header[2]]),
+                    key: &operands(4 + header[3] as usize)?[4..],
+                }
+            }
+            Prefix::SPECIAL if postfix == SPECIAL_SPAWN => Instruction::Spawn(target()?),
+            Prefix::SPECIAL if postfix == SPECIAL_POSITION => {
+                Instruction::Position(Coordinate::from(operands(1)?[0]).ok_or(unknown)?)
+            }
+            Prefix::SPECIAL => Instruction::Special(Special::from(postfix).ok_or(unknown)?),
+        };
+
+        Ok((ins, ins.len()))
+    }
+
+    /// Appends encoded instruction to `code`, postfix operands are truncated to 4 bits
+    pub fn encode(&self, code: &mut Vec<u8>) {
+        let prefix = self.prefix() as u8;
+        match *self {
+            Instruction::Pop(n) | Instruction::Peek(n) | Instruction::Swap(n) => {
+                code.push(prefix | (n & 0x0F))
+            }
+            Instruction::PushB(bytes) => {
+                code.push(prefix | (bytes.len() as u8 & 0x0F));
+                code.extend_from_slice(bytes);
+            }
+            Instruction::PushI(bytes) => {
+                code.push(prefix | ((bytes.len() / 4) as u8 & 0x0F));
+                code.extend_from_slice(bytes);
+            }
+            Instruction::Jmp(target) | Instruction::Jz(target) | Instruction::Jnz(target) => {
+                code.push(prefix);
+                code.extend_from_slice(&target.to_le_bytes());
+            }
+            Instruction::Unary(u) => code.push(prefix | u as u8),
+            Instruction::Binary(b) => code.push(prefix | b as u8),
+            Instruction::User(u) => code.push(prefix | u as u8),
+            Instruction::Host {
+                id,
+                inputs,
+                outputs,
+            } => code.extend([
+                prefix | USER_EXTENDED,
+                id,
+                (inputs & 0x0F) << 4 | (outputs & 0x0F),
+            ]),
+            Instruction::Special(s) => code.push(prefix | s as u8),
+            Instruction::Spawn(end) => {
+                code.push(prefix | SPECIAL_SPAWN);
+                code.extend_from_slice(&end.to_le_bytes());
+            }
+            Instruction::Position(coordinate) => {
+                code.extend([prefix | SPECIAL_POSITION, coordinate as u8])
+            }
+            Instruction::On { kind, key, end } => {
+                code.extend([prefix | SPECIAL_ON, kind as u8]);
+                code.extend_from_slice(&end.to_le_bytes());
+                code.push(key.len() as u8);
+                code.extend_from_slice(key);
+            }
+        }
+    }
+
+    pub fn prefix(&self) -> Prefix {
+        match self {
+            Instruction::Pop(_) => Prefix::POP,
+            Instruction::PushB(_) => Prefix::PUSHB,
+            Instruction::Peek(_) => Prefix::PEEK,
+            Instruction::PushI(_) => Prefix::PUSHI,
+            Instruction::Jmp(_) => Prefix::JMP,
+            Instruction::Jz(_) => Prefix::JZ,
+            Instruction::Jnz(_) => Prefix::JNZ,
+            Instruction::Unary(_) => Prefix::UNARY,
+            Instruction::Binary(_) => Prefix::BINARY,
+            Instruction::Swap(_) => Prefix::SWAP,
+            Instruction::User(_) | Instruction::Host { .. } => Prefix::USER,
+            Instruction::Special(_)
+            | Instruction::On { .. }
+            | Instruction::Spawn(_)
+            | Instruction::Position(_) => Prefix::SPECIAL,
+        }
+    }
+
+    /// Encoded length in bytes
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        match self {
+            Instruction::PushB(bytes) | Instruction::PushI(bytes) => 1 + bytes.len(),
+            Instruction::Jmp(_) | Instruction::Jz(_) | Instruction::Jnz(_) => 3,
+            Instruction::Host { .. } | Instruction::Spawn(_) => 3,
+            Instruction::On { key, .. } => 5 + key.len(),
+            Instruction::Position(_) => 2,
+            _ => 1,
+        }
+    }
+
+    /// Number of stack values the instruction needs and the number it leaves in their place
+    pub fn stack_effect(&self) -> (usize, usize) {
+        match *self {
+            Instruction::Pop(n) => (n as usize, 0),
+            Instruction::PushB(_) | Instruction::PushI(_) => (0, self.immediates().count()),
+            Instruction::Peek(n) => (n as usize + 1, n as usize + 2),
+            Instruction::Swap(n) => (n as usize + 1, n as usize + 1),
+            Instruction::Jmp(_) => (0, 0),
+            Instruction::Jz(_) | Instruction::Jnz(_) => (1, 1),
+            Instruction::Unary(_) => (1, 1),
+            Instruction::Binary(_) => (2, 1),
+            Instruction::User(u) => u.stack_effect(),
+            Instruction::Host {
+                inputs, outputs, ..
+            } => (inputs as usize, outputs as usize),
+            Instruction::Special(_) | Instruction::On { .. } | Instruction::Spawn(_) => (0, 0),
+            Instruction::Position(_) => (1, 1),
+        }
+    }
+
+    /// Values pushed by `PushB` and `PushI`
+    pub fn immediates(&self) -> impl Iterator<Item = u32> + 'a {
+        let (bytes, width): (&'a [u8], usize) = match *self {
+            Instruction::PushB([]) => (&[0], 1),
+            Instruction::PushB(bytes) => (bytes, 1),
+            Instruction::PushI(bytes) => (bytes, 4),
+            _ => (&[], 1),
+        };
+        bytes.chunks_exact(width).map(|c| {
+            c.iter()
+                .rev()
+                .fold(0u32, |acc, b| (acc << 8) | u32::from(*b))
+        })
+    }
+}
+
+impl fmt::Display for Instruction<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\t", self.prefix())?;
+        match self {
+            Instruction::Pop(n) | Instruction::Peek(n) | Instruction::Swap(n) => write!(f, "{}", n),
+            Instruction::PushB([]) => write!(f, "0"),
+            Instruction::PushB(bytes) | Instruction::PushI(bytes) => write!(f, "{:02x?}", bytes),
+            Instruction::Jmp(target) | Instruction::Jz(target) | Instruction::Jnz(target) => {
+                write!(f, "to {}", target)
+            }
+            Instruction::Unary(u) => write!(f, "{}", u),
+            Instruction::Binary(b) => write!(f, "{}", b),
+            Instruction::User(u) => write!(f, "{}", u),
+            Instruction::Host {
+                id,
+                inputs,
+                outputs,
+            } => write!(f, "host {} in {} out {}", id, inputs, outputs),
+            Instruction::Special(s) => write!(f, "{}", s),
+            Instruction::Spawn(end) => write!(f, "spawn to {}", end),
+            Instruction::Position(coordinate) => write!(f, "position {}", coordinate),
+            Instruction::On { kind, key, end } => match kind {
+                EventKind::BUTTON => {
+                    write!(f, "on {} {} to {}", kind, key.first().unwrap_or(&0), end)
+                }
+                EventKind::PARAM_CHANGED => {
+                    write!(f, "on {} {} to {}", kind, String::from_utf8_lossy(key), end)
+                }
+            },
+        }
+    }
+}
+
+/// Iterator over `(pc, instruction)` pairs of a program, stops after the first decode error
+pub struct Instructions<'a> {
+    code: &'a [u8],
+    pc: usize,
+}
+
+impl<'a> Instructions<'a> {
+    pub fn new(code: &'a [u8]) -> Self {
+        Instructions { code, pc: 0 }
+    }
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Result<(usize, Instruction<'a>), DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pc >= self.code.len() {
+            return None;
+        }
+
+        let pc = self.pc;
+        match Instruction::decode(self.code, pc) {
+            Ok((ins, len)) => {
+                self.pc += len;
+                Some(Ok((pc, ins)))
+            }
+            Err(e) => {
+                self.pc = self.code.len();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::FromSource;
+    use crate::program::Program;
+
+    #[test]
+    fn check_encode_decode_round_trip() {
+        let p = Program::from_source(include_str!("../example_progs/fade_life.txt")).unwrap();
+        let mut code = vec![];
+        for decoded in p.instructions() {
+            let (pc, ins) = decoded.unwrap();
+            assert_eq!(pc, code.len());
+            ins.encode(&mut code);
+        }
+        assert_eq!(&code, p.code());
+    }
+
+    #[test]
+    fn check_decode_errors() {
+        assert_eq!(
+            Instruction::decode(&[0x11, 0x05, 0x32, 0x00], 2),
+            Err(DecodeError::Truncated(2))
+        );
+        assert_eq!(
+            Instruction::decode(&[0xA0], 0),
+            Err(DecodeError::UnknownInstruction(0, 0xA0))
+        );
+        assert_eq!(
+            Instruction::decode(&[0xA0], 1),
+            Err(DecodeError::Truncated(1))
+        );
+        assert_eq!(
+            Instruction::decode(&[0x10], 0).map(|(ins, len)| (ins.immediates().collect(), len)),
+            Ok((vec![0], 1))
+        );
+    }
 }
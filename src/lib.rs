@@ -1,4 +1,11 @@
+pub mod assembler;
+pub mod budget;
 pub mod compiler;
-mod instructions;
+pub mod container;
+pub mod cost;
+pub mod decompiler;
+pub mod instructions;
 pub mod program;
+pub mod source_map;
+pub mod verifier;
 pub mod vm;
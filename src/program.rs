@@ -1,16 +1,24 @@
 use std::fmt;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::Read;
 
 use thiserror::Error;
 
-use crate::instructions::{Binary, Prefix, Special, Unary, UserCommand};
+use crate::instructions::{
+    Binary, Coordinate, DecodeError, Instruction, Instructions, Prefix, Special, Unary, UserCommand,
+};
+use crate::source_map::{SourceMap, Span};
+use crate::vm::event::Event;
+use crate::vm::host::{HostCommands, Signature};
 
 #[derive(Clone)]
 pub struct Program {
     pub(crate) code: Vec<u8>,
     pub(crate) stack_size: i32,
     pub(crate) offset: usize,
+    pub(crate) source_map: Option<SourceMap>,
+    /// Host commands the code calls, by id, as they were registered when it was compiled
+    pub(crate) host_commands: Vec<(u8, Signature)>,
 }
 
 pub const POSTFIX_MAX: u8 = 15; // U4::MAX
@@ -35,24 +43,51 @@ pub enum SyntaxError {
     #[error("fragment in {0} cannot modify stack size")]
     FragmentCannotModifyStackSize(&'static str),
 
+    #[error("jump target {0} does not fit into 16 bits")]
+    JumpOutOfRange(usize),
+
+    #[error("unknown function: {0}")]
+    UnknownFunction(String),
+
+    #[error("{0} takes {1} arguments, {2} given")]
+    ArgumentCount(String, usize, usize),
+
+    #[error("{0} does not return a single value and can not be used in expressions")]
+    NoValue(String),
+
+    #[error("event key of {0} bytes does not fit into a handler")]
+    EventKeyTooLong(usize),
+
     #[error("could not parse, remainder: {0}")]
     CouldNotParseRamainder(String),
     #[error("parse error")]
     ParseError(String),
 }
 
+impl Default for Program {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[allow(dead_code)]
 impl Program {
-    fn write(&mut self, buffer: &[u8]) -> &mut Program {
-        self.code.write_all(buffer).unwrap();
+    fn emit(&mut self, ins: Instruction) -> &mut Program {
+        ins.encode(&mut self.code);
         self
     }
 
+    fn target(address: usize) -> Result<u16, SyntaxError> {
+        u16::try_from(address).map_err(|_| SyntaxError::JumpOutOfRange(address))
+    }
+
     pub fn from_binary(data: Vec<u8>) -> Program {
         Program {
             code: data,
             stack_size: 0,
             offset: 0,
+            source_map: None,
+            host_commands: vec![],
         }
     }
 
@@ -63,6 +98,8 @@ impl Program {
             code: stored_bin,
             stack_size: 0,
             offset: 0,
+            source_map: None,
+            host_commands: vec![],
         })
     }
 
@@ -71,11 +108,13 @@ impl Program {
             code: Vec::<u8>::new(),
             stack_size: 0,
             offset: 0,
+            source_map: None,
+            host_commands: vec![],
         }
     }
 
     pub fn nop(&mut self) -> &mut Program {
-        self.write(&[Prefix::POP as u8]) // POP 0
+        self.emit(Instruction::Pop(0))
     }
 
     pub fn pop(&mut self, n: u8) -> Result<&mut Program, SyntaxError> {
@@ -83,7 +122,7 @@ impl Program {
             Err(SyntaxError::PostfixLimit("pop", n))
         } else {
             self.stack_size -= i32::from(n);
-            Ok(self.write(&[Prefix::POP as u8 | n])) // POP n
+            Ok(self.emit(Instruction::Pop(n)))
         }
     }
 
@@ -98,7 +137,7 @@ impl Program {
             Err(SyntaxError::PostfixLimit("peek", n))
         } else {
             self.stack_size += 1;
-            Ok(self.write(&[Prefix::PEEK as u8 | n])) // PEEK n
+            Ok(self.emit(Instruction::Peek(n)))
         }
     }
 
@@ -106,63 +145,68 @@ impl Program {
         if n > POSTFIX_MAX {
             Err(SyntaxError::PostfixLimit("swap", n))
         } else {
-            Ok(self.write(&[Prefix::SWAP as u8 | n]))
+            Ok(self.emit(Instruction::Swap(n)))
         }
     }
 
     pub fn unary(&mut self, u: Unary) -> &mut Program {
-        self.write(&[Prefix::UNARY as u8 | u as u8]) // UNARY u
+        self.emit(Instruction::Unary(u))
     }
 
     pub(crate) fn binary(&mut self, u: Binary) -> &mut Program {
         self.stack_size -= 1;
-        self.write(&[Prefix::BINARY as u8 | u as u8]) // BINARY u
+        self.emit(Instruction::Binary(u))
     }
 
     pub fn special(&mut self, u: Special) -> &mut Program {
         self.stack_size += match u {
             Special::DUMP => 0,
             Special::TWOBYTE => unimplemented!(),
+            Special::RETURN | Special::YIELD => 0,
         };
-        self.write(&[Prefix::SPECIAL as u8 | u as u8]) // SPECIAL u
+        self.emit(Instruction::Special(u))
     }
 
     pub fn user(&mut self, u: UserCommand) -> &mut Program {
-        self.stack_size += match u {
-            UserCommand::GET_LENGTH => 1,
-            UserCommand::GET_PRECISE_TIME => 1,
-            UserCommand::GET_WALL_TIME => 1,
-            UserCommand::BLIT => 0,
-            UserCommand::SET_PIXEL => -1,
-            UserCommand::RANDOM_INT => 0,
-            UserCommand::GET_PIXEL => 0,
-        };
-        self.write(&[Prefix::USER as u8 | u as u8]) // SPECIAL u
+        let (inputs, outputs) = u.stack_effect();
+        self.stack_size += outputs as i32 - inputs as i32;
+        self.emit(Instruction::User(u))
     }
 
-    fn skip<F>(&mut self, prefix: Prefix, mut builder: F) -> Result<&mut Program, SyntaxError>
+    /// Coordinate of the led whose index is on the stack, see `vm::layout`
+    pub fn position(&mut self, coordinate: Coordinate) -> &mut Program {
+        self.emit(Instruction::Position(coordinate))
+    }
+
+    /// Calls host command `id`, see `vm::host`
+    pub fn host(&mut self, id: u8, inputs: u8, outputs: u8) -> &mut Program {
+        self.stack_size += outputs as i32 - inputs as i32;
+        self.emit(Instruction::Host {
+            id,
+            inputs,
+            outputs,
+        })
+    }
+
+    fn skip<F>(
+        &mut self,
+        jump: fn(u16) -> Instruction<'static>,
+        mut builder: F,
+    ) -> Result<&mut Program, SyntaxError>
     where
         F: FnMut(&mut Program) -> Result<(), SyntaxError>,
     {
-        let mut fragment = Program {
-            code: Vec::<u8>::new(),
-            stack_size: 0,
-            offset: self.current_pc() + 3, // before fragment would be inst+2bytes address
-        };
+        let mut fragment = self.fragment(3); // before fragment would be inst+2bytes address
         builder(&mut fragment)?;
         if fragment.stack_size != 0 {
             return Err(SyntaxError::FragmentCannotModifyStackSize("branch"));
         }
 
         // [JS/JNS, addr, addr, ...fragment], so we add 3 on top of fragment size to get end addr
-        let end_address = self.current_pc() + 3 + fragment.code.len();
+        let end_address = Self::target(self.current_pc() + 3 + fragment.code.len())?;
         // Always write three-byte jumps for now
-        self.write(&[
-            prefix as u8,
-            (end_address & 0xFF) as u8,
-            ((end_address >> 8) & 0xFF) as u8,
-        ]);
-        self.write(&fragment.code);
+        self.emit(jump(end_address));
+        self.append(fragment);
         Ok(self)
     }
 
@@ -170,37 +214,72 @@ impl Program {
     where
         F: FnMut(&mut Program) -> Result<(), SyntaxError>,
     {
-        self.skip(Prefix::JNZ, builder)
+        self.skip(Instruction::Jnz, builder)
     }
 
     pub fn if_not_zero<F>(&mut self, builder: F) -> Result<&mut Program, SyntaxError>
     where
         F: FnMut(&mut Program) -> Result<(), SyntaxError>,
     {
-        self.skip(Prefix::JZ, builder)
+        self.skip(Instruction::Jz, builder)
+    }
+
+    /// Registers the code built by `builder` as handler for `event`, it runs on its own stack
+    pub fn on<F>(&mut self, event: &Event, mut builder: F) -> Result<&mut Program, SyntaxError>
+    where
+        F: FnMut(&mut Program) -> Result<(), SyntaxError>,
+    {
+        let (kind, key) = event.key();
+        if key.len() > u8::MAX as usize {
+            return Err(SyntaxError::EventKeyTooLong(key.len()));
+        }
+        // [ON, kind, addr, addr, key length, ...key][...handler...][RETURN]
+        let header = 5 + key.len();
+        let mut fragment = self.fragment(header);
+        builder(&mut fragment)?;
+        if fragment.stack_size != 0 {
+            return Err(SyntaxError::FragmentCannotModifyStackSize("event handler"));
+        }
+        fragment.special(Special::RETURN);
+
+        let end = Self::target(self.current_pc() + header + fragment.code.len())?;
+        self.emit(Instruction::On { kind, key, end });
+        self.append(fragment);
+        Ok(self)
+    }
+
+    /// Starts the code built by `builder` as a task with its own stack
+    pub fn spawn<F>(&mut self, mut builder: F) -> Result<&mut Program, SyntaxError>
+    where
+        F: FnMut(&mut Program) -> Result<(), SyntaxError>,
+    {
+        // [SPAWN, addr, addr][...task...][RETURN]
+        let mut fragment = self.fragment(3);
+        builder(&mut fragment)?;
+        if fragment.stack_size != 0 {
+            return Err(SyntaxError::FragmentCannotModifyStackSize("task"));
+        }
+        fragment.special(Special::RETURN);
+
+        let end = Self::target(self.current_pc() + 3 + fragment.code.len())?;
+        self.emit(Instruction::Spawn(end));
+        self.append(fragment);
+        Ok(self)
     }
 
     pub fn repeat_forever<F>(&mut self, mut builder: F) -> Result<&mut Program, SyntaxError>
     where
         F: FnMut(&mut Program) -> Result<(), SyntaxError>,
     {
-        let mut fragment = Program {
-            code: Vec::<u8>::new(),
-            stack_size: 0,
-            offset: self.current_pc(),
-        };
+        let mut fragment = self.fragment(0);
         builder(&mut fragment)?;
         if fragment.stack_size != 0 {
             return Err(SyntaxError::FragmentCannotModifyStackSize("forever loop"));
         }
 
-        let start = self.current_pc();
-        self.write(&fragment.code);
-        self.write(&[
-            Prefix::JMP as u8,
-            (start & 0xFF) as u8,
-            ((start >> 8) & 0xFF) as u8,
-        ]);
+        let start = Self::target(self.current_pc())?;
+        self.append(fragment);
+        self.emit(Instruction::Jmp(start));
         Ok(self)
     }
 
@@ -208,36 +287,57 @@ impl Program {
         self.offset + self.code.len()
     }
 
+    /// Empty program for code which will be appended `gap` bytes after the current end
+    fn fragment(&self, gap: usize) -> Program {
+        Program {
+            code: Vec::<u8>::new(),
+            stack_size: 0,
+            offset: self.current_pc() + gap,
+            source_map: self.source_map.as_ref().map(|_| SourceMap::default()),
+            host_commands: vec![],
+        }
+    }
+
+    fn append(&mut self, fragment: Program) {
+        let resume_pc = self.current_pc() + fragment.code.len();
+        self.code.extend(fragment.code);
+        if let (Some(map), Some(fragment_map)) = (&mut self.source_map, fragment.source_map) {
+            // Code after the fragment belongs to the statement enclosing it again
+            let enclosing = map.lookup(resume_pc);
+            map.extend(fragment_map);
+            if let Some(span) = enclosing {
+                map.insert(resume_pc, span);
+            }
+        }
+    }
+
+    /// Attributes code emitted from now on to `span`, when recording a source map
+    pub(crate) fn mark(&mut self, span: Span) -> &mut Program {
+        let pc = self.current_pc();
+        if let Some(map) = &mut self.source_map {
+            map.insert(pc, span);
+        }
+        self
+    }
+
     pub fn repeat<F>(&mut self, mut builder: F) -> Result<&mut Program, SyntaxError>
     where
         F: FnMut(&mut Program) -> Result<(), SyntaxError>,
     {
-        let mut fragment = Program {
-            code: Vec::<u8>::new(),
-            stack_size: 0,
-            offset: self.current_pc() + 3, // before fragment would be inst+2bytes address
-        };
+        let mut fragment = self.fragment(3); // before fragment would be inst+2bytes address
         builder(&mut fragment)?;
         if fragment.stack_size != 0 {
             return Err(SyntaxError::FragmentCannotModifyStackSize("for loop"));
         }
 
         let start = self.current_pc();
-        // [JMP,addr,addr][...loop body...][DEC][JMP,addr,addr]
-        let end = start + 3 + fragment.code.len() + 1 + 3;
-        self.write(&[
-            Prefix::JZ as u8,
-            (end & 0xFF) as u8,
-            ((end >> 8) & 0xFF) as u8,
-        ]);
-
-        self.write(&fragment.code);
-        self.write(&[Prefix::UNARY as u8 | Unary::DEC as u8]);
-        self.write(&[
-            Prefix::JMP as u8,
-            (start & 0xFF) as u8,
-            ((start >> 8) & 0xFF) as u8,
-        ]);
+        // [JZ,addr,addr][...loop body...][DEC][JMP,addr,addr]
+        let end = Self::target(start + 3 + fragment.code.len() + 1 + 3)?;
+        let start = Self::target(start)?;
+        self.emit(Instruction::Jz(end));
+        self.append(fragment);
+        self.emit(Instruction::Unary(Unary::DEC));
+        self.emit(Instruction::Jmp(start));
         Ok(self)
     }
 
@@ -345,133 +445,100 @@ impl Program {
     pub fn push(&mut self, b: u32) -> &mut Program {
         self.stack_size += 1;
         match b {
-            0 => self.code.write(&[Prefix::PUSHB as u8]).unwrap(),
-            _ if b <= 0xFF => self
-                .code
-                .write(&[Prefix::PUSHB as u8 | 0x01, b as u8])
-                .unwrap(),
-            _ => self
-                .code
-                .write(&[
-                    Prefix::PUSHI as u8 | 0x01,
-                    (b & 0xFF) as u8,
-                    ((b >> 8) & 0xFF) as u8,
-                    ((b >> 16) & 0xFF) as u8,
-                    ((b >> 24) & 0xFF) as u8,
-                ])
-                .unwrap(),
-        };
-        self
+            0 => self.emit(Instruction::PushB(&[])),
+            _ if b <= 0xFF => self.emit(Instruction::PushB(&[b as u8])),
+            _ => self.emit(Instruction::PushI(&b.to_le_bytes())),
+        }
     }
 
     pub fn code(&self) -> &Vec<u8> {
         &self.code
     }
+
+    pub(crate) fn open_variable(&mut self, name: &str, slot: usize) {
+        let pc = self.current_pc();
+        if let Some(map) = &mut self.source_map {
+            map.open_variable(name, slot, pc);
+        }
+    }
+
+    pub(crate) fn close_variable(&mut self, name: &str) {
+        let pc = self.current_pc();
+        if let Some(map) = &mut self.source_map {
+            map.close_variable(name, pc);
+        }
+    }
+
+    pub fn source_map(&self) -> Option<&SourceMap> {
+        self.source_map.as_ref()
+    }
+
+    /// Attaches or, with `None`, strips debug info
+    pub fn set_source_map(&mut self, source_map: Option<SourceMap>) {
+        self.source_map = source_map;
+    }
+
+    /// Names and signatures of the host commands the code calls, sorted by id. Recorded by the
+    /// compiler and stored in containers, empty for programs assembled or loaded from plain bytecode.
+    pub fn host_commands(&self) -> &[(u8, Signature)] {
+        &self.host_commands
+    }
+
+    pub fn set_host_commands(&mut self, host_commands: Vec<(u8, Signature)>) {
+        self.host_commands = host_commands;
+    }
+
+    /// Records the signatures in `commands` of the host commands the code calls
+    pub(crate) fn record_host_commands(&mut self, commands: &HostCommands) {
+        let mut ids: Vec<u8> = self
+            .instructions()
+            .filter_map(|item| match item {
+                Ok((_, Instruction::Host { id, .. })) => Some(id),
+                _ => None,
+            })
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        self.host_commands = ids
+            .into_iter()
+            .filter_map(|id| commands.signature(id).map(|s| (id, s.clone())))
+            .collect();
+    }
+
+    /// Decodes the program one instruction at a time, yielding `(pc, instruction)`
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions::new(&self.code)
+    }
 }
 
 impl fmt::Debug for Program {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut pc = 0;
         while pc < self.code.len() {
-            let ins = Prefix::from(self.code[pc]);
-            if let Some(i) = ins {
-                let postfix = self.code[pc] & 0x0F;
-                write!(f, "{:04}.\t{:02x}\t{}", pc, self.code[pc], i)?;
-                match i {
-                    Prefix::PUSHI => {
-                        let end = (postfix as usize) * 4 + pc + 1;
-                        if end > self.code.len() {
-                            write!(f, "\t(invalid, overruns code; size={})", (postfix as usize))?;
-                            return Ok(());
-                        } else {
-                            write!(
-                                f,
-                                "\t{:02x?}",
-                                &self.code[(pc + 1)..(pc + 1 + (postfix as usize) * 4)]
-                            )?;
-                            pc += (postfix as usize) * 4;
-                        }
-                    }
-                    Prefix::PUSHB => {
-                        if postfix == 0 {
-                            write!(f, "\t0")?;
-                        } else {
-                            let end = (postfix as usize) + pc + 1;
-                            if end > self.code.len() {
-                                write!(
-                                    f,
-                                    "\t(invalid, overruns code; size={})",
-                                    (postfix as usize)
-                                )?;
-                                return Ok(());
-                            } else {
-                                write!(
-                                    f,
-                                    "\t{:02x?}",
-                                    &self.code[(pc + 1)..(pc + 1 + (postfix as usize))]
-                                )?;
-                                pc += postfix as usize;
-                            }
-                        }
-                    }
-                    Prefix::JMP | Prefix::JZ | Prefix::JNZ => {
-                        if self.code.len() < (pc + 1) {
-                            write!(f, "\t(invalid, overruns code)")?;
-                            return Ok(());
-                        }
-                        let target =
-                            u32::from(self.code[pc + 1]) | u32::from(self.code[pc + 2]) << 8;
-                        write!(f, "\tto {}", target)?;
-                        pc += 2
-                    }
-                    Prefix::BINARY => {
-                        if let Some(op) = Binary::from(postfix) {
-                            write!(f, "\t{}", op)?;
-                        } else {
-                            write!(f, "\tunknown {}", postfix)?;
-                        }
-                    }
-                    Prefix::UNARY => {
-                        if let Some(op) = Unary::from(postfix) {
-                            write!(f, "\t{}", op)?;
-                        } else {
-                            write!(f, "\tunknown {}", postfix)?;
-                        }
-                    }
-                    Prefix::USER => {
-                        let name = match postfix {
-                            0 => "get_length",
-                            1 => "get_wall_time",
-                            2 => "get_precise_time",
-                            3 => "set_pixel",
-                            4 => "blit",
-                            5 => "random_int",
-                            6 => "get_pixel",
-                            _ => "(unknown user function)",
-                        };
-                        write!(f, "\t{}", name)?;
-                    }
-                    Prefix::SPECIAL => {
-                        let name = match postfix {
-                            12 => "swap",
-                            13 => "dump",
-                            14 => "yield",
-                            15 => "two-byte instruction",
-                            _ => "(unknown special function)",
-                        };
-                        write!(f, "\t{}", name)?;
-                    }
-                    _ => {
-                        write!(f, "\t{}", postfix)?;
+            write!(f, "{:04}.\t{:02x}\t", pc, self.code[pc])?;
+            match Instruction::decode(&self.code, pc) {
+                Ok((ins, len)) => {
+                    writeln!(f, "{}", ins)?;
+                    pc += len;
+                }
+                Err(DecodeError::UnknownInstruction(_, opcode)) => {
+                    if let Some(prefix) = Prefix::from(opcode) {
+                        writeln!(f, "{}\tunknown {}", prefix, opcode & 0x0F)?;
+                        pc += 1;
+                    } else {
+                        writeln!(f, "Unknown instruction")?;
+                        break;
                     }
                 }
-                writeln!(f)?;
-            } else {
-                writeln!(f, "{:04}.\t{:02x}\tUnknown instruction", pc, self.code[pc])?;
-                break;
+                Err(DecodeError::Truncated(_)) => {
+                    writeln!(
+                        f,
+                        "{}\t(invalid, overruns code)",
+                        Prefix::from(self.code[pc]).unwrap()
+                    )?;
+                    break;
+                }
             }
-
-            pc += 1;
         }
         Ok(())
     }
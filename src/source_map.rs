@@ -0,0 +1,182 @@
+use std::fmt;
+
+/// Position in source code, lines and columns count from 1
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub line: u32,
+    pub column: u32,
+}
+
+impl Span {
+    /// Line and column of byte `offset` in `source`
+    pub fn at(source: &str, offset: usize) -> Span {
+        let before = &source[..offset];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Span {
+            line: before.matches('\n').count() as u32 + 1,
+            column: before[line_start..].chars().count() as u32 + 1,
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/// Maps bytecode offsets to the statement they were compiled from. Each entry
+/// covers code from its pc up to the pc of the next entry.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    entries: Vec<(usize, Span)>,
+    variables: Vec<Variable>,
+}
+
+/// Named variable living in stack slot `slot`, counted from the bottom, while `start <= pc < end`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Variable {
+    pub name: String,
+    pub slot: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl SourceMap {
+    /// Attributes code from `pc` onwards to `span`
+    pub fn insert(&mut self, pc: usize, span: Span) {
+        let i = self.entries.partition_point(|(p, _)| *p < pc);
+        match self.entries.get_mut(i) {
+            Some(entry) if entry.0 == pc => entry.1 = span,
+            _ => self.entries.insert(i, (pc, span)),
+        }
+    }
+
+    pub fn lookup(&self, pc: usize) -> Option<Span> {
+        let i = self.entries.partition_point(|(p, _)| *p <= pc);
+        i.checked_sub(1).map(|i| self.entries[i].1)
+    }
+
+    /// First pc of every statement on `line`
+    pub fn pcs_of_line(&self, line: u32) -> impl Iterator<Item = usize> + '_ {
+        self.entries
+            .iter()
+            .filter(move |(_, span)| span.line == line)
+            .map(|(pc, _)| *pc)
+    }
+
+    /// Variables in scope at `pc`, outer scopes first
+    pub fn variables_at(&self, pc: usize) -> impl Iterator<Item = &Variable> {
+        self.variables
+            .iter()
+            .filter(move |v| v.start <= pc && pc < v.end)
+    }
+
+    /// Starts a variable at `pc`, it stays in scope until `close_variable`
+    pub(crate) fn open_variable(&mut self, name: &str, slot: usize, pc: usize) {
+        self.variables.push(Variable {
+            name: name.to_string(),
+            slot,
+            start: pc,
+            end: usize::MAX,
+        });
+    }
+
+    pub(crate) fn close_variable(&mut self, name: &str, pc: usize) {
+        if let Some(v) = self
+            .variables
+            .iter_mut()
+            .rev()
+            .find(|v| v.name == name && v.end == usize::MAX)
+        {
+            v.end = pc;
+        }
+    }
+
+    pub(crate) fn extend(&mut self, other: SourceMap) {
+        for (pc, span) in other.entries {
+            self.insert(pc, span);
+        }
+        self.variables.extend(other.variables);
+    }
+
+    /// Encodes as `[count, (pc, line, column)...]` followed by
+    /// `[count, (name length, name, slot, start, end)...]`, numbers are little endian u32
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::from((self.entries.len() as u32).to_le_bytes());
+        for (pc, span) in self.entries.iter() {
+            data.extend((*pc as u32).to_le_bytes());
+            data.extend(span.line.to_le_bytes());
+            data.extend(span.column.to_le_bytes());
+        }
+        data.extend((self.variables.len() as u32).to_le_bytes());
+        for v in self.variables.iter() {
+            data.extend((v.name.len() as u32).to_le_bytes());
+            data.extend(v.name.as_bytes());
+            for n in [v.slot, v.start, v.end] {
+                data.extend((n.min(u32::MAX as usize) as u32).to_le_bytes());
+            }
+        }
+        data
+    }
+
+    pub fn from_bytes(mut data: &[u8]) -> Option<SourceMap> {
+        let mut map = SourceMap::default();
+        for _ in 0..word(&mut data)? {
+            let (pc, line, column) = (word(&mut data)?, word(&mut data)?, word(&mut data)?);
+            map.insert(pc as usize, Span { line, column });
+        }
+        for _ in 0..word(&mut data)? {
+            let length = word(&mut data)? as usize;
+            let name = String::from_utf8(take(&mut data, length)?.to_vec()).ok()?;
+            let [slot, start, end] =
+                [word(&mut data)?, word(&mut data)?, word(&mut data)?].map(|n| match n {
+                    u32::MAX => usize::MAX,
+                    n => n as usize,
+                });
+            map.variables.push(Variable {
+                name,
+                slot,
+                start,
+                end,
+            });
+        }
+        data.is_empty().then_some(map)
+    }
+}
+
+fn take<'a>(data: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    let (bytes, rest) = data.split_at_checked(n)?;
+    *data = rest;
+    Some(bytes)
+}
+
+fn word(data: &mut &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(take(data, 4)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::FromSource;
+    use crate::program::Program;
+    use crate::vm::{errors::VMError, Outcome, VM};
+
+    #[test]
+    fn check_runtime_error_location() {
+        let source = "let a = 0;\nloop {\n    for(i=3) {\n        set_pixel(a, 1, 2, 3, 4);\n        a = a + 1;\n    };\n    blit;\n}";
+        let p = Program::from_source(source).unwrap();
+        let map = p.source_map().unwrap();
+        assert_eq!(map.lookup(0), Some(Span { line: 1, column: 1 }));
+        assert_eq!(SourceMap::from_bytes(&map.to_bytes()).as_ref(), Some(map));
+
+        let mut state = VM::new(2, Default::default()).start(p, Default::default());
+        match state.run() {
+            Outcome::Error(e) => {
+                assert_eq!(e.context().unwrap().span, Some(Span { line: 4, column: 9 }));
+                assert!(matches!(e.root(), VMError::PixelOutOfRange(2, 2)));
+            }
+            _ => panic!("expected error"),
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use crate::instructions::{DecodeError, Instruction, Special, Unary};
+use crate::program::Program;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+
+    #[error("jump at {0} lands inside the instruction containing {1}")]
+    JumpIntoInstruction(usize, usize),
+
+    #[error("jump at {0} targets {1}, past the end of the code")]
+    JumpOutOfBounds(usize, usize),
+
+    #[error("stack underflow at {0}: needs {1} values, {2} available")]
+    StackUnderflow(usize, usize, usize),
+
+    #[error("stack depth at {0} is {1} or {2} depending on path, loop bodies and branches must be stack neutral")]
+    StackMismatch(usize, usize, usize),
+
+    #[error("unimplemented instruction at {0}")]
+    Unimplemented(usize),
+}
+
+impl Program {
+    /// Checks the code is safe to load: every instruction decodes, every jump lands
+    /// on an instruction and the stack depth is the same on every path reaching an instruction.
+    pub fn verify(&self) -> Result<(), Vec<VerifyError>> {
+        let code = self.code();
+        let mut errors = vec![];
+
+        // Instruction boundaries, `None` where no instruction starts
+        let mut decoded: Vec<Option<(Instruction, usize)>> = vec![None; code.len()];
+        let mut decoded_until = code.len();
+        let mut pc = 0;
+        while pc < code.len() {
+            match Instruction::decode(code, pc) {
+                Ok((ins, len)) => {
+                    decoded[pc] = Some((ins, len));
+                    pc += len;
+                }
+                Err(e) => {
+                    errors.push(e.into());
+                    decoded_until = pc;
+                    break;
+                }
+            }
+        }
+
+        for (pc, (ins, _)) in decoded
+            .iter()
+            .enumerate()
+            .filter_map(|(pc, d)| d.map(|d| (pc, d)))
+        {
+            if let Some(target) = jump_target(&ins) {
+                if target > code.len() {
+                    errors.push(VerifyError::JumpOutOfBounds(pc, target));
+                } else if target < decoded_until && decoded[target].is_none() {
+                    errors.push(VerifyError::JumpIntoInstruction(pc, target));
+                }
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        // Propagate stack depths along every path
+        let mut depths: Vec<Option<usize>> = vec![None; code.len() + 1];
+        let mut mismatched = vec![false; code.len() + 1];
+        let mut pending = vec![(0, 0)];
+        while let Some((pc, depth)) = pending.pop() {
+            match depths[pc] {
+                Some(known) if known == depth => continue,
+                Some(known) => {
+                    if !mismatched[pc] {
+                        mismatched[pc] = true;
+                        errors.push(VerifyError::StackMismatch(pc, known, depth));
+                    }
+                    continue;
+                }
+                None => depths[pc] = Some(depth),
+            }
+
+            let Some((ins, len)) = decoded.get(pc).copied().flatten() else {
+                // Falling off the end
+                continue;
+            };
+
+            if matches!(
+                ins,
+                Instruction::Special(Special::TWOBYTE) | Instruction::Unary(Unary::NEG)
+            ) {
+                errors.push(VerifyError::Unimplemented(pc));
+                continue;
+            }
+
+            let (inputs, outputs) = ins.stack_effect();
+            if depth < inputs {
+                errors.push(VerifyError::StackUnderflow(pc, inputs, depth));
+                continue;
+            }
+            let depth = depth - inputs + outputs;
+
+            match ins {
+                Instruction::Jmp(target) => pending.push((target as usize, depth)),
+                // Handlers and tasks start on an empty stack of their own
+                Instruction::On { end, .. } | Instruction::Spawn(end) => {
+                    pending.push((end as usize, depth));
+                    pending.push((pc + len, 0));
+                }
+                Instruction::Special(Special::RETURN) => {}
+                Instruction::Jz(target) | Instruction::Jnz(target) => {
+                    pending.push((target as usize, depth));
+                    pending.push((pc + len, depth));
+                }
+                _ => pending.push((pc + len, depth)),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+fn jump_target(ins: &Instruction) -> Option<usize> {
+    match ins {
+        Instruction::Jmp(t) | Instruction::Jz(t) | Instruction::Jnz(t) => Some(*t as usize),
+        Instruction::On { end, .. } | Instruction::Spawn(end) => Some(*end as usize),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::FromSource;
+    use crate::vm::{Outcome, VM};
+
+    const EXAMPLES: [&str; 10] = [
+        include_str!("../example_progs/blink.txt"),
+        include_str!("../example_progs/clamp.txt"),
+        include_str!("../example_progs/clock.txt"),
+        include_str!("../example_progs/fade_life.txt"),
+        include_str!("../example_progs/gradient_hue_rainbow.txt"),
+        include_str!("../example_progs/hue_rainbow.txt"),
+        include_str!("../example_progs/intrinsics.txt"),
+        include_str!("../example_progs/modify_variable.txt"),
+        include_str!("../example_progs/random_inc.txt"),
+        include_str!("../example_progs/rainbow.txt"),
+    ];
+
+    #[test]
+    fn check_examples_verify() {
+        for source in EXAMPLES {
+            assert_eq!(Program::from_source(source).unwrap().verify(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn check_verify_errors() {
+        // PUSHI with a truncated operand
+        let p = Program::from_binary(vec![0x31, 0x01, 0x00]);
+        assert_eq!(
+            p.verify(),
+            Err(vec![VerifyError::Decode(DecodeError::Truncated(0))])
+        );
+
+        // JMP into the operand of PUSHB
+        let p = Program::from_binary(vec![0x11, 0x01, 0x40, 0x01, 0x00]);
+        assert_eq!(
+            p.verify(),
+            Err(vec![VerifyError::JumpIntoInstruction(2, 1)])
+        );
+
+        // POP 1 on an empty stack
+        let p = Program::from_binary(vec![0x01]);
+        assert_eq!(p.verify(), Err(vec![VerifyError::StackUnderflow(0, 1, 0)]));
+
+        // Loop body pushing a value each iteration
+        let p = Program::from_binary(vec![0x10, 0x40, 0x00, 0x00]);
+        assert_eq!(p.verify(), Err(vec![VerifyError::StackMismatch(0, 0, 1)]));
+    }
+
+    #[test]
+    fn check_clamp_is_stack_neutral() {
+        let p = Program::from_source(
+            "let a = 20; let b = 7; set_pixel(0, clamp(a, 0, 3), clamp(b, 9, 12), clamp(b, 0, 0), 0); blit;",
+        )
+        .unwrap();
+        assert_eq!(p.verify(), Ok(()));
+
+        let vm = VM::new(1, Default::default());
+        let mut state = vm.start(p, Default::default());
+        match state.run() {
+            Outcome::BLIT(mut frame) => {
+                let pixel = frame.next().unwrap();
+                assert_eq!((pixel.r, pixel.g, pixel.b), (3, 9, 0));
+            }
+            _ => panic!("expected a frame"),
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Time source of `get_wall_time`, `get_precise_time` and timers, set with `VMStateConfig::clock`
+pub trait Clock {
+    /// Time since the program started
+    fn elapsed(&self) -> Duration;
+
+    /// Time since `UNIX_EPOCH`, virtual clocks start at the epoch
+    fn wall_time(&self) -> Duration {
+        self.elapsed()
+    }
+
+    /// Called whenever a frame is emitted
+    fn on_frame(&mut self) {}
+}
+
+/// Real time, started when the clock is created
+#[derive(Clone, Debug)]
+pub struct SystemClock {
+    start: SystemTime,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            start: SystemTime::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn elapsed(&self) -> Duration {
+        self.start.elapsed().unwrap_or_default()
+    }
+
+    fn wall_time(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+/// Virtual time advancing by `step` on every frame, e.g. 33ms for 30 frames per second
+#[derive(Clone, Debug)]
+pub struct FixedStepClock {
+    step: Duration,
+    elapsed: Duration,
+}
+
+impl FixedStepClock {
+    pub fn new(step: Duration) -> Self {
+        Self {
+            step,
+            elapsed: Duration::ZERO,
+        }
+    }
+}
+
+impl Clock for FixedStepClock {
+    fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    fn on_frame(&mut self) {
+        self.elapsed += self.step;
+    }
+}
+
+/// Virtual time set by the host, clones share the time, so a handle kept by the host
+/// steps the clock owned by the running program
+#[derive(Clone, Debug, Default)]
+pub struct ManualClock {
+    nanos: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&self, elapsed: Duration) {
+        self.nanos
+            .store(elapsed.as_nanos() as u64, Ordering::Release);
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.nanos.fetch_add(by.as_nanos() as u64, Ordering::AcqRel);
+    }
+}
+
+impl Clock for ManualClock {
+    fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::Acquire))
+    }
+}
+
+/// Runs `clock` `factor` times faster, for slow motion or fast previews
+#[derive(Clone, Debug)]
+pub struct ScaledClock<C> {
+    clock: C,
+    factor: f64,
+}
+
+impl<C: Clock> ScaledClock<C> {
+    /// `None` unless `factor` is finite and not negative, `0.0` stops the time
+    pub fn new(clock: C, factor: f64) -> Option<Self> {
+        (factor.is_finite() && factor >= 0.0).then_some(Self { clock, factor })
+    }
+}
+
+impl<C: Clock> Clock for ScaledClock<C> {
+    /// Saturates at `Duration::MAX` instead of overflowing
+    fn elapsed(&self) -> Duration {
+        Duration::try_from_secs_f64(self.clock.elapsed().as_secs_f64() * self.factor)
+            .unwrap_or(Duration::MAX)
+    }
+
+    fn wall_time(&self) -> Duration {
+        self.clock
+            .wall_time()
+            .saturating_add(self.elapsed())
+            .saturating_sub(self.clock.elapsed())
+    }
+
+    fn on_frame(&mut self) {
+        self.clock.on_frame()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::FromSource;
+    use crate::program::Program;
+    use crate::vm::{Outcome, VMState, VMStateConfig, VM};
+
+    fn start(clock: impl Clock + 'static) -> VMState {
+        let source = "loop {\n    let t = get_precise_time;\n    set_pixel(0, (t / 256) % 256, t % 256, 0, 0);\n    after 600s {\n        set_pixel(1, 1, 0, 0, 0);\n    };\n    blit;\n}";
+        let config = VMStateConfig {
+            clock: Some(Box::new(clock)),
+            ..Default::default()
+        };
+        VM::new(2, Default::default()).start(Program::from_source(source).unwrap(), config)
+    }
+
+    fn frame(state: &mut VMState) -> (u32, u8) {
+        assert!(matches!(state.run(), Outcome::BLIT(_)));
+        let time = state.pixel(0).map(|c| c.r as u32 * 256 + c.g as u32);
+        (time.unwrap(), state.pixel(1).unwrap().r)
+    }
+
+    #[test]
+    fn check_clocks() {
+        let mut state = start(FixedStepClock::new(Duration::from_millis(33)));
+        let times: Vec<u32> = (0..4).map(|_| frame(&mut state).0).collect();
+        assert_eq!(times, vec![0, 33, 66, 99]);
+
+        let step = FixedStepClock::new(Duration::from_millis(10));
+        let mut state = start(ScaledClock::new(step.clone(), 2.5).unwrap());
+        let times: Vec<u32> = (0..3).map(|_| frame(&mut state).0).collect();
+        assert_eq!(times, vec![0, 25, 50]);
+        for factor in [-1.0, f64::NAN, f64::INFINITY] {
+            assert!(ScaledClock::new(step.clone(), factor).is_none());
+        }
+        let mut huge = ScaledClock::new(step, f64::MAX).unwrap();
+        huge.on_frame();
+        assert_eq!(huge.elapsed(), Duration::MAX);
+        assert_eq!(huge.wall_time(), Duration::MAX - Duration::from_millis(10));
+
+        // Fast-forward to minute 10
+        let clock = ManualClock::new();
+        let mut state = start(clock.clone());
+        assert_eq!(frame(&mut state), (0, 0));
+        clock.set(Duration::from_secs(600));
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(frame(&mut state), ((600_005 % 65536) as u32, 1));
+
+        let system = SystemClock::new();
+        assert!(system.wall_time() > Duration::from_secs(1_600_000_000));
+        assert!(system.elapsed() < Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::BTreeSet;
+
+use smart_leds_trait::White;
+use thiserror::Error;
+
+use super::errors::VMError;
+use super::{Frame, Outcome, VMState, RGBW8};
+use crate::instructions::{Instruction, UserCommand};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Breakpoint {
+    Pc(usize),
+    /// Start of every statement on a source line, needs a source map
+    Line(u32),
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum DebugError {
+    #[error("program has no debug info")]
+    NoDebugInfo,
+
+    #[error("no code on line {0}")]
+    NoCodeOnLine(u32),
+
+    #[error("pc {0} is not the start of an instruction")]
+    NotAnInstruction(usize),
+}
+
+/// Why the debugger handed control back
+pub enum Event {
+    Stepped,
+    Breakpoint(usize),
+    /// Instruction at `pc` wrote `color` to watched pixel `index`
+    PixelWrite {
+        pc: usize,
+        index: u32,
+        color: RGBW8,
+    },
+    Blit(Frame),
+    Ended,
+    Error(VMError),
+}
+
+/// Drives a `VMState` one instruction at a time, stopping at breakpoints and pixel writes
+pub struct Debugger {
+    state: VMState,
+    breakpoints: BTreeSet<usize>,
+    watched_pixels: BTreeSet<u32>,
+}
+
+impl Debugger {
+    pub fn new(state: VMState) -> Self {
+        Debugger {
+            state,
+            breakpoints: BTreeSet::new(),
+            watched_pixels: BTreeSet::new(),
+        }
+    }
+
+    pub fn state(&self) -> &VMState {
+        &self.state
+    }
+
+    pub fn into_state(self) -> VMState {
+        self.state
+    }
+
+    /// Adds a breakpoint, returning the pcs it resolved to
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> Result<Vec<usize>, DebugError> {
+        let pcs = self.resolve(breakpoint)?;
+        self.breakpoints.extend(pcs.iter());
+        Ok(pcs)
+    }
+
+    pub fn remove_breakpoint(&mut self, breakpoint: Breakpoint) -> Result<(), DebugError> {
+        for pc in self.resolve(breakpoint)? {
+            self.breakpoints.remove(&pc);
+        }
+        Ok(())
+    }
+
+    fn resolve(&self, breakpoint: Breakpoint) -> Result<Vec<usize>, DebugError> {
+        match breakpoint {
+            Breakpoint::Pc(pc) => {
+                if self
+                    .state
+                    .program()
+                    .instructions()
+                    .any(|i| matches!(i, Ok((p, _)) if p == pc))
+                {
+                    Ok(vec![pc])
+                } else {
+                    Err(DebugError::NotAnInstruction(pc))
+                }
+            }
+            Breakpoint::Line(line) => {
+                let map = self
+                    .state
+                    .program()
+                    .source_map()
+                    .ok_or(DebugError::NoDebugInfo)?;
+                let pcs: Vec<usize> = map.pcs_of_line(line).collect();
+                if pcs.is_empty() {
+                    Err(DebugError::NoCodeOnLine(line))
+                } else {
+                    Ok(pcs)
+                }
+            }
+        }
+    }
+
+    pub fn watch_pixel(&mut self, index: u32) {
+        self.watched_pixels.insert(index);
+    }
+
+    pub fn unwatch_pixel(&mut self, index: u32) {
+        self.watched_pixels.remove(&index);
+    }
+
+    pub fn stack(&self) -> &[u32] {
+        self.state.stack()
+    }
+
+    /// Named variables in scope at the current pc with their values, outer scopes first
+    pub fn variables(&self) -> Vec<(String, u32)> {
+        let stack = self.state.stack();
+        match self.state.program().source_map() {
+            Some(map) => map
+                .variables_at(self.state.pc())
+                .filter_map(|v| Some((v.name.clone(), *stack.get(v.slot)?)))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Executes a single instruction
+    pub fn step(&mut self) -> Event {
+        let write = self.pending_pixel_write();
+        let pc = self.state.pc();
+        match self.state.step() {
+            None => match write {
+                Some((index, color)) if self.watched_pixels.contains(&index) => {
+                    Event::PixelWrite { pc, index, color }
+                }
+                _ => Event::Stepped,
+            },
+            Some(Outcome::BLIT(frame)) => Event::Blit(frame),
+            Some(Outcome::Ended) => Event::Ended,
+            Some(Outcome::Error(e)) => Event::Error(e),
+        }
+    }
+
+    /// Runs until a breakpoint, a watched pixel write, the next frame, the end or an error
+    pub fn resume(&mut self) -> Event {
+        self.run(true)
+    }
+
+    /// Runs until the next frame, the end or an error, ignoring breakpoints and watchpoints
+    pub fn run_until_blit(&mut self) -> Event {
+        self.run(false)
+    }
+
+    fn run(&mut self, stop: bool) -> Event {
+        let mut first = true;
+        loop {
+            if stop && !first && self.breakpoints.contains(&self.state.pc()) {
+                return Event::Breakpoint(self.state.pc());
+            }
+            first = false;
+
+            match self.step() {
+                Event::Stepped => {}
+                Event::PixelWrite { .. } if !stop => {}
+                event => return event,
+            }
+        }
+    }
+
+    /// Pixel index and color the next instruction writes, when it is a `set_pixel`
+    fn pending_pixel_write(&self) -> Option<(u32, RGBW8)> {
+        let code = self.state.program().code();
+        match Instruction::decode(code, self.state.pc()) {
+            Ok((Instruction::User(UserCommand::SET_PIXEL), _)) => {
+                let stack = self.state.stack();
+                let [r, g, b, w] = stack.last()?.to_le_bytes();
+                let index = *stack.get(stack.len().checked_sub(2)?)?;
+                Some((index, RGBW8::new_alpha(r, g, b, White(w))))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::FromSource;
+    use crate::program::Program;
+    use crate::vm::VM;
+
+    #[test]
+    fn check_debugger() {
+        let source = "let a = 7;\nfor(i=3) {\n    set_pixel(i - 1, a, 0, 0, 0);\n};\nblit;";
+        let p = Program::from_source(source).unwrap();
+        let state = VM::new(3, Default::default()).start(p, Default::default());
+        let mut debugger = Debugger::new(state);
+
+        assert_eq!(
+            debugger.add_breakpoint(Breakpoint::Line(9)),
+            Err(DebugError::NoCodeOnLine(9))
+        );
+        debugger.add_breakpoint(Breakpoint::Line(3)).unwrap();
+        debugger.watch_pixel(0);
+
+        assert!(matches!(debugger.resume(), Event::Breakpoint(_)));
+        assert_eq!(
+            debugger.variables(),
+            vec![("a".to_string(), 7), ("i".to_string(), 3)]
+        );
+
+        debugger.remove_breakpoint(Breakpoint::Line(3)).unwrap();
+        match debugger.resume() {
+            Event::PixelWrite { index, color, .. } => {
+                assert_eq!((index, color.r), (0, 7));
+                assert_eq!(debugger.variables()[1], ("i".to_string(), 1));
+            }
+            _ => panic!("expected pixel write"),
+        }
+
+        assert!(matches!(debugger.resume(), Event::Blit(_)));
+        assert!(matches!(debugger.run_until_blit(), Event::Ended));
+        assert_eq!(debugger.stack(), &[] as &[u32]);
+    }
+}
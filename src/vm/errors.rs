@@ -1,3 +1,6 @@
+use crate::instructions::{Binary, DecodeError, Instruction, Unary};
+use crate::source_map::Span;
+use std::fmt;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -8,15 +11,121 @@ pub enum VMError {
     #[error("local Instruction limit reached: cur[{0}] > max[{1}]")]
     LocalInstructionLimitReached(usize, usize),
 
-    #[error("unknown instruction, postfix: {0}")]
-    UnknownInstruction(u8),
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
 
-    #[error("unimplemented instruction, postfix: {0}")]
+    #[error("unimplemented instruction {0:#04x}")]
     UnimplementedInstruction(u8),
 
     #[error("stack under flow")]
     StackUnderflow,
 
+    #[error("error during {0}, lhs: {1}, rhs: {2}, overflow/underflow/division by zero")]
+    ArithmeticError(Binary, u32, u32),
+
+    #[error("error during {0}, value: {1}, overflow/underflow")]
+    UnaryArithmeticError(Unary, u32),
+
+    #[error("pixel index {0} exceeds strip length {1}")]
+    PixelOutOfRange(u32, u32),
+
+    #[error("pixel ({0}, {1}) is outside the {2}x{3} matrix")]
+    CoordinateOutOfRange(u32, u32, u32, u32),
+
+    #[error("input channel {0} does not exist, there are {1}")]
+    InputOutOfRange(u32, usize),
+
+    #[error("spawn exceeds the limit of {0} tasks")]
+    TooManyTasks(usize),
+
+    #[error("return outside of an event handler")]
+    ReturnOutsideHandler,
+
+    #[error("random_int needs an upper bound above 0")]
+    EmptyRandomRange,
+
     #[error("run time error: {0}")]
     RuntimeError(String),
+
+    #[error("no host command with id {0}")]
+    UnknownHostCommand(u8),
+
+    #[error("host command {0} ({1}) is registered with a different stack effect")]
+    HostSignatureMismatch(u8, String),
+
+    #[error("host command {0} is {1} on this VM, the program was compiled against {2}")]
+    HostCommandMismatch(u8, String, String),
+
+    #[error("host command {0} failed: {1}")]
+    HostCommand(String, String),
+
+    #[error("writing the frame failed: {0}")]
+    Output(String),
+
+    #[error("in segment {0}: {1}")]
+    Segment(String, Box<VMError>),
+
+    /// Error together with the VM state at the failing instruction, `VMState::run` wraps every error
+    #[error("{error}, {context}")]
+    InContext {
+        context: Box<ErrorContext>,
+        error: Box<VMError>,
+    },
+}
+
+impl VMError {
+    /// The error itself, without context
+    pub fn root(&self) -> &VMError {
+        match self {
+            VMError::InContext { error, .. } | VMError::Segment(_, error) => error.root(),
+            e => e,
+        }
+    }
+
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            VMError::InContext { context, .. } => Some(context),
+            VMError::Segment(_, error) => error.context(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrorContext {
+    pub pc: usize,
+    /// Bytes of the failing instruction, just the opcode when it did not decode
+    pub instruction: Vec<u8>,
+    pub instruction_count: usize,
+    pub stack_depth: usize,
+    /// Topmost stack values, the last one is the top of the stack
+    pub stack_top: Vec<u32>,
+    /// Statement the instruction was compiled from, when the program has a source map
+    pub span: Option<Span>,
+}
+
+impl ErrorContext {
+    pub fn decoded(&self) -> Option<Instruction<'_>> {
+        Instruction::decode(&self.instruction, 0)
+            .ok()
+            .map(|(ins, _)| ins)
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at pc {}", self.pc)?;
+        match self.decoded() {
+            Some(ins) => write!(f, " ({})", ins.to_string().replace('\t', " "))?,
+            None => write!(f, " ({:02x?})", self.instruction)?,
+        }
+        if let Some(span) = self.span {
+            write!(f, ", {}", span)?;
+        }
+        write!(
+            f,
+            ", after {} instructions, stack depth {}, top {:?}",
+            self.instruction_count, self.stack_depth, self.stack_top
+        )
+    }
 }
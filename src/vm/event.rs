@@ -0,0 +1,102 @@
+use crate::instructions::EventKind;
+
+/// Event raised by the host through `VMState::raise_event`, runs the matching `on` handler
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// `on button(n)`
+    Button(u8),
+    /// `on param_changed(name)`
+    ParamChanged(String),
+}
+
+impl Event {
+    /// Kind and key the handler instruction stores for this event
+    pub fn key(&self) -> (EventKind, &[u8]) {
+        match self {
+            Event::Button(n) => (EventKind::BUTTON, std::slice::from_ref(n)),
+            Event::ParamChanged(name) => (EventKind::PARAM_CHANGED, name.as_bytes()),
+        }
+    }
+
+    /// Event handled by a handler with `kind` and `key`
+    pub fn from_key(kind: EventKind, key: &[u8]) -> Option<Event> {
+        match (kind, key) {
+            (EventKind::BUTTON, [n]) => Some(Event::Button(*n)),
+            (EventKind::PARAM_CHANGED, name) => std::str::from_utf8(name)
+                .ok()
+                .map(|name| Event::ParamChanged(name.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// Handler registered by executing its `on` instruction
+#[derive(Clone, Debug)]
+pub(crate) struct Handler {
+    pub event: Event,
+    pub entry: usize,
+}
+
+/// Main program state saved while a handler runs
+#[derive(Clone, Debug)]
+pub(crate) struct Interrupted {
+    pub pc: usize,
+    pub stack: Vec<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::FromAssembly;
+    use crate::budget::Bound;
+    use crate::compiler::FromSource;
+    use crate::decompiler::ToSource;
+    use crate::program::{Program, SyntaxError};
+    use crate::vm::errors::VMError;
+    use crate::vm::{Outcome, VMState, VM};
+
+    #[test]
+    fn check_event_handlers() {
+        let source = "let hue = 10;\non button(1) {\n    let a = 3;\n    set_pixel(1, a, 0, 0, 0);\n};\non param_changed(speed) {\n    set_pixel(2, 0, 9, 0, 0);\n};\nloop {\n    set_pixel(0, hue, 0, 0, 0);\n    blit;\n}";
+        let p = Program::from_source(source).unwrap();
+        assert_eq!(p.verify(), Ok(()));
+        let decompiled = p.to_source().unwrap();
+        assert!(decompiled.contains("on button(1) {\n"));
+        assert!(decompiled.contains("on param_changed(speed) {\n"));
+        assert_eq!(Program::from_source(&decompiled).unwrap().code(), p.code());
+
+        let listing = format!("{:?}", p);
+        assert!(listing.contains("SPECIAL\ton param_changed speed to "));
+        assert_eq!(Program::from_assembly(&listing).unwrap().code(), p.code());
+        let budget = p.frame_budget(3).unwrap();
+        assert!(budget.per_event > Bound::Finite(0) && budget.per_frame < Bound::Unbounded);
+
+        fn frame(state: &mut VMState) -> (u8, u8) {
+            assert!(matches!(state.run(), Outcome::BLIT(_)));
+            (state.pixel(1).unwrap().r, state.pixel(2).unwrap().g)
+        }
+        let mut state = VM::new(3, Default::default()).start(p, Default::default());
+        assert!(!state.raise_event(Event::Button(1)));
+        assert_eq!(frame(&mut state), (0, 0));
+        assert!(state.raise_event(Event::Button(1)));
+        assert!(!state.raise_event(Event::Button(2)));
+        assert!(state.raise_event(Event::ParamChanged("speed".to_string())));
+        assert_eq!(frame(&mut state), (3, 9));
+        assert_eq!(state.pixel(0).map(|c| c.r), Some(10));
+        assert_eq!(state.stack(), &[10]);
+
+        // Handlers run on their own stack and can't see the main program's variables
+        let e = Program::from_source("let hue = 1; on button(0) { set_pixel(0, hue, 0, 0, 0); }")
+            .unwrap_err();
+        assert!(matches!(e, SyntaxError::UndefinedVariable(name) if name == "hue"));
+
+        let p = Program::from_binary(vec![0xF4]);
+        match VM::new(1, Default::default())
+            .start(p, Default::default())
+            .run()
+        {
+            Outcome::Error(e) => assert!(matches!(e.root(), VMError::ReturnOutsideHandler)),
+            _ => panic!("expected error"),
+        }
+    }
+}
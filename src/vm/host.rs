@@ -0,0 +1,308 @@
+use std::cell::{RefCell, RefMut};
+use std::fmt;
+use std::rc::Rc;
+use thiserror::Error;
+
+use crate::program::Program;
+
+/// Called with the command's inputs, deepest first, returns the values to push
+pub type HostFunction = Box<dyn FnMut(&[u32]) -> Result<Vec<u32>, String>>;
+
+/// Names the compiler parses as builtins or keywords, host commands can neither use them nor start
+/// with the ones written without parentheses
+const RESERVED: &[&str] = &[
+    "random",
+    "get_pixel",
+    "input",
+    "input_changed",
+    "every",
+    "after",
+    "wait",
+    "frame_rate",
+    "xy",
+    "set_xy",
+    "get_xy",
+    "pos_x",
+    "pos_y",
+    "pos_z",
+    "angle",
+    "radius",
+    "set_pixel",
+    "rgb",
+    "clamp",
+    "red",
+    "green",
+    "blue",
+    "if",
+    "for",
+    "loop",
+    "let",
+    "else",
+];
+const RESERVED_PREFIXES: &[&str] = &[
+    "get_length",
+    "get_wall_time",
+    "get_precise_time",
+    "get_width",
+    "get_height",
+    "blit",
+    "dump",
+    "yield",
+];
+
+/// Most values a host command can take or return, both are packed into one byte
+pub const MAX_VALUES: u8 = 0x0F;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum HostError {
+    #[error("invalid host command name: {0}")]
+    InvalidName(String),
+
+    #[error("host command already registered: {0}")]
+    Duplicate(String),
+
+    #[error("host command {0} takes or returns more than {MAX_VALUES} values")]
+    TooManyValues(String),
+
+    #[error("no more host commands can be registered")]
+    Full,
+
+    #[error("the program calls host command {0} as {1}, which is registered differently")]
+    Mismatch(u8, String),
+}
+
+/// Name and stack effect of a host command
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Signature {
+    pub name: String,
+    pub inputs: u8,
+    pub outputs: u8,
+}
+
+/// Host commands callable from programs, a command's id is its registration order.
+/// Clones call the same functions, e.g. from the segments of a strip.
+#[derive(Clone, Default)]
+pub struct HostCommands {
+    signatures: Vec<Signature>,
+    functions: Vec<Rc<RefCell<HostFunction>>>,
+}
+
+impl HostCommands {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `function` under `name`, returning the id programs call it by
+    pub fn register(
+        &mut self,
+        name: &str,
+        inputs: u8,
+        outputs: u8,
+        function: impl FnMut(&[u32]) -> Result<Vec<u32>, String> + 'static,
+    ) -> Result<u8, HostError> {
+        if !valid_name(name) {
+            return Err(HostError::InvalidName(name.to_string()));
+        }
+        if self.lookup(name).is_some() {
+            return Err(HostError::Duplicate(name.to_string()));
+        }
+        if inputs > MAX_VALUES || outputs > MAX_VALUES {
+            return Err(HostError::TooManyValues(name.to_string()));
+        }
+        let id = u8::try_from(self.signatures.len()).map_err(|_| HostError::Full)?;
+
+        self.signatures.push(Signature {
+            name: name.to_string(),
+            inputs,
+            outputs,
+        });
+        self.functions
+            .push(Rc::new(RefCell::new(Box::new(function))));
+        Ok(id)
+    }
+
+    /// Id and signature of the command called `name`
+    pub fn lookup(&self, name: &str) -> Option<(u8, &Signature)> {
+        self.signatures
+            .iter()
+            .position(|s| s.name == name)
+            .map(|id| (id as u8, &self.signatures[id]))
+    }
+
+    pub fn signature(&self, id: u8) -> Option<&Signature> {
+        self.signatures.get(id as usize)
+    }
+
+    /// Signatures indexed by id
+    pub fn signatures(&self) -> &[Signature] {
+        &self.signatures
+    }
+
+    /// Checks that every host command `program` calls has the id, name and stack effect it was
+    /// compiled against, hosts registering their commands in another order would call the wrong ones
+    pub fn check(&self, program: &Program) -> Result<(), HostError> {
+        for (id, expected) in program.host_commands() {
+            if self.signature(*id) != Some(expected) {
+                return Err(HostError::Mismatch(*id, expected.name.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn function(&self, id: u8) -> Option<RefMut<'_, HostFunction>> {
+        self.functions.get(id as usize).map(|f| f.borrow_mut())
+    }
+}
+
+impl fmt::Debug for HostCommands {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.signatures.iter()).finish()
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        && !RESERVED.contains(&name)
+        && !RESERVED_PREFIXES.iter().any(|p| name.starts_with(p))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::FromAssembly;
+    use crate::compiler::FromSource;
+    use crate::container::IsaVersion;
+    use crate::decompiler::ToSource;
+    use crate::program::SyntaxError;
+    use crate::vm::errors::VMError;
+    use crate::vm::{Outcome, VM};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn check_host_commands() {
+        let relay = Rc::new(RefCell::new(Vec::<u32>::new()));
+        let mut vm = VM::new(2, Default::default());
+        vm.register_command("sensor", 0, 1, |_| Ok(vec![42]))
+            .unwrap();
+        let written = relay.clone();
+        vm.register_command("relay", 1, 0, move |args| {
+            written.borrow_mut().extend_from_slice(args);
+            Ok(vec![])
+        })
+        .unwrap();
+        vm.register_command("split", 1, 2, |args| Ok(vec![args[0] / 2, args[0] % 2]))
+            .unwrap();
+        vm.register_command("fail", 0, 0, |_| Err("unplugged".to_string()))
+            .unwrap();
+
+        assert!(matches!(
+            vm.register_command("blitz", 0, 0, |_| Ok(vec![])),
+            Err(HostError::InvalidName(_))
+        ));
+        assert!(matches!(
+            vm.register_command("random", 1, 1, |_| Ok(vec![])),
+            Err(HostError::InvalidName(_))
+        ));
+        assert_eq!(
+            vm.register_command("relay", 0, 0, |_| Ok(vec![])),
+            Err(HostError::Duplicate("relay".to_string()))
+        );
+        assert_eq!(
+            vm.register_command("wide", 16, 0, |_| Ok(vec![])),
+            Err(HostError::TooManyValues("wide".to_string()))
+        );
+
+        let source =
+            "let a = sensor();\nrelay(a + 1);\nsplit(a);\nset_pixel(0, a, 0, 0, 0);\nblit;";
+        assert!(matches!(
+            Program::from_source(source),
+            Err(SyntaxError::UnknownFunction(name)) if name == "sensor"
+        ));
+        assert!(matches!(
+            Program::from_source_with_commands("relay(1, 2);", vm.commands()),
+            Err(SyntaxError::ArgumentCount(name, 1, 2)) if name == "relay"
+        ));
+        assert!(matches!(
+            Program::from_source_with_commands("let x = relay(1);", vm.commands()),
+            Err(SyntaxError::NoValue(name)) if name == "relay"
+        ));
+
+        let p = Program::from_source_with_commands(source, vm.commands()).unwrap();
+        assert_eq!(p.verify(), Ok(()));
+        assert_eq!(p.required_isa(), IsaVersion { major: 1, minor: 1 });
+        let listing = format!("{:?}", p);
+        assert!(listing.contains("USER\thost 0 in 0 out 1"));
+        assert_eq!(Program::from_assembly(&listing).unwrap().code(), p.code());
+        assert!(p
+            .to_source()
+            .unwrap()
+            .contains("host_1(a + 1);\nhost_2(a);"));
+        let decompiled = p.to_source_with_commands(vm.commands()).unwrap();
+        assert!(decompiled.contains("let a = sensor();\nrelay(a + 1);\nsplit(a);"));
+        let recompiled = Program::from_source_with_commands(&decompiled, vm.commands()).unwrap();
+        assert_eq!(recompiled.code(), p.code());
+
+        let mut state = vm.start(p.clone(), Default::default());
+        assert!(matches!(state.run(), Outcome::BLIT(_)));
+        assert_eq!(*relay.borrow(), vec![43]);
+        assert_eq!(state.pixel(0).map(|c| c.r), Some(42));
+        assert!(matches!(state.run(), Outcome::Ended));
+
+        let (mut vm, _, _) = state.stop();
+        let p = Program::from_source_with_commands("fail();", vm.commands()).unwrap();
+        match vm.start(p.clone(), Default::default()).run() {
+            Outcome::Error(e) => assert!(
+                matches!(e.root(), VMError::HostCommand(name, message) if name == "fail" && message == "unplugged")
+            ),
+            _ => panic!("expected error"),
+        }
+        vm = VM::new(2, Default::default());
+        match vm.start(p, Default::default()).run() {
+            Outcome::Error(e) => assert!(matches!(e.root(), VMError::UnknownHostCommand(3))),
+            _ => panic!("expected error"),
+        }
+    }
+
+    #[test]
+    fn check_host_command_order() {
+        let mut vm = VM::new(1, Default::default());
+        vm.register_command("sensor", 0, 1, |_| Ok(vec![1]))
+            .unwrap();
+        vm.register_command("level", 0, 1, |_| Ok(vec![2])).unwrap();
+        let source = "set_pixel(0, level(), 0, 0, 0);\nblit;";
+        let p = Program::from_source_with_commands(source, vm.commands()).unwrap();
+        let level = Signature {
+            name: "level".to_string(),
+            inputs: 0,
+            outputs: 1,
+        };
+        assert_eq!(p.host_commands(), &[(1, level)]);
+        assert_eq!(vm.commands().check(&p), Ok(()));
+        let data = p.to_container(&Default::default()).unwrap();
+        let (loaded, _) = Program::from_container(&data).unwrap();
+        assert_eq!(loaded.host_commands(), p.host_commands());
+
+        // Another host registering the same commands in another order
+        let mut vm = VM::new(1, Default::default());
+        vm.register_command("level", 0, 1, |_| Ok(vec![2])).unwrap();
+        vm.register_command("sensor", 0, 1, |_| Ok(vec![1]))
+            .unwrap();
+        assert_eq!(
+            vm.commands().check(&loaded),
+            Err(HostError::Mismatch(1, "level".to_string()))
+        );
+        match vm.start(loaded, Default::default()).run() {
+            Outcome::Error(e) => assert!(matches!(
+                e.root(),
+                VMError::HostCommandMismatch(1, registered, compiled)
+                    if registered == "sensor" && compiled == "level"
+            )),
+            _ => panic!("expected error"),
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// Number of input channels programs can read
+pub const INPUT_CHANNELS: usize = 16;
+
+#[derive(Default)]
+struct Registers {
+    values: [AtomicU32; INPUT_CHANNELS],
+    /// Bumped on every write that changes the value
+    versions: [AtomicU32; INPUT_CHANNELS],
+}
+
+/// Input registers the host writes and programs read with `input(n)`, clones share the registers,
+/// so a handle can be moved to another thread and updated while a frame is rendered
+#[derive(Clone, Default)]
+pub struct Inputs {
+    registers: Arc<Registers>,
+}
+
+impl Inputs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets channel `n`, returns false when there is no such channel
+    pub fn set(&self, n: usize, value: u32) -> bool {
+        let Some(register) = self.registers.values.get(n) else {
+            return false;
+        };
+        if register.swap(value, Ordering::AcqRel) != value {
+            self.registers.versions[n].fetch_add(1, Ordering::AcqRel);
+        }
+        true
+    }
+
+    pub fn get(&self, n: usize) -> Option<u32> {
+        self.registers
+            .values
+            .get(n)
+            .map(|v| v.load(Ordering::Acquire))
+    }
+
+    pub(crate) fn version(&self, n: usize) -> Option<u32> {
+        self.registers
+            .versions
+            .get(n)
+            .map(|v| v.load(Ordering::Acquire))
+    }
+}
+
+impl std::fmt::Debug for Inputs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries((0..INPUT_CHANNELS).filter_map(|n| self.get(n)))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::FromSource;
+    use crate::program::Program;
+    use crate::vm::errors::VMError;
+    use crate::vm::{Outcome, VMState, VM};
+
+    #[test]
+    fn check_inputs() {
+        let source = "loop {\n    set_pixel(0, input(2), input_changed(2), 0, 0);\n    blit;\n}";
+        let p = Program::from_source(source).unwrap();
+        let mut state = VM::new(1, Default::default()).start(p, Default::default());
+        let inputs = state.inputs().clone();
+
+        fn frame(state: &mut VMState) -> (u8, u8) {
+            assert!(matches!(state.run(), Outcome::BLIT(_)));
+            state.pixel(0).map(|c| (c.r, c.g)).unwrap()
+        }
+        assert_eq!(frame(&mut state), (0, 0));
+        assert!(inputs.set(2, 7));
+        assert_eq!(frame(&mut state), (7, 1));
+        assert_eq!(frame(&mut state), (7, 0));
+        assert!(state.set_input(2, 7));
+        assert_eq!(frame(&mut state), (7, 0));
+        std::thread::spawn(move || inputs.set(2, 9)).join().unwrap();
+        assert_eq!(frame(&mut state), (9, 1));
+        assert!(!state.set_input(16, 1));
+
+        let p = Program::from_source("let a = input(16);").unwrap();
+        match VM::new(1, Default::default())
+            .start(p, Default::default())
+            .run()
+        {
+            Outcome::Error(e) => assert!(matches!(e.root(), VMError::InputOutOfRange(16, 16))),
+            _ => panic!("expected error"),
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use crate::instructions::Coordinate;
+use serde_json::Value;
+use std::f32::consts::TAU;
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum LayoutError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid layout JSON: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("led {0} is neither an array of [x, y, z] nor an object with x, y and z members")]
+    InvalidLed(usize),
+
+    #[error("the {1} coordinate of led {0} is not a number")]
+    InvalidCoordinate(usize, char),
+
+    #[error("invalid layout CSV at line {0}, expected x,y or x,y,z")]
+    Csv(usize),
+
+    #[error("led {0} has no {1} coordinate")]
+    MissingCoordinate(usize, char),
+
+    #[error("layout has no leds")]
+    Empty,
+
+    #[error("layout has {0} leds, the strip {1}")]
+    LengthMismatch(usize, u32),
+}
+
+/// Physical position of every led, installed with `VM::set_layout`
+#[derive(Clone, Debug)]
+pub struct Layout {
+    /// Indexed by `Coordinate`
+    values: Vec<[u8; 5]>,
+}
+
+impl Layout {
+    /// Layout of leds at the given x, y, z coordinates, in strip order
+    pub fn new(points: &[[f32; 3]]) -> Result<Layout, LayoutError> {
+        let bounds = Bounds::of(points).ok_or(LayoutError::Empty)?;
+        Ok(Layout {
+            values: points.iter().map(|p| bounds.normalise(*p)).collect(),
+        })
+    }
+
+    /// Reads a `.json` or otherwise CSV layout file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Layout, LayoutError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        if path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("json"))
+        {
+            Layout::from_json(&text)
+        } else {
+            Layout::from_csv(&text)
+        }
+    }
+
+    /// Parses lines of `x,y` or `x,y,z`, an optional header line and `#` comments are skipped
+    pub fn from_csv(text: &str) -> Result<Layout, LayoutError> {
+        let mut points = vec![];
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let values: Result<Vec<f32>, _> = line.split(',').map(|v| v.trim().parse()).collect();
+            match values.as_deref() {
+                Ok([x, y]) => points.push([*x, *y, 0.0]),
+                Ok([x, y, z]) => points.push([*x, *y, *z]),
+                Err(_) if points.is_empty() && n == 0 => {} // Header
+                _ => return Err(LayoutError::Csv(n + 1)),
+            }
+        }
+        Layout::new(&points)
+    }
+
+    /// Parses an array of `[x, y]`, `[x, y, z]` or objects with `x`, `y` and optional `z` members,
+    /// other members are ignored
+    pub fn from_json(text: &str) -> Result<Layout, LayoutError> {
+        let leds: Vec<Value> = serde_json::from_str(text)?;
+
+        let mut points = vec![];
+        for (n, led) in leds.iter().enumerate() {
+            let get = |axis: usize, name: char| {
+                let value = match led {
+                    Value::Array(values) => values.get(axis),
+                    Value::Object(members) => members.get(&name.to_string()),
+                    _ => return Err(LayoutError::InvalidLed(n)),
+                };
+                value
+                    .map(|v| {
+                        v.as_f64()
+                            .map(|v| v as f32)
+                            .ok_or(LayoutError::InvalidCoordinate(n, name))
+                    })
+                    .transpose()
+            };
+            let x = get(0, 'x')?.ok_or(LayoutError::MissingCoordinate(n, 'x'))?;
+            let y = get(1, 'y')?.ok_or(LayoutError::MissingCoordinate(n, 'y'))?;
+            points.push([x, y, get(2, 'z')?.unwrap_or(0.0)]);
+        }
+        Layout::new(&points)
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// `coordinate` of led `index`, normalised to 0..255
+    pub fn get(&self, index: u32, coordinate: Coordinate) -> Option<u8> {
+        self.values
+            .get(index as usize)
+            .map(|v| v[coordinate as usize])
+    }
+
+    /// Positions of leds `start..start + length`, in segment order
+    pub(crate) fn segment(&self, start: usize, length: usize, reverse: bool) -> Option<Layout> {
+        let mut values = self.values.get(start..start + length)?.to_vec();
+        if reverse {
+            values.reverse();
+        }
+        Some(Layout { values })
+    }
+
+    /// Leds of a strip of `length`, evenly spaced along x
+    pub(crate) fn line(index: u32, length: u32, coordinate: Coordinate) -> Option<u8> {
+        let end = length.checked_sub(1)? as f32;
+        let bounds = Bounds {
+            min: [0.0; 3],
+            max: [end, 0.0, 0.0],
+            radius: end / 2.0,
+        };
+        (index < length).then(|| bounds.normalise([index as f32, 0.0, 0.0])[coordinate as usize])
+    }
+}
+
+/// Range of the coordinates and the largest distance from the center in the x/y plane
+struct Bounds {
+    min: [f32; 3],
+    max: [f32; 3],
+    radius: f32,
+}
+
+impl Bounds {
+    fn of(points: &[[f32; 3]]) -> Option<Bounds> {
+        let first = *points.first()?;
+        let (mut min, mut max) = (first, first);
+        for p in points {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(p[axis]);
+                max[axis] = max[axis].max(p[axis]);
+            }
+        }
+        let mut bounds = Bounds {
+            min,
+            max,
+            radius: 0.0,
+        };
+        bounds.radius = points
+            .iter()
+            .map(|p| bounds.polar(*p).1)
+            .fold(0.0, f32::max);
+        Some(bounds)
+    }
+
+    /// Angle counterclockwise from the x axis and distance from the center in the x/y plane
+    fn polar(&self, p: [f32; 3]) -> (f32, f32) {
+        let x = p[0] - (self.min[0] + self.max[0]) / 2.0;
+        let y = p[1] - (self.min[1] + self.max[1]) / 2.0;
+        (y.atan2(x), x.hypot(y))
+    }
+
+    fn normalise(&self, p: [f32; 3]) -> [u8; 5] {
+        let scale = |value: f32, max: f32| {
+            if max > 0.0 {
+                (value / max * 255.0).round() as u8
+            } else {
+                0
+            }
+        };
+        let axis = |a: usize| scale(p[a] - self.min[a], self.max[a] - self.min[a]);
+        let (angle, radius) = self.polar(p);
+        // A full turn is 256, so the angle wraps around like a u8
+        let angle = (angle.rem_euclid(TAU) / TAU * 256.0).round() as u32 % 256;
+        [
+            axis(0),
+            axis(1),
+            axis(2),
+            angle as u8,
+            scale(radius, self.radius),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::FromAssembly;
+    use crate::compiler::FromSource;
+    use crate::decompiler::ToSource;
+    use crate::program::Program;
+    use crate::vm::{VMConfig, VM};
+
+    fn frame(vm: VM, p: &Program) -> Vec<(u8, u8, u8, u8)> {
+        let mut state = vm.start(p.clone(), Default::default());
+        let pixels = state.next_frame().unwrap().unwrap();
+        pixels.iter().map(|c| (c.r, c.g, c.b, c.a.0)).collect()
+    }
+
+    #[test]
+    fn check_layouts() {
+        let ring = Layout::from_csv("x,y\n1,0\n0,1\n-1,0\n# bottom\n0,-1\n").unwrap();
+        let angles: Vec<_> = (0..4).map(|i| ring.get(i, Coordinate::ANGLE)).collect();
+        assert_eq!(angles, vec![Some(0), Some(64), Some(128), Some(192)]);
+        assert_eq!(ring.get(1, Coordinate::X), Some(128));
+        assert_eq!(ring.get(3, Coordinate::RADIUS), Some(255));
+        assert!(matches!(
+            Layout::from_csv("1,0\n1;0"),
+            Err(LayoutError::Csv(2))
+        ));
+
+        let tree =
+            Layout::from_json(r#"[{"x": 0, "y": 0, "z": 0}, {"x": 0, "y": 0, "z": 2}, [2, 0, 1]]"#)
+                .unwrap();
+        let z: Vec<_> = (0..3).map(|i| tree.get(i, Coordinate::Z)).collect();
+        assert_eq!(z, vec![Some(0), Some(255), Some(128)]);
+        assert!(matches!(
+            Layout::from_json(r#"[{"x": 0, "z": 1}]"#),
+            Err(LayoutError::MissingCoordinate(0, 'y'))
+        ));
+        assert!(matches!(
+            Layout::from_json("[1, 2]"),
+            Err(LayoutError::InvalidLed(0))
+        ));
+        assert!(matches!(
+            Layout::from_json(r#"[[0, 0], [1, "a"]]"#),
+            Err(LayoutError::InvalidCoordinate(1, 'y'))
+        ));
+        let error = Layout::from_json("[[0, 0],\n [1, 0]").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid layout JSON: EOF while parsing a list at line 2 column 7"
+        );
+        // Members other than the coordinates are skipped
+        let labelled = Layout::from_json(
+            r#"[{"": 1, "id": "a\"b", "x": 0, "y": 0, "meta": {"x": 5}}, {"x": 2, "y": 0}]"#,
+        )
+        .unwrap();
+        assert_eq!(labelled.get(1, Coordinate::X), Some(255));
+
+        let source = "for(i = get_length) {\n    let n = i - 1;\n    set_pixel(n, pos_x(n), pos_y(n), angle(n), radius(n));\n};\nblit;";
+        let p = Program::from_source(source).unwrap();
+        assert_eq!(p.verify(), Ok(()));
+        let decompiled = p.to_source().unwrap();
+        assert!(decompiled.contains("pos_x(b), pos_y(b), angle(b), radius(b)"));
+        assert_eq!(Program::from_source(&decompiled).unwrap().code(), p.code());
+        let listing = format!("{:?}", p);
+        assert!(listing.contains("SPECIAL\tposition angle"));
+        assert_eq!(Program::from_assembly(&listing).unwrap().code(), p.code());
+
+        let mut vm = VM::new(4, VMConfig::default());
+        assert!(matches!(
+            VM::new(5, VMConfig::default()).set_layout(ring.clone()),
+            Err(LayoutError::LengthMismatch(4, 5))
+        ));
+        vm.set_layout(ring).unwrap();
+        assert_eq!(
+            frame(vm, &p),
+            vec![
+                (255, 128, 0, 255),
+                (128, 255, 64, 255),
+                (0, 128, 128, 255),
+                (128, 0, 192, 255)
+            ]
+        );
+        // Without a layout the strip is a line along x
+        assert_eq!(
+            frame(VM::new(3, VMConfig::default()), &p),
+            vec![(0, 0, 128, 255), (128, 0, 0, 0), (255, 0, 0, 255)]
+        );
+    }
+}
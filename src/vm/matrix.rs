@@ -0,0 +1,137 @@
+/// Rotation of the image on the panel, clockwise
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Rotation {
+    #[default]
+    None,
+    Cw90,
+    Cw180,
+    Cw270,
+}
+
+/// How the leds of a panel are wired and mounted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MatrixLayout {
+    /// Every other row runs backwards, otherwise all rows run left to right
+    pub serpentine: bool,
+    pub rotation: Rotation,
+    /// Mirrors the image left to right, before rotating it
+    pub flip_x: bool,
+    /// Mirrors the image top to bottom, before rotating it
+    pub flip_y: bool,
+}
+
+/// Panel set up by `VM::new_matrix`, programs address it by `x` left to right and `y` top to bottom
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Matrix {
+    pub width: u32,
+    pub height: u32,
+    pub layout: MatrixLayout,
+}
+
+impl Matrix {
+    /// Index in the strip of the led showing `x`, `y`
+    pub fn index(&self, x: u32, y: u32) -> Option<u32> {
+        let (width, height) = (self.width, self.height);
+        if x >= width || y >= height {
+            return None;
+        }
+        let x = if self.layout.flip_x { width - 1 - x } else { x };
+        let y = if self.layout.flip_y {
+            height - 1 - y
+        } else {
+            y
+        };
+        // Position on the panel, which is `height` wide when rotated by a quarter
+        let (column, row, columns) = match self.layout.rotation {
+            Rotation::None => (x, y, width),
+            Rotation::Cw90 => (height - 1 - y, x, height),
+            Rotation::Cw180 => (width - 1 - x, height - 1 - y, width),
+            Rotation::Cw270 => (y, width - 1 - x, height),
+        };
+        let column = if self.layout.serpentine && row % 2 == 1 {
+            columns - 1 - column
+        } else {
+            column
+        };
+        Some(row * columns + column)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::FromSource;
+    use crate::decompiler::ToSource;
+    use crate::program::Program;
+    use crate::vm::errors::VMError;
+    use crate::vm::{Outcome, VMConfig, VM};
+
+    fn indices(width: u32, height: u32, layout: MatrixLayout) -> Vec<u32> {
+        let matrix = Matrix {
+            width,
+            height,
+            layout,
+        };
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| matrix.index(x, y).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn check_matrix() {
+        let serpentine = MatrixLayout {
+            serpentine: true,
+            ..Default::default()
+        };
+        assert_eq!(indices(3, 2, Default::default()), vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(indices(3, 2, serpentine), vec![0, 1, 2, 5, 4, 3]);
+        let rotated = MatrixLayout {
+            rotation: Rotation::Cw90,
+            ..Default::default()
+        };
+        assert_eq!(indices(3, 2, rotated), vec![1, 3, 5, 0, 2, 4]);
+        let flipped = MatrixLayout {
+            flip_x: true,
+            rotation: Rotation::Cw180,
+            ..serpentine
+        };
+        assert_eq!(indices(3, 2, flipped), vec![5, 4, 3, 0, 1, 2]);
+
+        let source = "for(y = get_height) {\n    for(x = get_width) {\n        set_xy(x - 1, y - 1, x * 10 + y, 0, 0, 0);\n    };\n};\nset_pixel(0, get_xy(2, 1), 0, 0, 0);\nblit;";
+        let p = Program::from_source(source).unwrap();
+        let decompiled = p.to_source().unwrap();
+        assert!(decompiled.contains("set_xy(b - 1, a - 1, "));
+        assert!(decompiled.contains("get_xy(2, 1)"));
+        assert_eq!(Program::from_source(&decompiled).unwrap().code(), p.code());
+
+        let vm = VM::new_matrix(3, 2, serpentine, VMConfig::default());
+        let mut state = vm.start(p, Default::default());
+        let frame: Vec<u8> = state
+            .next_frame()
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(|c| c.r)
+            .collect();
+        // Serpentine wiring puts (2, 1) at index 3, which is copied to index 0
+        assert_eq!(frame, vec![32, 21, 31, 32, 22, 12]);
+
+        let p = Program::from_source("set_xy(3, 0, 1, 1, 1, 1);").unwrap();
+        let mut state = VM::new_matrix(3, 2, serpentine, VMConfig::default())
+            .start(p.clone(), Default::default());
+        match state.run() {
+            Outcome::Error(e) => {
+                assert!(matches!(
+                    e.root(),
+                    VMError::CoordinateOutOfRange(3, 0, 3, 2)
+                ))
+            }
+            _ => panic!("expected error"),
+        }
+        // A strip is a matrix one row high
+        let mut state = VM::new(4, VMConfig::default()).start(p, Default::default());
+        assert!(matches!(state.run(), Outcome::Ended));
+        assert_eq!(state.pixel(3).map(|c| c.r), Some(1));
+    }
+}
@@ -1,18 +1,50 @@
+pub mod clock;
+pub mod debug;
 pub mod errors;
-pub(crate) mod strip;
+pub mod event;
+pub mod host;
+pub mod input;
+pub mod layout;
+pub mod matrix;
+pub mod pacing;
+pub mod profile;
+pub mod segment;
+pub mod simulate;
+pub mod strip;
+pub mod task;
+pub(crate) mod timer;
+pub mod trace;
 
-use super::instructions::{Binary, Prefix, Special, Unary, UserCommand};
+use super::instructions::{Coordinate, Instruction, Prefix, Special, UserCommand};
+use crate::cost::CostModel;
 use crate::program::Program;
+use clock::Clock;
 use derivative::Derivative;
-use errors::VMError;
+use errors::{ErrorContext, VMError};
+use event::{Event, Handler, Interrupted};
+use host::{HostCommands, HostError};
+use input::{Inputs, INPUT_CHANNELS};
+use layout::{Layout, LayoutError};
+use matrix::{Matrix, MatrixLayout};
+use profile::Profile;
 use rand::{Rng, RngCore, SeedableRng};
 use rand_chacha::ChaCha8Rng;
+use segment::Segment;
 use smart_leds_trait::{White, RGBW};
-use std::time::{SystemTime, UNIX_EPOCH};
-use strip::DummyLedStrip;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use strip::{DummyLedStrip, LedStrip};
+use task::{Task, MAIN_TASK};
+use timer::Timers;
+use trace::{StdoutSink, TraceEvent, TraceSink};
 
 pub type RGBW8 = RGBW<u8>;
 
+/// Number of topmost stack values kept in `ErrorContext`
+pub const STACK_SNAPSHOT_LEN: usize = 8;
+
 #[derive(Derivative)]
 #[derivative(Default)]
 pub struct VMStateConfig {
@@ -20,6 +52,15 @@ pub struct VMStateConfig {
     pub local_instruction_limit: Option<usize>,
     #[derivative(Default(value = "Box::new(ChaCha8Rng::seed_from_u64(0))"))]
     pub rng: Box<dyn RngCore>,
+    /// Count executed instructions, see `VMState::profile`
+    pub profile: bool,
+    /// Charges cycles per instruction, see `VMState::cycles`
+    pub cost_model: Option<Box<dyn CostModel>>,
+    /// Time source, the system clock when not set, or the instruction count with `VMConfig::deterministic`
+    pub clock: Option<Box<dyn Clock>>,
+    /// Most tasks running at once, the main program included, `None` for no limit
+    #[derivative(Default(value = "Some(task::DEFAULT_TASK_LIMIT)"))]
+    pub task_limit: Option<usize>,
 }
 
 pub struct VMState {
@@ -30,11 +71,42 @@ pub struct VMState {
     start_time: SystemTime,
     instruction_count: usize,
     config: VMStateConfig,
+    profile: Option<Profile>,
+    cycles: u64,
+    inputs: Inputs,
+    /// Input versions last seen by `input_changed`
+    seen_inputs: [u32; INPUT_CHANNELS],
+    handlers: Vec<Handler>,
+    events: VecDeque<Event>,
+    /// Set while an event handler runs
+    interrupted: Option<Interrupted>,
+    /// Id of the running task
+    task: usize,
+    /// Tasks waiting for their turn, in scheduling order
+    tasks: VecDeque<Task>,
+    next_task: usize,
+    /// Tasks, the running one included, still to reach `blit` or `yield` before the frame is emitted
+    turns_left: usize,
+    timers: Timers,
+    /// Set by `frame_rate`
+    frame_period: Option<Duration>,
+    /// Set by `wait` for the next frame
+    wait: Option<Duration>,
+    frame_delay: Option<Duration>,
 }
 
 pub struct VM {
-    strip: DummyLedStrip,
+    strip: Box<dyn LedStrip>,
+    /// Set by `new_matrix`, a plain strip is one row high
+    matrix: Option<Matrix>,
+    /// Set by `set_layout`, otherwise the leds are on a line
+    layout: Option<Layout>,
+    /// Set by `add_segment`
+    segments: Vec<Segment>,
     pub config: VMConfig,
+    /// Shared with the segments' VMs
+    trace_sink: Rc<RefCell<Box<dyn TraceSink>>>,
+    commands: HostCommands,
 }
 
 #[derive(Default)]
@@ -46,7 +118,23 @@ pub struct VMConfig {
 pub enum Outcome {
     Ended,
     Error(VMError),
-    BLIT(Box<dyn Iterator<Item = RGBW<u8, u8>> + Send>),
+    BLIT(Frame),
+}
+
+/// Pixels of an emitted frame
+pub struct Frame {
+    pixels: Box<dyn Iterator<Item = RGBW8> + Send>,
+    /// How long to show the frame, set by `wait(ms)` for one frame or by `frame_rate(n)`,
+    /// `None` leaves pacing to the host
+    pub delay: Option<Duration>,
+}
+
+impl Iterator for Frame {
+    type Item = RGBW8;
+
+    fn next(&mut self) -> Option<RGBW8> {
+        self.pixels.next()
+    }
 }
 
 impl VMState {
@@ -56,6 +144,7 @@ impl VMState {
         } else {
             SystemTime::now()
         };
+        let profile = config.profile.then(|| Profile::new(program.code.len()));
         VMState {
             vm,
             program,
@@ -64,89 +153,278 @@ impl VMState {
             start_time,
             config,
             instruction_count: 0,
+            profile,
+            cycles: 0,
+            inputs: Inputs::new(),
+            seen_inputs: [0; INPUT_CHANNELS],
+            handlers: vec![],
+            events: VecDeque::new(),
+            interrupted: None,
+            task: MAIN_TASK,
+            tasks: VecDeque::new(),
+            next_task: MAIN_TASK + 1,
+            turns_left: 1,
+            timers: Timers::default(),
+            frame_period: None,
+            wait: None,
+            frame_delay: None,
         }
     }
     pub fn pc(&self) -> usize {
         self.pc
     }
 
-    fn pushi(&mut self, postfix: u8) {
-        for _ in 0..postfix {
-            let value = u32::from(self.program.code[self.pc + 1])
-                | u32::from(self.program.code[self.pc + 2]) << 8
-                | u32::from(self.program.code[self.pc + 3]) << 16
-                | u32::from(self.program.code[self.pc + 4]) << 24;
-            self.stack.push(value);
+    /// Values on the stack, the last one is the top
+    pub fn stack(&self) -> &[u32] {
+        &self.stack
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    pub fn instruction_count(&self) -> usize {
+        self.instruction_count
+    }
+
+    /// Cycles charged by `VMStateConfig::cost_model` so far, the instruction count without one
+    pub fn cycles(&self) -> u64 {
+        match self.config.cost_model {
+            Some(_) => self.cycles,
+            None => self.instruction_count as u64,
+        }
+    }
+
+    /// Execution counts so far, when `VMStateConfig::profile` is set
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Handle to the input registers, clone it to update them from elsewhere
+    pub fn inputs(&self) -> &Inputs {
+        &self.inputs
+    }
+
+    /// Sets input channel `n`, returns false when there is no such channel
+    pub fn set_input(&mut self, n: usize, value: u32) -> bool {
+        self.inputs.set(n, value)
+    }
+
+    /// Queues `event`, its handler runs at the next instruction boundary on its own stack and the
+    /// program resumes afterwards. Returns false, dropping the event, when no handler is registered yet.
+    pub fn raise_event(&mut self, event: Event) -> bool {
+        if !self.handlers.iter().any(|h| h.event == event) {
+            return false;
+        }
+        self.events.push_back(event);
+        true
+    }
+
+    /// Id of the running task, `task::MAIN_TASK` until the main program ends
+    pub fn task(&self) -> usize {
+        self.task
+    }
+
+    /// Tasks still running, the main program and the running task included
+    pub fn task_count(&self) -> usize {
+        self.tasks.len() + 1
+    }
 
-            if self.vm.config.trace {
-                print!("\tv={}", value);
+    /// Ends the turn of the running task at `blit` or `yield`, emitting the frame once every task had its turn.
+    /// Handlers don't take part in scheduling, a `blit` in one emits the frame right away.
+    fn schedule(&mut self) -> Option<Outcome> {
+        if self.interrupted.is_none() {
+            if let Some(next) = self.tasks.pop_front() {
+                let current = Task {
+                    id: self.task,
+                    pc: self.pc,
+                    stack: std::mem::take(&mut self.stack),
+                };
+                self.tasks.push_back(current);
+                self.resume(next);
+            }
+            if !self.end_turn() {
+                return None;
             }
-            self.pc += 4;
         }
+        Some(self.frame())
     }
 
-    fn pushb(&mut self, postfix: u8) {
-        if postfix == 0 {
-            self.stack.push(0);
-        } else {
-            for _ in 0..postfix {
-                self.pc += 1;
-                if self.vm.config.trace {
-                    print!("\tv={}", self.program.code[self.pc]);
-                }
-                self.stack.push(u32::from(self.program.code[self.pc]));
+    /// Ends the running task, returning the outcome when it ended the program or completed a frame
+    fn end_task(&mut self) -> Option<Outcome> {
+        self.timers.end_task(self.task);
+        let Some(next) = self.tasks.pop_front() else {
+            self.pc = self.program.code.len();
+            return Some(Outcome::Ended);
+        };
+        self.resume(next);
+        self.end_turn().then(|| self.frame())
+    }
+
+    /// Counts a finished turn, returns true and starts the next round when it was the last one
+    fn end_turn(&mut self) -> bool {
+        self.turns_left -= 1;
+        if self.turns_left > 0 {
+            return false;
+        }
+        self.turns_left = self.task_count();
+        true
+    }
+
+    fn resume(&mut self, task: Task) {
+        self.task = task.id;
+        self.pc = task.pc;
+        self.stack = task.stack;
+    }
+
+    fn frame(&mut self) -> Outcome {
+        self.vm.trace(|| TraceEvent::Blit);
+        if let Some(profile) = &mut self.profile {
+            profile.end_frame();
+        }
+        if let Some(clock) = &mut self.config.clock {
+            clock.on_frame();
+        }
+        if let Err(e) = self.vm.strip.present() {
+            return Outcome::Error(VMError::Output(e));
+        }
+        self.frame_delay = self.wait.take().or(self.frame_period);
+        // Pixels are copied in by `finish`, `next_frame` lends them instead
+        Outcome::BLIT(Frame {
+            pixels: Box::new(std::iter::empty()),
+            delay: self.frame_delay,
+        })
+    }
+
+    /// Whether an event handler is running
+    pub fn in_handler(&self) -> bool {
+        self.interrupted.is_some()
+    }
+
+    /// Enters the handler of the oldest queued event, unless one is running already
+    fn dispatch_event(&mut self) {
+        if self.interrupted.is_some() {
+            return;
+        }
+        let Some(event) = self.events.pop_front() else {
+            return;
+        };
+        if let Some(handler) = self.handlers.iter().find(|h| h.event == event) {
+            self.interrupted = Some(Interrupted {
+                pc: self.pc,
+                stack: std::mem::take(&mut self.stack),
+            });
+            self.pc = handler.entry;
+        }
+    }
+
+    /// Color last written to pixel `idx`
+    pub fn pixel(&self, idx: u32) -> Option<RGBW8> {
+        self.vm.strip.get_pixel(idx)
+    }
+
+    /// Delay requested for the last frame, see `Frame::delay`
+    pub fn frame_delay(&self) -> Option<Duration> {
+        self.frame_delay
+    }
+
+    fn host(&mut self, id: u8, inputs: u8, outputs: u8) -> Result<(), VMError> {
+        let signature = self
+            .vm
+            .commands
+            .signature(id)
+            .ok_or(VMError::UnknownHostCommand(id))?;
+        if (signature.inputs, signature.outputs) != (inputs, outputs) {
+            return Err(VMError::HostSignatureMismatch(id, signature.name.clone()));
+        }
+        // Programs not checked with `HostCommands::check` still never call the wrong command
+        if let Some((_, expected)) = self.program.host_commands.iter().find(|(i, _)| *i == id) {
+            if expected != signature {
+                return Err(VMError::HostCommandMismatch(
+                    id,
+                    signature.name.clone(),
+                    expected.name.clone(),
+                ));
             }
         }
+        let name = signature.name.clone();
+
+        let args_start = self
+            .stack
+            .len()
+            .checked_sub(inputs as usize)
+            .ok_or(VMError::StackUnderflow)?;
+        let args = self.stack.split_off(args_start);
+        let mut function = self
+            .vm
+            .commands
+            .function(id)
+            .expect("registered with signature");
+        let results = (*function)(&args).map_err(|e| VMError::HostCommand(name.clone(), e))?;
+        if results.len() != outputs as usize {
+            return Err(VMError::HostCommand(
+                name,
+                format!("returned {} values instead of {}", results.len(), outputs),
+            ));
+        }
+        self.stack.extend(results);
+        Ok(())
+    }
+
+    /// Milliseconds since the program started, one per executed instruction when deterministic without a clock
+    fn precise_time(&self) -> u32 {
+        let time = match &self.config.clock {
+            Some(clock) => clock.elapsed(),
+            None if self.vm.config.deterministic => {
+                Duration::from_millis(self.instruction_count as u64)
+            }
+            None => SystemTime::now()
+                .duration_since(self.start_time)
+                .unwrap_or_default(),
+        };
+        (time.as_millis() & u32::MAX as u128) as u32 // Wrap around when we exceed u32::MAX
     }
 
-    fn user(&mut self, postfix: u8) -> Option<Outcome> {
-        let user = UserCommand::from(postfix);
+    /// Seconds since `UNIX_EPOCH`, one per ten executed instructions when deterministic without a clock
+    fn wall_time(&self) -> u32 {
+        let time = match &self.config.clock {
+            Some(clock) => clock.wall_time().as_secs(),
+            None if self.vm.config.deterministic => (self.instruction_count / 10) as u64,
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+        (time & u32::MAX as u64) as u32 // Wrap around when we exceed u32::MAX
+    }
 
+    fn user(&mut self, user: UserCommand) -> Option<Outcome> {
         match user {
-            None => Some(Outcome::Error(VMError::UnknownInstruction(postfix))),
-            Some(UserCommand::GET_LENGTH) => {
+            UserCommand::GET_LENGTH => {
                 self.stack.push(self.vm.strip.length());
                 None
             }
-            Some(UserCommand::GET_WALL_TIME) => {
-                if self.vm.config.deterministic {
-                    self.stack.push((self.instruction_count / 10) as u32);
-                } else {
-                    let time = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs();
-                    self.stack.push((time & u32::MAX as u64) as u32); // Wrap around when we exceed u32::MAX
-                }
+            UserCommand::GET_WALL_TIME => {
+                self.stack.push(self.wall_time());
                 None
             }
-            Some(UserCommand::GET_PRECISE_TIME) => {
-                if self.vm.config.deterministic {
-                    self.stack.push(self.instruction_count as u32);
-                } else {
-                    let time = SystemTime::now()
-                        .duration_since(self.start_time)
-                        .unwrap()
-                        .as_millis();
-                    self.stack.push((time & u32::MAX as u128) as u32); // Wrap around when we exceed u32::MAX
-                }
+            UserCommand::GET_PRECISE_TIME => {
+                self.stack.push(self.precise_time());
                 None
             }
-            Some(UserCommand::SET_PIXEL) => {
+            UserCommand::SET_PIXEL => {
                 if let (Some(v), Some(idx)) = (self.stack.pop(), self.stack.last()) {
                     let [r, g, b, w] = v.to_le_bytes();
                     let color = RGBW8::new_alpha(r, g, b, White(w));
 
-                    if self.vm.config.trace {
-                        print!("\tset_pixel {} idx={} color={:?}", v, idx, color);
-                    }
+                    let index = *idx;
+                    self.vm.trace(|| TraceEvent::SetPixel { index, color });
 
                     if *idx >= self.vm.strip.length() {
-                        return Some(Outcome::Error(VMError::RuntimeError(format!(
-                            "index {} exceeds strip length {}",
+                        return Some(Outcome::Error(VMError::PixelOutOfRange(
                             *idx,
-                            self.vm.strip.length()
-                        ))));
+                            self.vm.strip.length(),
+                        )));
                     }
 
                     self.vm.strip.set_pixel(*idx, color);
@@ -155,25 +433,75 @@ impl VMState {
                     Some(Outcome::Error(VMError::StackUnderflow))
                 }
             }
-            Some(UserCommand::BLIT) => {
-                if self.vm.config.trace {
-                    print!("\tblit");
-                }
-                self.vm.strip.blit();
+            UserCommand::BLIT => {
                 self.pc += 1;
-                Some(Outcome::BLIT(self.vm.strip.export()))
+                self.schedule()
             }
-            Some(UserCommand::RANDOM_INT) => {
+            UserCommand::WAIT => {
+                let Some(&ms) = self.stack.last() else {
+                    return Some(Outcome::Error(VMError::StackUnderflow));
+                };
+                self.wait = Some(Duration::from_millis(ms as u64));
+                self.pc += 1;
+                self.schedule()
+            }
+            UserCommand::FRAME_RATE => {
+                let Some(&fps) = self.stack.last() else {
+                    return Some(Outcome::Error(VMError::StackUnderflow));
+                };
+                // 0 leaves pacing to the host again
+                self.frame_period = Duration::from_secs(1).checked_div(fps);
+                None
+            }
+            UserCommand::XY => {
+                let (Some(y), Some(x)) = (self.stack.pop(), self.stack.pop()) else {
+                    return Some(Outcome::Error(VMError::StackUnderflow));
+                };
+                let Some(index) = self.vm.index(x, y) else {
+                    let (width, height) = self.vm.dimensions();
+                    return Some(Outcome::Error(VMError::CoordinateOutOfRange(
+                        x, y, width, height,
+                    )));
+                };
+                self.stack.push(index);
+                None
+            }
+            UserCommand::SIZE => {
+                let Some(n) = self.stack.pop() else {
+                    return Some(Outcome::Error(VMError::StackUnderflow));
+                };
+                let (width, height) = self.vm.dimensions();
+                match n {
+                    0 => self.stack.push(width),
+                    1 => self.stack.push(height),
+                    _ => {
+                        return Some(Outcome::Error(VMError::RuntimeError(format!(
+                            "no dimension {}",
+                            n
+                        ))))
+                    }
+                }
+                None
+            }
+            UserCommand::RANDOM_INT => {
                 if let Some(v) = self.stack.pop() {
+                    if v == 0 {
+                        return Some(Outcome::Error(VMError::EmptyRandomRange));
+                    }
                     self.stack.push(self.config.rng.gen_range(0..v));
                     None
                 } else {
                     Some(Outcome::Error(VMError::StackUnderflow))
                 }
             }
-            Some(UserCommand::GET_PIXEL) => {
+            UserCommand::GET_PIXEL => {
                 if let Some(v) = self.stack.pop() {
-                    let color = self.vm.strip.get_pixel(v);
+                    let Some(color) = self.vm.strip.get_pixel(v) else {
+                        return Some(Outcome::Error(VMError::PixelOutOfRange(
+                            v,
+                            self.vm.strip.length(),
+                        )));
+                    };
                     let color_value = u32::from_le_bytes([color.r, color.g, color.b, 0]);
                     self.stack.push(color_value);
                     None
@@ -181,28 +509,144 @@ impl VMState {
                     Some(Outcome::Error(VMError::StackUnderflow))
                 }
             }
+            UserCommand::EVERY | UserCommand::AFTER => {
+                let Some(duration) = self.stack.pop() else {
+                    return Some(Outcome::Error(VMError::StackUnderflow));
+                };
+                let now = self.precise_time();
+                let due = match user {
+                    UserCommand::EVERY => self.timers.every(self.task, self.pc, now, duration),
+                    _ => self.timers.after(self.task, self.pc, now, duration),
+                };
+                self.stack.push(u32::from(due));
+                None
+            }
+            UserCommand::GET_INPUT | UserCommand::INPUT_CHANGED => {
+                let Some(n) = self.stack.pop() else {
+                    return Some(Outcome::Error(VMError::StackUnderflow));
+                };
+                let channel = n as usize;
+                let (Some(value), Some(version)) =
+                    (self.inputs.get(channel), self.inputs.version(channel))
+                else {
+                    return Some(Outcome::Error(VMError::InputOutOfRange(n, INPUT_CHANNELS)));
+                };
+                if user == UserCommand::GET_INPUT {
+                    self.stack.push(value);
+                } else {
+                    let changed = self.seen_inputs[channel] != version;
+                    self.seen_inputs[channel] = version;
+                    self.stack.push(u32::from(changed));
+                }
+                None
+            }
         }
     }
 
-    fn special(&mut self, postfix: u8) -> Option<Outcome> {
-        let special = Special::from(postfix);
-
+    fn special(&mut self, special: Special) -> Option<Outcome> {
         match special {
-            None => Some(Outcome::Error(VMError::UnknownInstruction(postfix))),
-            Some(Special::DUMP) => {
-                // DUMP
-                println!("DUMP: {:?}", self.stack);
+            Special::DUMP => {
+                self.vm
+                    .trace_sink
+                    .borrow_mut()
+                    .event(TraceEvent::Dump(self.stack.clone()));
                 None
             }
-            Some(Special::TWOBYTE) => {
-                Some(Outcome::Error(VMError::UnimplementedInstruction(postfix)))
+            Special::TWOBYTE => Some(Outcome::Error(VMError::UnimplementedInstruction(
+                Prefix::SPECIAL as u8 | special as u8,
+            ))),
+            Special::RETURN => match self.interrupted.take() {
+                Some(interrupted) => {
+                    self.pc = interrupted.pc;
+                    self.stack = interrupted.stack;
+                    None
+                }
+                None if self.task != MAIN_TASK => self.end_task(),
+                None => Some(Outcome::Error(VMError::ReturnOutsideHandler)),
+            },
+            Special::YIELD => {
+                self.pc += 1;
+                self.schedule()
             }
         }
     }
 
     pub fn run(&mut self) -> Outcome {
+        let outcome = self.execute();
+        self.finish(outcome)
+    }
+
+    /// Runs until the next frame like `run`, but lends its pixels instead of copying them,
+    /// `None` once the program ended. The delay it requests is `frame_delay`.
+    pub fn next_frame(&mut self) -> Result<Option<&[RGBW8]>, VMError> {
+        match self.execute() {
+            Outcome::BLIT(_) => Ok(Some(self.vm.strip.pixels())),
+            Outcome::Ended => Ok(None),
+            Outcome::Error(error) => Err(self.in_context(error)),
+        }
+    }
+
+    /// Executes a single instruction, `None` means the program keeps running.
+    /// Instruction limits are not enforced, see `vm::debug::Debugger`.
+    pub fn step(&mut self) -> Option<Outcome> {
+        if self.pc >= self.program.code.len() {
+            return self.end_task().map(|outcome| self.finish(outcome));
+        }
+        self.execute_one().map(|outcome| self.finish(outcome))
+    }
+
+    /// Copies the pixels into emitted frames and adds context to errors
+    fn finish(&self, outcome: Outcome) -> Outcome {
+        match outcome {
+            Outcome::BLIT(frame) => Outcome::BLIT(Frame {
+                pixels: Box::new(self.vm.strip.pixels().to_vec().into_iter()),
+                ..frame
+            }),
+            Outcome::Error(error) => Outcome::Error(self.in_context(error)),
+            Outcome::Ended => Outcome::Ended,
+        }
+    }
+
+    fn in_context(&self, error: VMError) -> VMError {
+        VMError::InContext {
+            context: Box::new(self.error_context()),
+            error: Box::new(error),
+        }
+    }
+
+    /// Snapshot of the state at the current pc, for errors raised there
+    fn error_context(&self) -> ErrorContext {
+        let code = &self.program.code;
+        let end = match Instruction::decode(code, self.pc) {
+            Ok((_, len)) => self.pc + len,
+            Err(_) => (self.pc + 1).min(code.len()),
+        };
+        let pc = self.pc.min(code.len());
+        ErrorContext {
+            pc: self.pc,
+            instruction: code[pc..end.max(pc)].to_vec(),
+            instruction_count: self.instruction_count,
+            stack_depth: self.stack.len(),
+            stack_top: self.stack[self.stack.len().saturating_sub(STACK_SNAPSHOT_LEN)..].to_vec(),
+            span: self.program.source_map().and_then(|m| m.lookup(self.pc)),
+        }
+    }
+
+    fn execute(&mut self) -> Outcome {
         let mut local_instruction_count = 0;
-        while self.pc < self.program.code.len() {
+        loop {
+            if self.pc >= self.program.code.len() {
+                // The running task ran off the end of the code
+                if self.tasks.is_empty() {
+                    break;
+                }
+                match self.end_task() {
+                    Some(Outcome::Ended) => break,
+                    Some(outcome) => return outcome,
+                    None => continue,
+                }
+            }
+
             // Enforce global instruction count limit
             if let Some(limit) = self.config.global_instruction_limit {
                 if self.instruction_count >= limit {
@@ -223,138 +667,174 @@ impl VMState {
                 }
             }
 
-            let ins = Prefix::from(self.program.code[self.pc]);
-            if let Some(i) = ins {
-                self.instruction_count += 1;
-                local_instruction_count += 1;
-                let postfix = self.program.code[self.pc] & 0x0F;
+            local_instruction_count += 1;
+            match self.execute_one() {
+                Some(Outcome::Ended) => break,
+                Some(outcome) => return outcome,
+                None => {}
+            }
+        }
 
-                if self.vm.config.trace {
-                    print!("{:04}.\t{:02x}\t{}", self.pc, self.program.code[self.pc], i);
+        let instruction_count = self.instruction_count;
+        self.vm.trace(|| TraceEvent::Ended { instruction_count });
+
+        Outcome::Ended
+    }
+
+    /// Executes the instruction at `pc`, returning the outcome when it ends the run
+    fn execute_one(&mut self) -> Option<Outcome> {
+        if !self.events.is_empty() {
+            self.dispatch_event();
+        }
+        let (ins, len) = match Instruction::decode(&self.program.code, self.pc) {
+            Ok(decoded) => decoded,
+            Err(e) => return Some(Outcome::Error(e.into())),
+        };
+        self.instruction_count += 1;
+        if let Some(profile) = &mut self.profile {
+            profile.record(self.pc, ins.prefix());
+        }
+        if let Some(model) = &self.config.cost_model {
+            self.cycles += model.cycles(&ins, self.vm.strip.length() as usize);
+        }
+
+        let (pc, bytes) = (self.pc, &self.program.code[self.pc..self.pc + len]);
+        self.vm.trace(|| TraceEvent::Instruction {
+            pc,
+            bytes: bytes.to_vec(),
+        });
+
+        match ins {
+            Instruction::PushB(_) | Instruction::PushI(_) => {
+                self.stack.extend(ins.immediates());
+            }
+            Instruction::Pop(n) => {
+                if n as usize > self.stack.len() {
+                    return Some(Outcome::Error(VMError::StackUnderflow));
                 }
 
-                match i {
-                    Prefix::PUSHI => {
-                        self.pushi(postfix);
-                    }
-                    Prefix::PUSHB => {
-                        self.pushb(postfix);
-                    }
-                    Prefix::POP => {
-                        if postfix as usize > self.stack.len() {
-                            return Outcome::Error(VMError::StackUnderflow);
-                        }
-
-                        for _ in 0..postfix {
-                            let _ = self.stack.pop();
-                        }
-                    }
-                    Prefix::PEEK => {
-                        if postfix as usize >= self.stack.len() {
-                            return Outcome::Error(VMError::StackUnderflow);
-                        }
-                        let val = self.stack[self.stack.len() - (postfix as usize) - 1];
-                        if self.vm.config.trace {
-                            print!("\tindex={} v={}", postfix, val);
-                        }
-                        self.stack.push(val);
-                    }
-                    Prefix::SWAP => {
-                        if postfix as usize >= self.stack.len() {
-                            return Outcome::Error(VMError::StackUnderflow);
-                        }
-                        let last_i = self.stack.len() - 1;
-                        let target_i = last_i - (postfix as usize);
-                        self.stack.swap(target_i, last_i);
-                    }
-                    Prefix::JMP | Prefix::JZ | Prefix::JNZ => {
-                        let target = (u32::from(self.program.code[self.pc + 1])
-                            | (u32::from(self.program.code[self.pc + 2]) << 8))
-                            as usize;
-
-                        self.pc = match i {
-                            Prefix::JMP => target,
-                            Prefix::JZ => {
-                                if let Some(head) = self.stack.last() {
-                                    if *head == 0 {
-                                        target
-                                    } else {
-                                        self.pc + 3
-                                    }
-                                } else {
-                                    return Outcome::Error(VMError::StackUnderflow);
-                                }
-                            }
-                            Prefix::JNZ => {
-                                if let Some(head) = self.stack.last() {
-                                    if *head != 0 {
-                                        target
-                                    } else {
-                                        self.pc + 3
-                                    }
-                                } else {
-                                    return Outcome::Error(VMError::StackUnderflow);
-                                }
-                            }
-                            _ => unreachable!(),
-                        };
-
-                        if self.vm.config.trace {
-                            println!();
-                        }
-                        continue;
-                    }
-                    Prefix::BINARY => {
-                        if let Some(op) = Binary::from(postfix) {
-                            if let (Some(rhs), Some(lhs)) = (self.stack.pop(), self.stack.pop()) {
-                                match op.apply(lhs, rhs) {
-                                    Ok(v) => self.stack.push(v),
-                                    Err(e) => return Outcome::Error(e),
-                                }
-                            } else {
-                                return Outcome::Error(VMError::StackUnderflow);
-                            }
-                        } else {
-                            return Outcome::Error(VMError::UnknownInstruction(postfix));
-                        }
-                    }
-                    Prefix::UNARY => {
-                        if let Some(op) = Unary::from(postfix) {
-                            if let Some(lhs) = self.stack.pop() {
-                                self.stack.push(op.apply(lhs))
-                            } else {
-                                return Outcome::Error(VMError::StackUnderflow);
-                            }
-                        } else {
-                            return Outcome::Error(VMError::UnknownInstruction(postfix));
-                        }
+                for _ in 0..n {
+                    let _ = self.stack.pop();
+                }
+            }
+            Instruction::Peek(n) => {
+                if n as usize >= self.stack.len() {
+                    return Some(Outcome::Error(VMError::StackUnderflow));
+                }
+                let val = self.stack[self.stack.len() - (n as usize) - 1];
+                self.stack.push(val);
+            }
+            Instruction::Swap(n) => {
+                if n as usize >= self.stack.len() {
+                    return Some(Outcome::Error(VMError::StackUnderflow));
+                }
+                let last_i = self.stack.len() - 1;
+                let target_i = last_i - (n as usize);
+                self.stack.swap(target_i, last_i);
+            }
+            Instruction::Jmp(target) | Instruction::Jz(target) | Instruction::Jnz(target) => {
+                let jump = match ins {
+                    Instruction::Jmp(_) => true,
+                    _ => match self.stack.last() {
+                        Some(head) => (*head == 0) == matches!(ins, Instruction::Jz(_)),
+                        None => return Some(Outcome::Error(VMError::StackUnderflow)),
+                    },
+                };
+
+                self.pc = if jump { target as usize } else { self.pc + len };
+
+                let stack = &self.stack;
+                self.vm.trace(|| TraceEvent::Stack(stack.clone()));
+                return None;
+            }
+            Instruction::Binary(op) => {
+                if let (Some(rhs), Some(lhs)) = (self.stack.pop(), self.stack.pop()) {
+                    match op.apply(lhs, rhs) {
+                        Ok(v) => self.stack.push(v),
+                        Err(e) => return Some(Outcome::Error(e)),
                     }
-                    Prefix::USER => {
-                        if let Some(outcome) = self.user(postfix) {
-                            return outcome;
-                        }
+                } else {
+                    return Some(Outcome::Error(VMError::StackUnderflow));
+                }
+            }
+            Instruction::Unary(op) => {
+                if let Some(lhs) = self.stack.pop() {
+                    match op.apply(lhs) {
+                        Ok(v) => self.stack.push(v),
+                        Err(e) => return Some(Outcome::Error(e)),
                     }
-                    Prefix::SPECIAL => {
-                        if let Some(outcome) = self.special(postfix) {
-                            return outcome;
-                        }
+                } else {
+                    return Some(Outcome::Error(VMError::StackUnderflow));
+                }
+            }
+            Instruction::User(user) => {
+                let outcome = self.user(user);
+                // blit and wait may continue with another task, pc is already set
+                if outcome.is_some() || matches!(user, UserCommand::BLIT | UserCommand::WAIT) {
+                    return outcome;
+                }
+            }
+            Instruction::Host {
+                id,
+                inputs,
+                outputs,
+            } => {
+                if let Err(e) = self.host(id, inputs, outputs) {
+                    return Some(Outcome::Error(e));
+                }
+            }
+            // Both continue elsewhere, pc is already set
+            Instruction::Special(special @ (Special::RETURN | Special::YIELD)) => {
+                return self.special(special);
+            }
+            Instruction::Special(special) => {
+                if let Some(outcome) = self.special(special) {
+                    return Some(outcome);
+                }
+            }
+            Instruction::Spawn(end) => {
+                if let Some(limit) = self.config.task_limit {
+                    if self.task_count() >= limit {
+                        return Some(Outcome::Error(VMError::TooManyTasks(limit)));
                     }
                 }
-            } else {
-                return Outcome::Error(VMError::UnknownInstruction(self.program.code[self.pc]));
+                self.tasks.push_back(Task {
+                    id: self.next_task,
+                    pc: self.pc + len,
+                    stack: vec![],
+                });
+                self.next_task += 1;
+                self.turns_left += 1;
+                self.pc = end as usize;
+                return None;
             }
-
-            if self.vm.config.trace {
-                println!("\tstack: {:?}", self.stack);
+            Instruction::Position(coordinate) => {
+                let Some(index) = self.stack.pop() else {
+                    return Some(Outcome::Error(VMError::StackUnderflow));
+                };
+                let Some(value) = self.vm.position(index, coordinate) else {
+                    let length = self.vm.strip.length();
+                    return Some(Outcome::Error(VMError::PixelOutOfRange(index, length)));
+                };
+                self.stack.push(value as u32);
+            }
+            Instruction::On { kind, key, end } => {
+                if let Some(event) = Event::from_key(kind, key) {
+                    let entry = self.pc + len;
+                    match self.handlers.iter_mut().find(|h| h.event == event) {
+                        Some(handler) => handler.entry = entry,
+                        None => self.handlers.push(Handler { event, entry }),
+                    }
+                }
+                self.pc = end as usize;
+                return None;
             }
-            self.pc += 1;
-        }
-
-        if self.vm.config.trace {
-            println!("Ended; {} instructions executed", self.instruction_count);
         }
 
-        Outcome::Ended
+        let stack = &self.stack;
+        self.vm.trace(|| TraceEvent::Stack(stack.clone()));
+        self.pc += len;
+        None
     }
 
     pub fn stop(self) -> (VM, VMStateConfig, Program) {
@@ -365,25 +845,132 @@ impl VMState {
 impl VM {
     pub fn new(length: usize, config: VMConfig) -> VM {
         VM {
-            strip: DummyLedStrip::new(length),
+            strip: Box::new(DummyLedStrip::new(length)),
+            matrix: None,
+            layout: None,
+            segments: vec![],
             config,
+            trace_sink: Rc::new(RefCell::new(Box::new(StdoutSink))),
+            commands: HostCommands::new(),
         }
     }
 
-    pub fn set_stip_length(&mut self, length: usize) {
-        self.strip.set_length(length)
+    /// VM drawing on a `width` x `height` panel wired as `layout`, programs address it with `set_xy` and `get_xy`
+    pub fn new_matrix(width: usize, height: usize, layout: MatrixLayout, config: VMConfig) -> VM {
+        let mut vm = VM::new(width * height, config);
+        let matrix = Matrix {
+            width: width as u32,
+            height: height as u32,
+            layout,
+        };
+        // Leds are positioned on the grid, with `pos_y` running top to bottom like `y`
+        let mut points = vec![[0.0; 3]; width * height];
+        for y in 0..matrix.height {
+            for x in 0..matrix.width {
+                if let Some(index) = matrix.index(x, y) {
+                    points[index as usize] = [x as f32, y as f32, 0.0];
+                }
+            }
+        }
+        vm.layout = Layout::new(&points).ok();
+        vm.matrix = Some(matrix);
+        vm
+    }
+
+    /// Width and height programs see
+    fn dimensions(&self) -> (u32, u32) {
+        match &self.matrix {
+            Some(matrix) => (matrix.width, matrix.height),
+            None => (self.strip.length(), 1),
+        }
     }
 
-    pub fn start(self, program: Program, config: VMStateConfig) -> VMState {
+    /// Index of the led showing `x`, `y`
+    fn index(&self, x: u32, y: u32) -> Option<u32> {
+        match &self.matrix {
+            Some(matrix) => matrix.index(x, y),
+            None => (y == 0 && x < self.strip.length()).then_some(x),
+        }
+    }
+
+    /// Coordinate of led `index` read by `pos_x(i)` and friends
+    fn position(&self, index: u32, coordinate: Coordinate) -> Option<u8> {
+        match &self.layout {
+            Some(layout) => layout.get(index, coordinate),
+            None => Layout::line(index, self.strip.length(), coordinate),
+        }
+    }
+
+    /// Registers a host command programs call as `name(...)`, see `HostCommands::register`
+    pub fn register_command(
+        &mut self,
+        name: &str,
+        inputs: u8,
+        outputs: u8,
+        function: impl FnMut(&[u32]) -> Result<Vec<u32>, String> + 'static,
+    ) -> Result<u8, HostError> {
+        self.commands.register(name, inputs, outputs, function)
+    }
+
+    /// Registered host commands, pass them to `FromSource::from_source_with_commands`
+    pub fn commands(&self) -> &HostCommands {
+        &self.commands
+    }
+
+    /// Replaces the sink receiving trace events and dumps, stdout by default
+    pub fn set_trace_sink(&mut self, sink: Box<dyn TraceSink>) {
+        self.trace_sink = Rc::new(RefCell::new(sink));
+    }
+
+    /// Sends the event built by `event` to the trace sink when tracing
+    fn trace<F: FnOnce() -> TraceEvent>(&mut self, event: F) {
         if self.config.trace {
-            println!("prog hex dump: {:X?}", program.code)
+            self.trace_sink.borrow_mut().event(event());
         }
+    }
+
+    /// Replaces the strip programs draw into, a `DummyLedStrip` by default. A layout of another
+    /// length is dropped, install one for the new strip with `set_layout`.
+    pub fn set_strip(&mut self, strip: Box<dyn LedStrip>) {
+        if self
+            .layout
+            .as_ref()
+            .is_some_and(|layout| layout.len() != strip.length() as usize)
+        {
+            self.layout = None;
+        }
+        self.strip = strip;
+    }
+
+    /// Sets the physical position of every led, see `Layout::from_file`, the layout has to have
+    /// as many leds as the strip
+    pub fn set_layout(&mut self, layout: Layout) -> Result<(), LayoutError> {
+        let length = self.strip.length();
+        if layout.len() != length as usize {
+            return Err(LayoutError::LengthMismatch(layout.len(), length));
+        }
+        self.layout = Some(layout);
+        Ok(())
+    }
+
+    /// Resizes the strip, turning a matrix back into a plain strip and dropping the layout and segments
+    pub fn set_stip_length(&mut self, length: usize) {
+        self.matrix = None;
+        self.layout = None;
+        self.segments.clear();
+        self.strip.set_length(length)
+    }
+
+    pub fn start(mut self, program: Program, config: VMStateConfig) -> VMState {
+        self.trace(|| TraceEvent::Start {
+            code: program.code.clone(),
+        });
         VMState::new(self, program, config)
     }
 }
 
 impl Iterator for VMState {
-    type Item = Result<Box<dyn Iterator<Item =RGBW8> + Send>, VMError>;
+    type Item = Result<Frame, VMError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         match self.run() {
@@ -393,3 +980,79 @@ impl Iterator for VMState {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::FromSource;
+    use crate::instructions::Binary;
+
+    #[test]
+    fn check_error_context() {
+        // PUSHB [07 01 00], DIV
+        let p = Program::from_binary(vec![0x13, 0x07, 0x01, 0x00, 0x82]);
+        let mut state = VM::new(1, Default::default()).start(p, Default::default());
+        let Outcome::Error(e) = state.run() else {
+            panic!("expected error");
+        };
+        assert!(matches!(
+            e.root(),
+            VMError::ArithmeticError(Binary::DIV, 1, 0)
+        ));
+
+        let context = e.context().unwrap();
+        assert_eq!(context.pc, 4);
+        assert_eq!(context.decoded(), Some(Instruction::Binary(Binary::DIV)));
+        assert_eq!(context.instruction_count, 2);
+        assert_eq!(context.stack_top, vec![7]);
+        assert_eq!(context.span, None);
+    }
+
+    #[test]
+    fn check_next_frame() {
+        let source = "frame_rate(10);\nfor(i = 2) {\n    set_pixel(0, i, 0, 0, 0);\n    blit;\n}";
+        let p = Program::from_source(source).unwrap();
+        let copied: Vec<Vec<u8>> = VM::new(2, Default::default())
+            .start(p.clone(), Default::default())
+            .map(|frame| frame.unwrap().map(|c| c.r).collect())
+            .collect();
+        assert_eq!(copied, vec![vec![2, 0], vec![1, 0]]);
+
+        let mut state = VM::new(2, Default::default()).start(p, Default::default());
+        let first = state.next_frame().unwrap().unwrap();
+        let buffer = first.as_ptr();
+        assert_eq!(first.iter().map(|c| c.r).collect::<Vec<_>>(), copied[0]);
+        assert_eq!(state.frame_delay(), Some(Duration::from_millis(100)));
+        // Every frame lends the same buffer
+        let second = state.next_frame().unwrap().unwrap();
+        assert_eq!(second.as_ptr(), buffer);
+        assert_eq!(second.iter().map(|c| c.r).collect::<Vec<_>>(), copied[1]);
+        assert!(state.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn check_random_programs_never_panic() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for _ in 0..20_000 {
+            let length = rng.gen_range(0..64);
+            let code = (0..length).map(|_| rng.gen()).collect();
+            let config = VMConfig {
+                trace: false,
+                deterministic: true,
+            };
+            let state_config = VMStateConfig {
+                global_instruction_limit: Some(10_000),
+                ..Default::default()
+            };
+            let vm = VM::new(rng.gen_range(0..8), config);
+            let mut state = vm.start(Program::from_binary(code), state_config);
+
+            for _ in 0..16 {
+                match state.run() {
+                    Outcome::BLIT(frame) => frame.for_each(drop),
+                    Outcome::Ended | Outcome::Error(_) => break,
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use super::errors::VMError;
+use super::{VMState, RGBW8};
+use smart_leds_trait::SmartLedsWrite;
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+
+impl VMState {
+    /// Writes frames to `strip` until the program ends, showing each one for the delay it requests,
+    /// or for `fallback` when it doesn't request one
+    pub fn play<W>(&mut self, strip: &mut W, fallback: Duration) -> Result<(), VMError>
+    where
+        W: SmartLedsWrite,
+        W::Color: From<RGBW8>,
+        W::Error: Debug,
+    {
+        self.play_with(strip, fallback, Instant::now, std::thread::sleep)
+    }
+
+    /// `play` reading the time from `now` and waiting with `sleep`, e.g. a timer and delay of an
+    /// embedded HAL
+    pub fn play_with<W>(
+        &mut self,
+        strip: &mut W,
+        fallback: Duration,
+        mut now: impl FnMut() -> Instant,
+        mut sleep: impl FnMut(Duration),
+    ) -> Result<(), VMError>
+    where
+        W: SmartLedsWrite,
+        W::Color: From<RGBW8>,
+        W::Error: Debug,
+    {
+        let mut next = now();
+        loop {
+            match self.next_frame()? {
+                Some(pixels) => {
+                    strip
+                        .write(pixels.iter().copied())
+                        .map_err(|e| VMError::Output(format!("{:?}", e)))?;
+                    next += self.frame_delay().unwrap_or(fallback);
+                    // Deadlines follow each other, so time spent rendering doesn't add up,
+                    // but a late frame doesn't make the following ones hurry
+                    let now = now();
+                    match next.checked_duration_since(now) {
+                        Some(remaining) => sleep(remaining),
+                        None => next = now,
+                    }
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::FromSource;
+    use crate::decompiler::ToSource;
+    use crate::program::Program;
+    use crate::vm::VM;
+    use std::cell::Cell;
+
+    struct Recorder(Vec<u8>);
+
+    impl SmartLedsWrite for Recorder {
+        type Error = ();
+        type Color = RGBW8;
+
+        fn write<T, I>(&mut self, iterator: T) -> Result<(), ()>
+        where
+            T: IntoIterator<Item = I>,
+            I: Into<RGBW8>,
+        {
+            self.0.extend(iterator.into_iter().map(|c| c.into().r));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn check_frame_pacing() {
+        let source = "blit;\nframe_rate(100);\nfor(i = 2) {\n    set_pixel(0, i, 0, 0, 0);\n    blit;\n};\nwait(50);\nframe_rate(0);\nblit;";
+        let p = Program::from_source(source).unwrap();
+        let decompiled = p.to_source().unwrap();
+        assert!(decompiled.contains("frame_rate(100);\n"));
+        assert!(decompiled.contains("wait(50);\n"));
+        assert_eq!(Program::from_source(&decompiled).unwrap().code(), p.code());
+
+        let delays: Vec<_> = VM::new(1, Default::default())
+            .start(p.clone(), Default::default())
+            .map(|frame| frame.unwrap().delay.map(|d| d.as_millis()))
+            .collect();
+        assert_eq!(delays, vec![None, Some(10), Some(10), Some(50), None]);
+
+        // Time only passes while sleeping
+        let clock = Cell::new(Instant::now());
+        let mut strip = Recorder(vec![]);
+        let mut sleeps = vec![];
+        let mut state = VM::new(1, Default::default()).start(p.clone(), Default::default());
+        state
+            .play_with(
+                &mut strip,
+                Duration::from_millis(5),
+                || clock.get(),
+                |d| {
+                    clock.set(clock.get() + d);
+                    sleeps.push(d.as_millis())
+                },
+            )
+            .unwrap();
+        assert_eq!(strip.0, vec![0, 2, 1, 1, 1]);
+        assert_eq!(sleeps, vec![5, 10, 10, 50, 5]);
+
+        // Rendering takes 20ms, late frames are shown right away without the next ones hurrying
+        let mut sleeps = vec![];
+        let mut state = VM::new(1, Default::default()).start(p, Default::default());
+        state
+            .play_with(
+                &mut Recorder(vec![]),
+                Duration::from_millis(5),
+                || {
+                    clock.set(clock.get() + Duration::from_millis(20));
+                    clock.get()
+                },
+                |d| {
+                    clock.set(clock.get() + d);
+                    sleeps.push(d.as_millis())
+                },
+            )
+            .unwrap();
+        assert_eq!(sleeps, vec![30]);
+    }
+}
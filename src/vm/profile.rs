@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::instructions::{Instruction, Prefix};
+use crate::program::Program;
+
+/// Execution counts collected while `VMStateConfig::profile` is set
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Profile {
+    per_pc: Vec<u64>,
+    /// Indexed by the prefix nibble
+    per_prefix: [u64; 16],
+    frames: Vec<usize>,
+    current_frame: usize,
+}
+
+impl Profile {
+    pub(crate) fn new(code_length: usize) -> Self {
+        Profile {
+            per_pc: vec![0; code_length],
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn record(&mut self, pc: usize, prefix: Prefix) {
+        self.per_pc[pc] += 1;
+        self.per_prefix[(prefix as u8 >> 4) as usize] += 1;
+        self.current_frame += 1;
+    }
+
+    pub(crate) fn end_frame(&mut self) {
+        self.frames.push(self.current_frame);
+        self.current_frame = 0;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.per_prefix.iter().sum()
+    }
+
+    /// Executions of the instruction at `pc`
+    pub fn count(&self, pc: usize) -> u64 {
+        self.per_pc.get(pc).copied().unwrap_or(0)
+    }
+
+    /// Executed instructions as `(pc, count)`, most executed first
+    pub fn hotspots(&self) -> Vec<(usize, u64)> {
+        let mut hotspots: Vec<(usize, u64)> = self
+            .per_pc
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(pc, count)| (pc, *count))
+            .collect();
+        hotspots.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hotspots
+    }
+
+    /// Executions per opcode class, most executed first
+    pub fn per_prefix(&self) -> Vec<(Prefix, u64)> {
+        let mut classes: Vec<(Prefix, u64)> = self
+            .per_prefix
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .filter_map(|(i, count)| Some((Prefix::from((i as u8) << 4)?, *count)))
+            .collect();
+        classes.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        classes
+    }
+
+    /// Instructions executed for each completed frame
+    pub fn frames(&self) -> &[usize] {
+        &self.frames
+    }
+
+    /// Executions per source line, needs the program's source map
+    pub fn per_line(&self, program: &Program) -> BTreeMap<u32, u64> {
+        let mut lines = BTreeMap::new();
+        if let Some(map) = program.source_map() {
+            for (pc, count) in self.hotspots() {
+                if let Some(span) = map.lookup(pc) {
+                    *lines.entry(span.line).or_default() += count;
+                }
+            }
+        }
+        lines
+    }
+
+    /// Human readable summary, listing the `top` most executed instructions
+    pub fn report(&self, program: &Program, top: usize) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "instructions executed: {}", self.total());
+
+        if let (Some(min), Some(max)) = (self.frames.iter().min(), self.frames.iter().max()) {
+            let average = self.frames.iter().sum::<usize>() / self.frames.len();
+            let _ = writeln!(
+                out,
+                "frames: {}, instructions per frame min/avg/max: {}/{}/{}",
+                self.frames.len(),
+                min,
+                average,
+                max
+            );
+        }
+
+        let _ = writeln!(out, "\nper opcode class:");
+        for (prefix, count) in self.per_prefix() {
+            let _ = writeln!(out, "{:>12}  {}", count, prefix);
+        }
+
+        let _ = writeln!(out, "\nhotspots:");
+        let map = program.source_map();
+        for (pc, count) in self.hotspots().into_iter().take(top) {
+            let ins = Instruction::decode(program.code(), pc)
+                .map(|(ins, _)| ins.to_string())
+                .unwrap_or_default();
+            let line = map
+                .and_then(|m| m.lookup(pc))
+                .map(|span| format!("\tline {}", span.line))
+                .unwrap_or_default();
+            let _ = writeln!(out, "{:>12}  {:04}.\t{}{}", count, pc, ins, line);
+        }
+
+        let lines = self.per_line(program);
+        if !lines.is_empty() {
+            let _ = writeln!(out, "\nper source line:");
+            for (line, count) in lines {
+                let _ = writeln!(out, "{:>12}  line {}", count, line);
+            }
+        }
+        out
+    }
+
+    /// Folded stacks as `program;line N;CLASS count`, the input format of flamegraph tools
+    pub fn folded_stacks(&self, program: &Program) -> String {
+        let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+        let map = program.source_map();
+        for (pc, count) in self.hotspots() {
+            let line = match map.and_then(|m| m.lookup(pc)) {
+                Some(span) => format!("line {}", span.line),
+                None => format!("pc {}", pc),
+            };
+            let class = Instruction::decode(program.code(), pc)
+                .map(|(ins, _)| ins.prefix().to_string())
+                .unwrap_or_default();
+            *stacks
+                .entry(format!("program;{};{}", line, class))
+                .or_default() += count;
+        }
+
+        let mut out = String::new();
+        for (stack, count) in stacks {
+            let _ = writeln!(out, "{} {}", stack, count);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::FromSource;
+    use crate::instructions::Prefix;
+    use crate::program::Program;
+    use crate::vm::{Outcome, VMStateConfig, VM};
+
+    #[test]
+    fn check_profile() {
+        let source = "loop {\n    for(i=get_length) {\n        set_pixel(i - 1, 1, 2, 3, 4);\n    };\n    blit;\n}";
+        let p = Program::from_source(source).unwrap();
+        let config = VMStateConfig {
+            profile: true,
+            ..Default::default()
+        };
+        let mut state = VM::new(4, Default::default()).start(p.clone(), config);
+        for _ in 0..3 {
+            assert!(matches!(state.run(), Outcome::BLIT(_)));
+        }
+
+        let profile = state.profile().unwrap();
+        assert_eq!(profile.frames().len(), 3);
+        assert_eq!(profile.frames()[1], profile.frames()[2]);
+        assert_eq!(
+            profile.total(),
+            profile.hotspots().iter().map(|h| h.1).sum::<u64>()
+        );
+
+        let lines = profile.per_line(&p);
+        assert_eq!(lines.keys().copied().collect::<Vec<_>>(), vec![1, 2, 3, 5]);
+        assert!(lines[&3] > lines[&2]);
+
+        let classes = profile.per_prefix();
+        assert!(classes
+            .iter()
+            .any(|(prefix, count)| *prefix == Prefix::USER && *count == 18));
+
+        let folded = profile.folded_stacks(&p);
+        assert!(folded.contains("program;line 5;USER 3\n"));
+        assert!(profile.report(&p, 5).contains("per source line:"));
+    }
+}
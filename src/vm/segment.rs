@@ -0,0 +1,319 @@
+use super::errors::VMError;
+use super::input::Inputs;
+use super::{VMConfig, VMState, VMStateConfig, RGBW8, VM};
+use crate::program::Program;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SegmentError {
+    #[error("segment {0} is already defined")]
+    Duplicate(String),
+
+    #[error("segment {0} has no leds")]
+    Empty(String),
+
+    #[error("segment {0} ends at {1}, past the strip length {2}")]
+    OutOfRange(String, usize, u32),
+
+    #[error("segment {0} overlaps segment {1}")]
+    Overlap(String, String),
+
+    #[error("no segment named {0}")]
+    Unknown(String),
+
+    #[error("the layout does not cover segment {0}")]
+    Layout(String),
+}
+
+/// Leds `start..start + length` of the strip, driven by a program of their own, see `VM::add_segment`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub name: String,
+    pub start: usize,
+    pub length: usize,
+    /// The program's index 0 is the last led of the segment
+    pub reverse: bool,
+}
+
+impl Segment {
+    fn end(&self) -> usize {
+        self.start + self.length
+    }
+
+    /// Index in the strip of the segment's led `index`
+    fn index(&self, index: usize) -> usize {
+        if self.reverse {
+            self.end() - 1 - index
+        } else {
+            self.start + index
+        }
+    }
+}
+
+impl VM {
+    /// Splits off leds `start..start + length` as segment `name`, programs running on it see
+    /// just these leds, numbered from `start` or from the end when `reverse`
+    pub fn add_segment(
+        &mut self,
+        name: &str,
+        start: usize,
+        length: usize,
+        reverse: bool,
+    ) -> Result<(), SegmentError> {
+        let segment = Segment {
+            name: name.to_string(),
+            start,
+            length,
+            reverse,
+        };
+        if length == 0 {
+            return Err(SegmentError::Empty(segment.name));
+        }
+        let end = segment.end();
+        if end > self.strip.length() as usize {
+            return Err(SegmentError::OutOfRange(
+                segment.name,
+                end,
+                self.strip.length(),
+            ));
+        }
+        for other in &self.segments {
+            if other.name == segment.name {
+                return Err(SegmentError::Duplicate(segment.name));
+            }
+            if other.start < segment.end() && segment.start < other.end() {
+                return Err(SegmentError::Overlap(segment.name, other.name.clone()));
+            }
+        }
+        self.segments.push(segment);
+        Ok(())
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Starts driving the strip by segments, load their programs with `Segments::load`
+    pub fn start_segments(self) -> Segments {
+        Segments {
+            states: self.segments.iter().map(|_| None).collect(),
+            vm: self,
+            inputs: Inputs::new(),
+            frame_delay: None,
+        }
+    }
+}
+
+/// Programs running on the segments of a strip, merged into one frame on every `blit`. They share
+/// the host commands and trace sink of the strip's `VM` and one set of input registers.
+pub struct Segments {
+    vm: VM,
+    /// Indexed like `VM::segments`, `None` until a program is loaded and once it ended
+    states: Vec<Option<VMState>>,
+    inputs: Inputs,
+    frame_delay: Option<Duration>,
+}
+
+impl Segments {
+    /// Runs `program` on segment `name`, replacing the program running there
+    pub fn load(
+        &mut self,
+        name: &str,
+        program: Program,
+        config: VMStateConfig,
+    ) -> Result<(), SegmentError> {
+        let n = self
+            .vm
+            .segments
+            .iter()
+            .position(|s| s.name == name)
+            .ok_or_else(|| SegmentError::Unknown(name.to_string()))?;
+        let segment = &self.vm.segments[n];
+        // `VM::set_strip` may have installed a shorter strip since the segment was added
+        let length = self.vm.strip.length();
+        if segment.end() > length as usize {
+            return Err(SegmentError::OutOfRange(
+                segment.name.clone(),
+                segment.end(),
+                length,
+            ));
+        }
+        let mut vm = VM::new(
+            segment.length,
+            VMConfig {
+                trace: self.vm.config.trace,
+                deterministic: self.vm.config.deterministic,
+            },
+        );
+        if let Some(layout) = &self.vm.layout {
+            vm.layout = Some(
+                layout
+                    .segment(segment.start, segment.length, segment.reverse)
+                    .ok_or_else(|| SegmentError::Layout(segment.name.clone()))?,
+            );
+        }
+        vm.commands = self.vm.commands.clone();
+        vm.trace_sink = self.vm.trace_sink.clone();
+        let mut state = vm.start(program, config);
+        state.inputs = self.inputs.clone();
+        self.states[n] = Some(state);
+        Ok(())
+    }
+
+    /// Input registers read by the programs of all segments
+    pub fn inputs(&self) -> &Inputs {
+        &self.inputs
+    }
+
+    /// State of the program running on segment `name`, e.g. to raise events
+    pub fn state(&mut self, name: &str) -> Option<&mut VMState> {
+        let n = self.vm.segments.iter().position(|s| s.name == name)?;
+        self.states[n].as_mut()
+    }
+
+    /// Runs every segment to its next frame and lends the merged pixels of the whole strip,
+    /// `None` once all programs ended. Segments without a running program keep their last pixels.
+    pub fn next_frame(&mut self) -> Result<Option<&[RGBW8]>, VMError> {
+        let mut emitted = false;
+        self.frame_delay = None;
+        for (segment, slot) in self.vm.segments.iter().zip(self.states.iter_mut()) {
+            let Some(state) = slot else {
+                continue;
+            };
+            let pixels = state
+                .next_frame()
+                .map_err(|e| VMError::Segment(segment.name.clone(), Box::new(e)))?;
+            match pixels {
+                Some(pixels) => {
+                    for (i, color) in pixels.iter().enumerate() {
+                        self.vm.strip.set_pixel(segment.index(i) as u32, *color);
+                    }
+                    // The segment asking for the shortest delay sets the pace
+                    self.frame_delay = match (self.frame_delay, state.frame_delay()) {
+                        (Some(a), Some(b)) => Some(a.min(b)),
+                        (a, b) => a.or(b),
+                    };
+                    emitted = true;
+                }
+                None => *slot = None,
+            }
+        }
+        if !emitted {
+            return Ok(None);
+        }
+        self.vm.strip.present().map_err(VMError::Output)?;
+        Ok(Some(self.vm.strip.pixels()))
+    }
+
+    /// Delay requested for the last frame, the shortest one of the segments, see `Frame::delay`
+    pub fn frame_delay(&self) -> Option<Duration> {
+        self.frame_delay
+    }
+
+    pub fn stop(self) -> VM {
+        self.vm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::FromSource;
+    use crate::vm::strip::DummyLedStrip;
+    use crate::vm::trace::{MemorySink, TraceEvent};
+
+    fn program(source: &str) -> Program {
+        Program::from_source(source).unwrap()
+    }
+
+    fn red(pixels: &[RGBW8]) -> Vec<u8> {
+        pixels.iter().map(|c| c.r).collect()
+    }
+
+    #[test]
+    fn check_segments() {
+        let mut vm = VM::new(10, VMConfig::default());
+        vm.add_segment("left", 0, 4, false).unwrap();
+        vm.add_segment("right", 6, 4, true).unwrap();
+        assert_eq!(
+            vm.add_segment("middle", 3, 3, false),
+            Err(SegmentError::Overlap("middle".into(), "left".into()))
+        );
+        assert_eq!(
+            vm.add_segment("tail", 8, 3, false),
+            Err(SegmentError::OutOfRange("tail".into(), 11, 10))
+        );
+        assert_eq!(
+            vm.add_segment("left", 4, 1, false),
+            Err(SegmentError::Duplicate("left".into()))
+        );
+
+        let mut segments = vm.start_segments();
+        let fill = "for(i = get_length) {\n    set_pixel(i - 1, OFFSET + i, 0, 0, 0);\n};\nblit;";
+        let left = format!(
+            "{}\nset_pixel(0, 9, 0, 0, 0);\nwait(20);",
+            fill.replace("OFFSET", "0")
+        );
+        let right = format!("frame_rate(25);\n{}", fill.replace("OFFSET", "10"));
+        segments
+            .load("left", program(&left), Default::default())
+            .unwrap();
+        segments
+            .load("right", program(&right), Default::default())
+            .unwrap();
+        assert_eq!(
+            segments.load("top", program("blit;"), Default::default()),
+            Err(SegmentError::Unknown("top".into()))
+        );
+
+        let frame = segments.next_frame().unwrap().map(red);
+        assert_eq!(frame, Some(vec![1, 2, 3, 4, 0, 0, 14, 13, 12, 11]));
+        assert_eq!(segments.frame_delay(), Some(Duration::from_millis(40)));
+        // The right segment ended and keeps its pixels
+        let frame = segments.next_frame().unwrap().map(red);
+        assert_eq!(frame, Some(vec![9, 2, 3, 4, 0, 0, 14, 13, 12, 11]));
+        assert_eq!(segments.frame_delay(), Some(Duration::from_millis(20)));
+        assert!(segments.next_frame().unwrap().is_none());
+
+        let mut segments = segments.stop().start_segments();
+        let overflow = program("set_pixel(4, 1, 0, 0, 0);");
+        segments
+            .load("right", overflow, Default::default())
+            .unwrap();
+        let e = segments.next_frame().unwrap_err();
+        assert!(matches!(&e, VMError::Segment(name, _) if name == "right"));
+        assert!(matches!(e.root(), VMError::PixelOutOfRange(4, 4)));
+
+        // Segments must fit the strip installed after adding them
+        let mut vm = VM::new(10, VMConfig::default());
+        vm.add_segment("tail", 6, 4, false).unwrap();
+        vm.set_strip(Box::new(DummyLedStrip::new(8)));
+        assert_eq!(
+            vm.start_segments()
+                .load("tail", program("blit;"), Default::default()),
+            Err(SegmentError::OutOfRange("tail".into(), 10, 8))
+        );
+
+        // Segments call the strip's host commands, trace to its sink and read shared inputs
+        let mut vm = segments.stop();
+        vm.register_command("level", 1, 1, |args| Ok(vec![args[0] * 2]))
+            .unwrap();
+        let sink = MemorySink::new();
+        vm.set_trace_sink(Box::new(sink.clone()));
+        let source = "set_pixel(0, level(input(0)), 0, 0, 0);\ndump;\nblit;";
+        let p = Program::from_source_with_commands(source, vm.commands()).unwrap();
+        let mut segments = vm.start_segments();
+        segments
+            .load("left", p.clone(), Default::default())
+            .unwrap();
+        segments.load("right", p, Default::default()).unwrap();
+        segments.inputs().set(0, 21);
+        let frame = segments.next_frame().unwrap().map(red);
+        assert_eq!(frame, Some(vec![42, 0, 0, 0, 0, 0, 0, 0, 0, 42]));
+        assert_eq!(
+            sink.take(),
+            vec![TraceEvent::Dump(vec![]), TraceEvent::Dump(vec![])]
+        );
+    }
+}
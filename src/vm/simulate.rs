@@ -0,0 +1,82 @@
+use super::errors::VMError;
+use super::{Outcome, VMState};
+
+/// Cycles spent on each frame by `VMState::simulate`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Simulation {
+    pub clock_hz: u64,
+    pub frame_cycles: Vec<u64>,
+}
+
+impl Simulation {
+    /// Frame rate at the average cost of a frame
+    pub fn average_fps(&self) -> Option<f64> {
+        let total: u64 = self.frame_cycles.iter().sum();
+        self.fps(total as f64 / self.frame_cycles.len() as f64)
+    }
+
+    /// Frame rate while the most expensive frame is shown
+    pub fn worst_fps(&self) -> Option<f64> {
+        self.fps(*self.frame_cycles.iter().max()? as f64)
+    }
+
+    fn fps(&self, cycles: f64) -> Option<f64> {
+        (cycles > 0.0).then(|| self.clock_hz as f64 / cycles)
+    }
+}
+
+impl VMState {
+    /// Runs up to `frames` frames on a simulated CPU running at `clock_hz`, charging cycles with
+    /// `VMStateConfig::cost_model`. Stops early when the program ends.
+    pub fn simulate(&mut self, frames: usize, clock_hz: u64) -> Result<Simulation, VMError> {
+        let mut simulation = Simulation {
+            clock_hz,
+            frame_cycles: vec![],
+        };
+        for _ in 0..frames {
+            let start = self.cycles();
+            match self.run() {
+                Outcome::BLIT(_) => simulation.frame_cycles.push(self.cycles() - start),
+                Outcome::Ended => break,
+                Outcome::Error(e) => return Err(e),
+            }
+        }
+        Ok(simulation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::budget::Bound;
+    use crate::compiler::FromSource;
+    use crate::cost::{CortexM0, Uniform};
+    use crate::program::Program;
+    use crate::vm::{VMStateConfig, VM};
+
+    #[test]
+    fn check_simulation() {
+        let source = "loop {\n    for(i=get_length) {\n        set_pixel(i - 1, i * 10 / 3, 2, 3, 4);\n    };\n    blit;\n}";
+        let p = Program::from_source(source).unwrap();
+        assert_eq!(
+            p.frame_cycles(6, &Uniform).unwrap(),
+            p.frame_budget(6).unwrap()
+        );
+
+        let config = VMStateConfig {
+            cost_model: Some(Box::new(CortexM0)),
+            ..Default::default()
+        };
+        let mut state = VM::new(6, Default::default()).start(p.clone(), config);
+        let simulation = state.simulate(4, 48_000_000).unwrap();
+        assert_eq!(simulation.frame_cycles.len(), 4);
+
+        // Straight line code, so the static bound is the cost of the longest frame
+        let longest = *simulation.frame_cycles.iter().max().unwrap();
+        assert_eq!(
+            p.frame_cycles(6, &CortexM0).unwrap().per_frame,
+            Bound::Finite(longest)
+        );
+        assert_eq!(simulation.worst_fps(), Some(48_000_000.0 / longest as f64));
+        assert!(simulation.average_fps() >= simulation.worst_fps());
+    }
+}
@@ -1,39 +1,61 @@
 use crate::vm::RGBW8;
-use smart_leds_trait::White;
-use std::iter::repeat;
+use smart_leds_trait::{SmartLedsWrite, White};
+use std::fmt::Debug;
+use std::iter::repeat_n;
 
 const RGBW_BLACK: RGBW8 = RGBW8::new_alpha(0, 0, 0, White(0));
 
+/// Output the VM draws into, installed with `VM::set_strip`
+pub trait LedStrip {
+    fn length(&self) -> u32;
+
+    fn set_length(&mut self, length: usize);
+
+    /// Only called with `idx` below `length`
+    fn set_pixel(&mut self, idx: u32, color: RGBW8);
+
+    fn get_pixel(&self, idx: u32) -> Option<RGBW8>;
+
+    /// Pixels as last set, lent by `VMState::next_frame`
+    fn pixels(&self) -> &[RGBW8];
+
+    /// Shows the pixels on `blit`
+    fn present(&mut self) -> Result<(), String>;
+}
+
+/// Strip keeping the pixels in memory for the host to copy out, the default
 pub struct DummyLedStrip {
     buf: Vec<RGBW8>,
 }
 
-impl DummyLedStrip {
-    pub fn length(&self) -> u32 {
+impl LedStrip for DummyLedStrip {
+    fn length(&self) -> u32 {
         self.buf.len() as u32
     }
 
-    pub fn blit(&mut self) {}
+    fn set_length(&mut self, length: usize) {
+        if length < self.buf.len() {
+            self.buf.truncate(length);
+        } else {
+            self.buf
+                .extend(repeat_n(RGBW_BLACK, length - self.buf.len()));
+        }
+    }
 
-    pub fn set_pixel(&mut self, idx: u32, color: RGBW8) {
+    fn set_pixel(&mut self, idx: u32, color: RGBW8) {
         self.buf[idx as usize] = color;
     }
 
-    pub fn get_pixel(&self, idx: u32) -> RGBW8 {
-        self.buf[idx as usize]
+    fn get_pixel(&self, idx: u32) -> Option<RGBW8> {
+        self.buf.get(idx as usize).copied()
     }
 
-    pub fn export(&self) -> Box<dyn Iterator<Item =RGBW8> + Send> {
-        Box::new(self.buf.clone().into_iter())
+    fn pixels(&self) -> &[RGBW8] {
+        &self.buf
     }
 
-    pub fn set_length(&mut self, length: usize) {
-        if length < self.buf.len() {
-            self.buf.truncate(length);
-        } else {
-            self.buf
-                .extend(repeat(RGBW_BLACK).take(length - self.buf.len()));
-        }
+    fn present(&mut self) -> Result<(), String> {
+        Ok(())
     }
 }
 
@@ -44,3 +66,114 @@ impl DummyLedStrip {
         }
     }
 }
+
+/// Forwards every frame to a `SmartLedsWrite` driver on `blit`
+pub struct SmartLedsStrip<W> {
+    writer: W,
+    buf: DummyLedStrip,
+}
+
+impl<W> SmartLedsStrip<W> {
+    pub fn new(writer: W, length: usize) -> Self {
+        Self {
+            writer,
+            buf: DummyLedStrip::new(length),
+        }
+    }
+
+    pub fn writer(&self) -> &W {
+        &self.writer
+    }
+}
+
+impl<W> LedStrip for SmartLedsStrip<W>
+where
+    W: SmartLedsWrite,
+    W::Color: From<RGBW8>,
+    W::Error: Debug,
+{
+    fn length(&self) -> u32 {
+        self.buf.length()
+    }
+
+    fn set_length(&mut self, length: usize) {
+        self.buf.set_length(length)
+    }
+
+    fn set_pixel(&mut self, idx: u32, color: RGBW8) {
+        self.buf.set_pixel(idx, color)
+    }
+
+    fn get_pixel(&self, idx: u32) -> Option<RGBW8> {
+        self.buf.get_pixel(idx)
+    }
+
+    fn pixels(&self) -> &[RGBW8] {
+        self.buf.pixels()
+    }
+
+    fn present(&mut self) -> Result<(), String> {
+        self.writer
+            .write(self.buf.pixels().iter().copied())
+            .map_err(|e| format!("{:?}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::FromSource;
+    use crate::program::Program;
+    use crate::vm::errors::VMError;
+    use crate::vm::{Outcome, VMConfig, VM};
+    use std::sync::{Arc, Mutex};
+
+    /// Driver recording the red channel of written frames, failing once `fail` is set
+    #[derive(Clone, Default)]
+    struct Driver {
+        frames: Arc<Mutex<Vec<Vec<u8>>>>,
+        fail: Arc<Mutex<bool>>,
+    }
+
+    impl SmartLedsWrite for Driver {
+        type Error = &'static str;
+        type Color = RGBW8;
+
+        fn write<T, I>(&mut self, iterator: T) -> Result<(), Self::Error>
+        where
+            T: IntoIterator<Item = I>,
+            I: Into<RGBW8>,
+        {
+            if *self.fail.lock().unwrap() {
+                return Err("bus error");
+            }
+            let frame = iterator.into_iter().map(|c| c.into().r).collect();
+            self.frames.lock().unwrap().push(frame);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn check_smart_leds_strip() {
+        let driver = Driver::default();
+        let mut vm = VM::new(1, VMConfig::default());
+        vm.set_strip(Box::new(SmartLedsStrip::new(driver.clone(), 3)));
+        let source = "for(i = get_length) {\n    set_pixel(i - 1, i, 0, 0, 0);\n    blit;\n}";
+        let mut state = vm.start(Program::from_source(source).unwrap(), Default::default());
+
+        while state.next_frame().unwrap().is_some() {}
+        assert_eq!(
+            *driver.frames.lock().unwrap(),
+            vec![vec![0, 0, 3], vec![0, 2, 3], vec![1, 2, 3]]
+        );
+
+        let (vm, config, program) = state.stop();
+        *driver.fail.lock().unwrap() = true;
+        match vm.start(program, config).run() {
+            Outcome::Error(e) => {
+                assert!(matches!(e.root(), VMError::Output(e) if e == "\"bus error\""))
+            }
+            _ => panic!("expected error"),
+        }
+    }
+}
@@ -0,0 +1,87 @@
+/// Id of the task running the program from its start
+pub const MAIN_TASK: usize = 0;
+
+/// Default of `VMStateConfig::task_limit`
+pub const DEFAULT_TASK_LIMIT: usize = 32;
+
+/// Task waiting for its turn, started by `spawn`
+#[derive(Clone, Debug)]
+pub(crate) struct Task {
+    pub id: usize,
+    pub pc: usize,
+    pub stack: Vec<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::FromAssembly;
+    use crate::budget::Unbounded;
+    use crate::compiler::FromSource;
+    use crate::decompiler::ToSource;
+    use crate::program::Program;
+    use crate::vm::errors::VMError;
+    use crate::vm::{Outcome, VMState, VMStateConfig, VM};
+
+    fn frame(state: &mut VMState) -> Vec<u8> {
+        match state.run() {
+            Outcome::BLIT(frame) => frame.map(|c| c.r).collect(),
+            _ => panic!("expected a frame"),
+        }
+    }
+
+    #[test]
+    fn check_tasks() {
+        let source = "spawn {\n    let a = 0;\n    loop {\n        a = a + 1;\n        set_pixel(1, a, 0, 0, 0);\n        yield;\n    };\n};\nspawn {\n    set_pixel(2, 7, 0, 0, 0);\n};\nfor(i = 2) {\n    set_pixel(0, i, 0, 0, 0);\n    blit;\n}";
+        let p = Program::from_source(source).unwrap();
+        assert_eq!(p.verify(), Ok(()));
+        let decompiled = p.to_source().unwrap();
+        assert!(decompiled.contains("spawn {\n"));
+        assert!(decompiled.contains("yield;\n"));
+        assert_eq!(Program::from_source(&decompiled).unwrap().code(), p.code());
+
+        let listing = format!("{:?}", p);
+        assert!(listing.contains("SPECIAL\tspawn to "));
+        assert_eq!(Program::from_assembly(&listing).unwrap().code(), p.code());
+        let main_only =
+            Program::from_source("for(i = 2) { set_pixel(0, i, 0, 0, 0); blit; }").unwrap();
+        assert!(
+            p.frame_budget(3).unwrap().per_frame > main_only.frame_budget(3).unwrap().per_frame
+        );
+        let spawning = Program::from_source("loop { spawn { yield; }; blit; }").unwrap();
+        assert!(matches!(
+            spawning.frame_budget(3).unwrap().reasons[..],
+            [Unbounded::RepeatedSpawn(0)]
+        ));
+
+        let mut state = VM::new(3, Default::default()).start(p, Default::default());
+        assert_eq!(frame(&mut state), vec![2, 1, 7]);
+        assert_eq!(state.task_count(), 2);
+        assert_eq!(frame(&mut state), vec![1, 2, 7]);
+        // The main program ended, the counting task keeps producing frames
+        assert_eq!(frame(&mut state), vec![1, 3, 7]);
+        assert_eq!(state.task_count(), 1);
+        assert_ne!(state.task(), super::MAIN_TASK);
+
+        let p = Program::from_source("spawn { yield; }; yield;").unwrap();
+        let mut state = VM::new(1, Default::default()).start(p, Default::default());
+        assert!(matches!(state.run(), Outcome::BLIT(_)));
+        assert!(matches!(state.run(), Outcome::Ended));
+        assert!(matches!(state.run(), Outcome::Ended));
+
+        // A task spawned every frame runs into the limit
+        let p = Program::from_source("loop { spawn { loop { yield; }; }; blit; }").unwrap();
+        let config = VMStateConfig {
+            task_limit: Some(4),
+            ..Default::default()
+        };
+        let mut state = VM::new(1, Default::default()).start(p, config);
+        for _ in 0..3 {
+            assert!(matches!(state.run(), Outcome::BLIT(_)));
+        }
+        assert_eq!(state.task_count(), 4);
+        match state.run() {
+            Outcome::Error(e) => assert!(matches!(e.root(), VMError::TooManyTasks(4))),
+            _ => panic!("expected error"),
+        }
+    }
+}
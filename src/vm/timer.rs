@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+
+/// State of the `every` and `after` statements, keyed by the running task and the pc of their
+/// instruction, so tasks spawned from the same code keep their own timers.
+/// Times are milliseconds of `get_precise_time` and may wrap around.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Timers {
+    deadlines: HashMap<(usize, usize), Deadline>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Deadline {
+    at: u32,
+    fired: bool,
+}
+
+impl Timers {
+    /// True once every `period` since the statement at `pc` was first reached, missed periods are skipped
+    /// but the phase is kept, so the timer doesn't drift
+    pub fn every(&mut self, task: usize, pc: usize, now: u32, period: u32) -> bool {
+        let period = period.max(1);
+        let Some(deadline) = self.due((task, pc), now, period) else {
+            return false;
+        };
+        let late = now.wrapping_sub(deadline.at);
+        deadline.at = deadline
+            .at
+            .wrapping_add(period.wrapping_mul(late / period + 1));
+        true
+    }
+
+    /// True the first time the statement at `pc` is reached `delay` or more after it was first reached
+    pub fn after(&mut self, task: usize, pc: usize, now: u32, delay: u32) -> bool {
+        match self.due((task, pc), now, delay) {
+            Some(deadline) if !deadline.fired => {
+                deadline.fired = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Forgets the timers of a task which ended
+    pub fn end_task(&mut self, task: usize) {
+        self.deadlines.retain(|(t, _), _| *t != task);
+    }
+
+    /// Deadline of the timer at `key` when it passed, starting the timer when it is reached the first time
+    fn due(&mut self, key: (usize, usize), now: u32, duration: u32) -> Option<&mut Deadline> {
+        let deadline = self.deadlines.entry(key).or_insert(Deadline {
+            at: now.wrapping_add(duration),
+            fired: false,
+        });
+        // Wrapping comparison, the deadline passed when it is less than half the range behind
+        (now.wrapping_sub(deadline.at) < 1 << 31).then_some(deadline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::FromSource;
+    use crate::decompiler::ToSource;
+    use crate::program::Program;
+    use crate::vm::clock::ManualClock;
+    use crate::vm::{Outcome, VMConfig, VMStateConfig, VM};
+    use std::time::Duration;
+
+    #[test]
+    fn check_timers() {
+        let mut timers = Timers::default();
+        let ticks: Vec<u32> = [0, 499, 500, 999, 1600, 2000]
+            .into_iter()
+            .filter(|now| timers.every(0, 0, *now, 500))
+            .collect();
+        assert_eq!(ticks, vec![500, 1600, 2000]);
+        assert!(!timers.after(0, 1, u32::MAX - 10, 20));
+        assert!(!timers.after(0, 1, 5, 20));
+        assert!(timers.after(0, 1, 9, 20));
+        assert!(!timers.after(0, 1, 30, 20));
+        // Another task reaching the same statement starts its own timer
+        assert!(!timers.after(1, 1, 30, 20));
+        timers.end_task(1);
+        assert_eq!(timers.deadlines.len(), 2);
+
+        let source = "let a = 0;\nlet b = 0;\nloop {\n    every 50ms {\n        a = a + 1;\n    };\n    after 1s {\n        b = 1;\n    };\n    set_pixel(0, a, b, 0, 0);\n    blit;\n}";
+        let p = Program::from_source(source).unwrap();
+        let decompiled = p.to_source().unwrap();
+        assert!(decompiled.contains("every 50ms {\n"));
+        assert!(decompiled.contains("after 1s {\n"));
+        assert_eq!(Program::from_source(&decompiled).unwrap().code(), p.code());
+
+        // Deterministic time advances by one millisecond per instruction
+        let vm = VM::new(
+            1,
+            VMConfig {
+                deterministic: true,
+                ..Default::default()
+            },
+        );
+        let mut state = vm.start(p, Default::default());
+        let mut last = (0, 0);
+        for _ in 0..100 {
+            assert!(matches!(state.run(), Outcome::BLIT(_)));
+            last = state.pixel(0).map(|c| (c.r, c.g)).unwrap();
+        }
+        let elapsed = state.instruction_count() as u32;
+        assert!((elapsed / 50).abs_diff(last.0 as u32) <= 1);
+        assert_eq!(last.1, 1);
+
+        // Both tasks spawned from the same code count every period
+        let source = "for(i = 2) {\n    spawn {\n        loop {\n            every 10ms {\n                set_pixel(0, red(get_pixel(0)) + 1, 0, 0, 0);\n            };\n            yield;\n        };\n    };\n};";
+        let clock = ManualClock::new();
+        let config = VMStateConfig {
+            clock: Some(Box::new(clock.clone())),
+            ..Default::default()
+        };
+        let mut state =
+            VM::new(1, Default::default()).start(Program::from_source(source).unwrap(), config);
+        assert!(matches!(state.run(), Outcome::BLIT(_)));
+        for _ in 0..4 {
+            clock.advance(Duration::from_millis(10));
+            assert!(matches!(state.run(), Outcome::BLIT(_)));
+        }
+        assert_eq!(state.pixel(0).map(|c| c.r), Some(8));
+    }
+}
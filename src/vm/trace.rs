@@ -0,0 +1,223 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use super::RGBW8;
+use crate::instructions::Instruction;
+
+/// Something observable the VM did, instruction level events are only sent when `VMConfig::trace` is set
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TraceEvent {
+    Start {
+        code: Vec<u8>,
+    },
+    /// Instruction at `pc` is about to execute
+    Instruction {
+        pc: usize,
+        bytes: Vec<u8>,
+    },
+    /// Stack after the last instruction, the last value is the top
+    Stack(Vec<u32>),
+    SetPixel {
+        index: u32,
+        color: RGBW8,
+    },
+    Blit,
+    /// Sent by `dump` statements whether tracing or not
+    Dump(Vec<u32>),
+    Ended {
+        instruction_count: usize,
+    },
+}
+
+impl TraceEvent {
+    /// Decoded instruction of an `Instruction` event
+    pub fn instruction(&self) -> Option<Instruction<'_>> {
+        match self {
+            TraceEvent::Instruction { bytes, .. } => {
+                Instruction::decode(bytes, 0).ok().map(|(ins, _)| ins)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Receives trace events, installed with `VM::set_trace_sink`
+pub trait TraceSink {
+    fn event(&mut self, event: TraceEvent);
+}
+
+/// Prints events to stdout in the disassembly listing format, the default sink
+#[derive(Default)]
+pub struct StdoutSink;
+
+impl TraceSink for StdoutSink {
+    fn event(&mut self, event: TraceEvent) {
+        match &event {
+            TraceEvent::Start { code } => println!("prog hex dump: {:X?}", code),
+            TraceEvent::Instruction { pc, bytes } => match event.instruction() {
+                Some(ins) => print!("{:04}.\t{:02x}\t{}", pc, bytes[0], ins),
+                None => print!("{:04}.\t{:02x?}", pc, bytes),
+            },
+            TraceEvent::Stack(stack) => println!("\tstack: {:?}", stack),
+            TraceEvent::SetPixel { index, color } => {
+                print!("\tset_pixel idx={} color={:?}", index, color)
+            }
+            TraceEvent::Blit => println!("\tblit"),
+            TraceEvent::Dump(stack) => println!("DUMP: {:?}", stack),
+            TraceEvent::Ended { instruction_count } => {
+                println!("Ended; {} instructions executed", instruction_count)
+            }
+        }
+    }
+}
+
+/// Collects events in memory, clones share the same buffer so one can stay with the host
+#[derive(Clone, Default)]
+pub struct MemorySink {
+    events: Arc<Mutex<Vec<TraceEvent>>>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Removes and returns the events collected so far
+    pub fn take(&self) -> Vec<TraceEvent> {
+        std::mem::take(&mut *self.events.lock().unwrap())
+    }
+}
+
+impl TraceSink for MemorySink {
+    fn event(&mut self, event: TraceEvent) {
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+/// Writes one JSON object per event and line, write errors are ignored
+pub struct JsonLinesSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> JsonLinesSink<W> {
+    pub fn new(writer: W) -> Self {
+        JsonLinesSink { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> TraceSink for JsonLinesSink<W> {
+    fn event(&mut self, event: TraceEvent) {
+        let line = match &event {
+            TraceEvent::Start { code } => format!(r#"{{"event":"start","code":{:?}}}"#, code),
+            TraceEvent::Instruction { pc, bytes } => format!(
+                r#"{{"event":"instruction","pc":{},"bytes":{:?},"instruction":{}}}"#,
+                pc,
+                bytes,
+                json_string(
+                    &event
+                        .instruction()
+                        .map(|ins| ins.to_string().replace('\t', " "))
+                        .unwrap_or_default()
+                )
+            ),
+            TraceEvent::Stack(stack) => format!(r#"{{"event":"stack","stack":{:?}}}"#, stack),
+            TraceEvent::SetPixel { index, color } => format!(
+                r#"{{"event":"set_pixel","index":{},"color":[{},{},{},{}]}}"#,
+                index, color.r, color.g, color.b, color.a.0
+            ),
+            TraceEvent::Blit => r#"{"event":"blit"}"#.to_string(),
+            TraceEvent::Dump(stack) => format!(r#"{{"event":"dump","stack":{:?}}}"#, stack),
+            TraceEvent::Ended { instruction_count } => format!(
+                r#"{{"event":"ended","instruction_count":{}}}"#,
+                instruction_count
+            ),
+        };
+        let _ = writeln!(self.writer, "{}", line);
+    }
+}
+
+/// `text` as a quoted JSON string, event keys may contain any byte
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::FromSource;
+    use crate::program::Program;
+    use crate::vm::{Outcome, VMConfig, VM};
+
+    #[test]
+    fn check_trace_sinks() {
+        let p = Program::from_source("set_pixel(0, 1, 2, 3, 4); dump; blit;").unwrap();
+
+        let sink = MemorySink::new();
+        let mut vm = VM::new(1, Default::default());
+        vm.set_trace_sink(Box::new(sink.clone()));
+        let mut state = vm.start(p.clone(), Default::default());
+        assert!(matches!(state.run(), Outcome::BLIT(_)));
+        // Without tracing only dumps arrive
+        assert_eq!(sink.take(), vec![TraceEvent::Dump(vec![])]);
+
+        let mut vm = VM::new(
+            1,
+            VMConfig {
+                trace: true,
+                ..Default::default()
+            },
+        );
+        vm.set_trace_sink(Box::new(sink.clone()));
+        let mut state = vm.start(p, Default::default());
+        assert!(matches!(state.run(), Outcome::BLIT(_)));
+        let events = sink.take();
+        assert!(events.contains(&TraceEvent::SetPixel {
+            index: 0,
+            color: RGBW8::new_alpha(1, 2, 3, smart_leds_trait::White(4)),
+        }));
+        assert_eq!(events.last(), Some(&TraceEvent::Blit));
+
+        let mut json = JsonLinesSink::new(vec![]);
+        for event in events {
+            json.event(event);
+        }
+        let output = String::from_utf8(json.into_inner()).unwrap();
+        assert!(output.starts_with(r#"{"event":"start","code":[16, "#));
+        assert!(output
+            .contains(r#"{"event":"instruction","pc":0,"bytes":[16],"instruction":"PUSHB 0"}"#));
+        assert!(output.ends_with("{\"event\":\"blit\"}\n"));
+
+        let on = Instruction::On {
+            kind: crate::instructions::EventKind::PARAM_CHANGED,
+            key: b"a\"b\\c\n\x01",
+            end: 20,
+        };
+        let mut bytes = vec![];
+        on.encode(&mut bytes);
+        let mut json = JsonLinesSink::new(vec![]);
+        json.event(TraceEvent::Instruction { pc: 3, bytes });
+        let output = String::from_utf8(json.into_inner()).unwrap();
+        assert_eq!(output.lines().count(), 1);
+        let line: serde_json::Value = serde_json::from_str(output.trim_end()).unwrap();
+        assert_eq!(
+            line["instruction"],
+            "SPECIAL on param_changed a\"b\\c\n\u{1} to 20"
+        );
+    }
+}